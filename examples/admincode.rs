@@ -1,17 +1,20 @@
-use std::fs::File;  
-use std::io::Write;
 use std::fmt::{self, Display, Formatter};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, OnceLock};
 use std::thread;
+use std::time::Duration;
+#[cfg(not(feature = "progress-bar"))]
+use crawl::progress::log_progress;
+use crawl::request::Request;
+use crawl::extract::{Extractor, Field, Query};
 use crawl::downloader::{Downloader, get_res_thread_arg, start_crawl, ResThreadArg};
+use crawl::pipeline::{CsvExporter, DedupStage, JsonLinesExporter, Pipeline, ValidateStage, sort_csv};
+use serde::{Deserialize, Serialize};
 use select::document::Document;
-use select::node::Node;
 use select::predicate::{Name, Class, Predicate};
 use url::Url;
-use bytes::Bytes;
-use encoding_rs::{UTF_8, GB18030};
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 enum CityType{
     Country,
     Province,
@@ -33,7 +36,7 @@ impl Display for CityType {
     }  
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 struct AdminCode{
     year: u16,
     code: String,
@@ -46,9 +49,6 @@ struct AdminCode{
     city_type: CityType,
     town_type_code: String
 }
-struct Manager{
-    datas:Vec<AdminCode>
-}
 enum CrawlFlag{
     Province(AdminCode),
     Data(AdminCode)
@@ -69,7 +69,7 @@ const MINGZU:[&str; 55] =  [
 ];
 
 fn name_to_short_name<'a>(name: &'a str, parent:&'a AdminCode, city_type:&CityType) -> (&'a str, String){
-    if vec!("市辖区", "省直辖县级行政区划", "自治区直辖县级行政区划", "县").contains(&name){
+    if ["市辖区", "省直辖县级行政区划", "自治区直辖县级行政区划", "县"].contains(&name){
         return (&parent.short_name, parent.full_short_name.clone());
     }
     let mut short_name = name;
@@ -92,7 +92,7 @@ fn name_to_short_name<'a>(name: &'a str, parent:&'a AdminCode, city_type:&CityTy
     short_name = rstrip(short_name, "街道");
     short_name = rstrip(short_name, "社区");
     short_name = rstrip(short_name, "地区");
-    if vec!(CityType::Province, CityType::City, CityType::County).contains(city_type){
+    if [CityType::Province, CityType::City, CityType::County].contains(city_type){
         short_name = rstrip(short_name, "省");
         short_name = rstrip(short_name, "市");
         if !short_name.ends_with("新区") && !short_name.ends_with("矿区"){
@@ -129,14 +129,15 @@ fn name_to_short_name<'a>(name: &'a str, parent:&'a AdminCode, city_type:&CityTy
     if short_name == parent.short_name{
         return (&parent.short_name, parent.full_short_name.clone());
     }
-    if parent.full_short_name == ""{
-        return (&short_name, short_name.to_string());
+    if parent.full_short_name.is_empty(){
+        return (short_name, short_name.to_string());
     }
     let  full_short_name = format!("{} {}", parent.full_short_name, short_name);
-    return (short_name, full_short_name);
+    (short_name, full_short_name)
 
 }
 impl AdminCode{
+    #[allow(clippy::too_many_arguments)]
     fn new(
         year: u16,
         code: &str,
@@ -150,7 +151,7 @@ impl AdminCode{
         town_type_code: &str
     ) -> AdminCode{
         AdminCode{
-            year: year,
+            year,
             parent_code: parent_code.to_string(),
             code: code.to_string(),
             short_code: short_code.to_string(),
@@ -158,7 +159,7 @@ impl AdminCode{
             short_name: short_name.to_string(),
             full_name: full_name.to_string(),
             full_short_name: full_short_name.to_string(),
-            city_type: city_type,
+            city_type,
             town_type_code: town_type_code.to_string()
         }
     }
@@ -169,109 +170,68 @@ impl AdminCode{
             code
         };
         let (short_name, full_short_name) = name_to_short_name(name, parent, &city_type);
-        let full_name ;
-        if parent.full_name == ""{
-            full_name = format!("{}", name);
+        let full_name = if parent.full_name.is_empty(){
+            name.to_string()
         }else{
-            full_name = format!("{} {}", parent.full_name, name);
-        }
+            format!("{} {}", parent.full_name, name)
+        };
         AdminCode::new(year, code, &parent.code, short_code, name, short_name, &full_name, &full_short_name, city_type, town_type_code)
 
     }
     fn china(year: u16) -> AdminCode{
         AdminCode::new(year, "000000000000","","000000", "中华人名共和国", "中国", "", "", CityType::Country, "")
     }
-    
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        let mut r = self.year.cmp(&other.year);
-        if r.is_eq() {
-            r = self.code.cmp(&other.code);
-        }
-        r
-    }
-    
 }
 
-fn get_text_href(node:Node)-> (String, Option<&str>){
-    match node.find(Name("a")).next(){
-        Some(a)=>(a.text(), a.attr("href")),
-        None=>(node.text(), None)
-    }
+#[derive(Deserialize)]
+struct Row{
+    class: String,
+    code: Option<String>,
+    second: Option<String>,
+    third: Option<String>,
+    href: Option<String>,
 }
-fn parse_trs(data:&AdminCode, arg:&ResThreadArg<CrawlFlag>, manager: &Arc<Mutex<Manager>>, base_url:&Url, doc:&Document, class_name:&str, city_type:CityType)-> anyhow::Result<()> {
 
-    for node in doc.find(Class(class_name)){
-        let mut tds = node.find(Name("td"));
-        let (code, href1) = match tds.next(){
-            None=>(String::new(), None),
-            Some(td)=>get_text_href(td)
+fn row_extractor() -> &'static Extractor{
+    static ROWS: OnceLock<Extractor> = OnceLock::new();
+    ROWS.get_or_init(|| {
+        let fields = || -> anyhow::Result<Extractor> {
+            Ok(Extractor::new()
+                .with_root(Query::xpath("//tr[@class='citytr' or @class='countytr' or @class='towntr' or @class='villagetr']")?)
+                .with_field(Field::xpath("class", ".")?.attr("class"))
+                .with_field(Field::xpath("code", "td[1]")?)
+                .with_field(Field::xpath("second", "td[2]")?)
+                .with_field(Field::xpath("third", "td[3]")?)
+                .with_field(Field::xpath("href", ".//a/@href")?.url()))
         };
-        let (text, href2) = match tds.next(){
-            None=>(String::new(), None),
-            Some(td)=>get_text_href(td)
+        fields().expect("valid row rules")
+    })
+}
+
+fn parse_data(url: &str, d:&str, data:&AdminCode, arg:&ResThreadArg<CrawlFlag>, pipeline: &Pipeline<AdminCode>) -> anyhow::Result<()> {
+    for row in row_extractor().extract_as::<Row>(d, url)?{
+        let city_type = match row.class.as_str(){
+            "citytr" => CityType::City,
+            "countytr" => CityType::County,
+            "towntr" => CityType::Town,
+            _ => CityType::Village,
         };
-        let href = match href1{
-            Some(h)=>Some(h),
-            None=>href2
+        let second = row.second.unwrap_or_default();
+        let (town_type_code, name) = match row.third{
+            Some(third) if city_type == CityType::Village => (second, third),
+            _ => (String::new(), second),
         };
-        let town_type_code;
-        let name;
-        if city_type == CityType::Village{
-            match tds.next(){
-                None=>{
-                    town_type_code = String::new();
-                    name = text;
-                },
-                Some(td)=>{
-                    town_type_code = text;
-                    name = td.text();
-                }
-            }
-        }else{
-            town_type_code = String::new();
-            name = text;
+        let mut admin_code = AdminCode::create(data.year, &row.code.unwrap_or_default(), &name, data, city_type, &town_type_code);
+        pipeline.process(&mut admin_code, url)?;
+        if let Some(href) = row.href{
+            arg.start_request(Request::new(href).with_flag(CrawlFlag::Data(admin_code)))?;
         }
-        let admin_code = AdminCode::create(data.year, &code, &name, &data, city_type.clone(), &town_type_code);
-        {
-            let mut m = manager.lock().unwrap();
-            m.datas.push(admin_code.clone());
-        }
-        match href{
-            None=>{},
-            Some(h)=>{
-                let new_url = base_url.join(&h)?;
-                arg.start_url(new_url.to_string(), false, Arc::new(CrawlFlag::Data(admin_code)))?;
-            }
-        }
-    }
-    Ok(())
-}
-
-fn decode_bytes(data:&Bytes) -> Option<String>{
-    let (text, _, _) = GB18030.decode(data.as_ref());
-    if text.contains("代码"){
-        return Some(text.into_owned());
-    }
-    
-    let (text, _, _) = UTF_8.decode(data.as_ref());
-    if text.contains("代码"){
-        return Some(text.into_owned());
     }
-    return None;
-    
-}
-fn parse_data(url: &str, d:&str, data:&AdminCode, arg:&ResThreadArg<CrawlFlag>, manager: &Arc<Mutex<Manager>>) -> anyhow::Result<()> {
-    let doc = Document::from(d);
-    let base_url = Url::parse(url)?;
-    parse_trs(data, arg, manager, &base_url, &doc, "citytr", CityType::City)?;
-    parse_trs(data, arg, manager, &base_url, &doc, "countytr", CityType::County)?;
-    parse_trs(data, arg, manager, &base_url, &doc, "towntr", CityType::Town)?;
-    parse_trs(data, arg, manager, &base_url, &doc, "villagetr", CityType::Village)?;
     Ok(())
 }
 
 
-fn parse_province(url: &str, d:&str, data:&AdminCode, arg:&ResThreadArg<CrawlFlag>, manager: &Arc<Mutex<Manager>>) -> anyhow::Result<()> {
+fn parse_province(url: &str, d:&str, data:&AdminCode, arg:&ResThreadArg<CrawlFlag>, pipeline: &Pipeline<AdminCode>) -> anyhow::Result<()> {
     
 
     let doc = Document::from(d);
@@ -283,90 +243,80 @@ fn parse_province(url: &str, d:&str, data:&AdminCode, arg:&ResThreadArg<CrawlFla
             Some((c, _)) => format!("{}0000000000", c).to_string(),
             None => String::new(),
         };
-        let admin_code = AdminCode::create(data.year, &code, &name, &data, CityType::Province, "");
-        {
-            let mut m = manager.lock().unwrap();
-            m.datas.push(admin_code.clone());
-        }
+        let mut admin_code = AdminCode::create(data.year, &code, &name, data, CityType::Province, "");
+        pipeline.process(&mut admin_code, url)?;
         let new_url = base_url.join(href)?;
 
-        arg.start_url(new_url.to_string(),  false, Arc::new(CrawlFlag::Data(admin_code)))?;
+        arg.start_request(Request::new(new_url).with_flag(CrawlFlag::Data(admin_code)))?;
     }
 
     Ok(())
 }
 
 
-fn res_run(arg:ResThreadArg<CrawlFlag>, manager: Arc<Mutex<Manager>>){
+fn res_run(arg:ResThreadArg<CrawlFlag>, pipeline: Arc<Pipeline<AdminCode>>){
     loop {
-        match arg.get_msg(){
-            Ok(msg)=>{
-                let d;
-                match &msg.data {
-                    Ok(data) => match data{
-                        Some(v) => match decode_bytes(&v){
-                            Some(text) => d = text,
-                            None => {
-                                match msg.retry(true){
-                                    Ok(_) => {},
-                                    Err(_) => {},
-                                };
-                                continue;
-                            }
-                        },
-                        None => continue,
-                    },
-                    Err(_) => {
-                        match msg.retry(false) {
-                            Ok(_) => {},
-                            Err(_) => {},
-                        };
-                        continue;
+        if let Ok(msg) = arg.get_msg() {
+            let d;
+            match &msg.data {
+                Ok(data) => match data{
+                    Some(_) => d = msg.text().unwrap_or_default(),
+                    None => continue,
+                },
+                Err(e) => {
+                    tracing::warn!(url = %msg.url, error = %e, "download failed");
+                    if msg.can_retry(){
+                        if let Err(e) = msg.retry(false){
+                            tracing::error!(url = %msg.url, error = %e, "retry failed");
+                        }
                     }
+                    continue;
                 }
-                let _ = match msg.flag.as_ref(){
-                    CrawlFlag::Province(data)=>parse_province(&msg.url, &d, &data, &arg, &manager),
-                    CrawlFlag::Data(data)=>parse_data(&msg.url, &d, &data, &arg, &manager),
-                };
-            },
-            Err(_)=>{}
+            }
+            let result = match msg.flag.as_ref(){
+                CrawlFlag::Province(data)=>parse_province(&msg.url, &d, data, &arg, &pipeline),
+                CrawlFlag::Data(data)=>parse_data(&msg.url, &d, data, &arg, &pipeline),
+            };
+            if let Err(e) = result{
+                tracing::warn!(url = %msg.url, error = %e, "parse failed");
+            }
         }
     }
 
 }
 fn main() -> anyhow::Result<()> {
-    let download = Arc::new(Downloader::new(
+    tracing_subscriber::fmt::init();
+    let download = Downloader::new(
         String::from(r"data"),
         String::from("https://www.stats.gov.cn/sj/tjbz/tjyqhdmhcxhfdm/")
-    ));
-    let manager = Arc::new(Mutex::new(Manager{datas:Vec::new()}));
-    {
-        let mut m: std::sync::MutexGuard<'_, Manager> = manager.lock().unwrap();
-
-        for year in 2009..=2023{
-            let china = AdminCode::china(year);
-            m.datas.push(china.clone());
-            let url = format!("https://www.stats.gov.cn/sj/tjbz/tjyqhdmhcxhfdm/{}/index.html", year);
-            download.start_url(url, false, Arc::new(CrawlFlag::Province(china)))?;
-        }
+    ).with_validator(|page| page.text().contains("代码")).with_retries(10);
+    #[cfg(feature = "progress-bar")]
+    let download = download.with_progress_bar(Duration::from_millis(500));
+    #[cfg(not(feature = "progress-bar"))]
+    let download = download.with_progress(Duration::from_secs(10), log_progress);
+    let download = Arc::new(download);
+    let pipeline = Arc::new(Pipeline::new()
+        .with_stage(ValidateStage::new()
+            .with_required(&["code", "name"])
+            .with_errors(JsonLinesExporter::create("admin_code_errors.jsonl")?))
+        .with_stage(DedupStage::by_fields(&["year", "code"]))
+        .with_exporter(CsvExporter::create("admin_code.csv")?));
+    for year in 2009..=2023{
+        let mut china = AdminCode::china(year);
+        let url = format!("https://www.stats.gov.cn/sj/tjbz/tjyqhdmhcxhfdm/{}/index.html", year);
+        pipeline.process(&mut china, &url)?;
+        download.start_request(Request::new(url).with_flag(CrawlFlag::Province(china)))?;
     }
     for _ in 0..32{
         let res_arg = get_res_thread_arg(&download);
-        let r = Arc::clone(&manager);
-        thread::spawn(move || res_run(res_arg, r));
+        let p = Arc::clone(&pipeline);
+        thread::spawn(move || res_run(res_arg, p));
     }
     start_crawl(&download, 32);
     download.wait_finish();
-    {
-        let mut m = manager.lock().unwrap();
-
-        println!("finish {}", m.datas.len());
-        let mut file = File::create("admin_code.csv")?;
-        write!(file, "year,code,parent_code,short_code,name,short_name,full_name,full_short_name,city_type,town_type_code\n")?;
-        m.datas.sort_by(|a,b| a.cmp(b));
-        for item in m.datas.iter(){
-            write!(file, "{},{},{},{},{},{},{},{},{},{}\n", item.year, item.code, item.parent_code, item.short_code, item.name, item.short_name, item.full_name, item.full_short_name, item.city_type, item.town_type_code)?;
-        }
-    }
+    pipeline.finish()?;
+    // rows arrive in crawl order, sort the finished file
+    sort_csv("admin_code.csv", &["year", "code"])?;
+    println!("finish {} dropped {}", pipeline.processed(), pipeline.dropped());
     Ok(())
 }
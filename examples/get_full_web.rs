@@ -1,13 +1,14 @@
-use crawl::downloader::{Downloader, ResThreadArg, ResMessage, get_res_thread_arg, start_crawl};
+#[cfg(not(feature = "progress-bar"))]
+use crawl::progress::log_progress;
+use crawl::downloader::{Downloader, Response};
+use crawl::request::Request;
+use crawl::spider::{Engine, Output, Spider};
+use crawl::link::LinkExtractor;
+use crawl::mirror::{self, Mirror};
 use select::predicate::Name;
 use select::document::Document;
 use url::Url;
-use bytes::Bytes;
-use encoding_rs::UTF_8;
-use std::collections::HashSet;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use anyhow;
+use std::time::Duration;
 
 
 struct Data{
@@ -23,90 +24,67 @@ impl Data{
         }
     }
 }
-struct Manager{
-    datas: Vec<Data>,
-    added_urls: HashSet<String>,
-}
 
-fn decode_bytes(data:&Bytes) -> String{
-    let (text, _, _) = UTF_8.decode(data.as_ref());
-    text.into_owned()
+struct BookSpider{
+    links: LinkExtractor,
 }
-fn parse(msg:ResMessage<Option<()>>,arg:&ResThreadArg<Option<()>>,manager: &Arc<Mutex<Manager>>) -> anyhow::Result<()>{
-    let d;
-    match &msg.data {
-        Ok(data)=> match data{
-            Some(v) => d = decode_bytes(&v),
-            None => return Ok(()),
-        },
-        Err(_)=>return msg.retry(false)
-    }
-    let doc = Document::from(d.as_str());
-    let base_url = Url::parse(msg.url.as_str())?;
-    let title = doc.find(Name("title")).next().unwrap().text();
-    {
-        let mut m = manager.lock().unwrap();
-        m.datas.push(Data::new(&title, base_url.as_str()));
+
+impl Spider for BookSpider{
+    type Flag = ();
+    type Item = Data;
+
+    fn start_urls(&self) -> Vec<Request>{
+        vec![Request::new("https://doc.rust-lang.org/book/index.html")]
     }
-    
-    for node in doc.find(Name("a")){
-        let href = node.attr("href");
-        match href{
-            None=>{},
-            Some(h)=>{
-                let no_hash = match h.split_once("#"){
-                    Some((t, _)) => t,
-                    None => h
-                };
-                let new_url = base_url.join(no_hash)?.to_string();
-                {
-                    let mut m = manager.lock().unwrap();
-                    if !m.added_urls.contains(&new_url)
-                    {
-                        m.added_urls.insert(new_url.clone());
-                        arg.start_url(new_url, false, Arc::new(None))?;
-                    }
-                }
+
+    fn parse(&self, response: &Response<()>) -> anyhow::Result<Vec<Output<(), Data>>>{
+        let mut outputs = Vec::new();
+        let d = match response.text(){
+            Some(text) => text,
+            None => return Ok(outputs),
+        };
+        if mirror::is_css(&response.headers, &response.url){
+            for url in mirror::css_links(&response.url, &d){
+                outputs.push(Output::Request(Request::new(url)));
             }
+            return Ok(outputs);
         }
-    }
-    Ok(())
-}
+        for url in mirror::requisites(&response.url, &d){
+            outputs.push(Output::Request(Request::new(url)));
+        }
+        let doc = Document::from(d.as_str());
+        let base_url = Url::parse(response.url.as_str())?;
+        let title = doc.find(Name("title")).next().map(|n| n.text()).unwrap_or_default();
+        outputs.push(Output::Item(Data::new(&title, base_url.as_str())));
 
-fn res_run(arg:ResThreadArg<Option<()>>, manager: Arc<Mutex<Manager>>){
-    loop {
-        match arg.get_msg(){
-            Ok(msg)=>match parse(msg, &arg, &manager){
-                Ok(_)=>{},
-                Err(_)=>{}
-            },
-            Err(_)=>{}
+        for url in self.links.extract(response.url.as_str(), &d)?{
+            outputs.push(Output::Request(Request::new(url)));
         }
+        Ok(outputs)
     }
-
 }
 
 fn main() -> anyhow::Result<()> {
-    let download = Arc::new(Downloader::new(
+    tracing_subscriber::fmt::init();
+    let download = Downloader::new(
         String::from(r"data/book1"),
         String::from("https://doc.rust-lang.org/book/")
-    ));
-    let url = String::from("https://doc.rust-lang.org/book/index.html");
-    let manager = Arc::new(Mutex::new(Manager{datas:Vec::new(), added_urls:HashSet::from([url.clone()])}));
-    download.start_url(url, false, Arc::new(None))?;
-    for _ in 0..16{
-        let res_arg = get_res_thread_arg(&download);
-        let r = Arc::clone(&manager);
-        thread::spawn(move || res_run(res_arg, r));
+    ).with_offline(std::env::var_os("CRAWL_OFFLINE").is_some());
+    #[cfg(feature = "progress-bar")]
+    let download = download.with_progress_bar(Duration::from_millis(500));
+    #[cfg(not(feature = "progress-bar"))]
+    let download = download.with_progress(Duration::from_secs(10), log_progress);
+    let spider = BookSpider{
+        links: LinkExtractor::new().with_tags(&[("a", "href")]).allow("^https://doc.rust-lang.org/book/")?,
+    };
+    let datas = Engine::new(spider, download).with_threads(16, 16).run()?;
+    println!("finish {}", datas.len());
+    for item in datas.iter(){
+        println!("{} {}", item.url, item.title);
     }
-    start_crawl(&download, 16);
-    download.wait_finish();
-    {
-        let m = manager.lock().unwrap();
-        println!("finish {}", m.datas.len());
-        for item in m.datas.iter(){
-            println!("{} {}", item.url, item.title);
-        }
+    if let Some(dir) = std::env::var_os("CRAWL_MIRROR"){
+        let files = Mirror::new("data/book1", "https://doc.rust-lang.org/book/").export(&dir)?;
+        println!("mirrored {} files", files);
     }
     Ok(())
 }
@@ -0,0 +1,36 @@
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Debug, Clone)]
+pub enum Auth{
+    Basic{username: String, password: Option<String>},
+    Bearer(String),
+    Form{url: String, fields: Vec<(String, String)>},
+}
+
+impl Auth{
+    pub fn basic<U: Into<String>, P: Into<String>>(username: U, password: P) -> Auth{
+        Auth::Basic{username: username.into(), password: Some(password.into())}
+    }
+    pub fn bearer<T: Into<String>>(token: T) -> Auth{
+        Auth::Bearer(token.into())
+    }
+    pub fn form<U: Into<String>>(url: U, fields: &[(&str, &str)]) -> Auth{
+        Auth::Form{
+            url: url.into(),
+            fields: fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LoggedOut{
+    pub url: String,
+}
+
+impl Display for LoggedOut{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result{
+        write!(f, "logged out while fetching {}", self.url)
+    }
+}
+
+impl std::error::Error for LoggedOut{}
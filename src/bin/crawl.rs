@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use clap::Parser;
+#[cfg(not(feature = "progress-bar"))]
+use crawl::progress::log_progress;
+use crawl::config::{CrawlConfig, FollowRule};
+
+/// Mirror a site into a local cache directory
+#[derive(Parser)]
+#[command(name = "crawl", version, about)]
+struct Args{
+    /// Start URLs
+    #[arg(required_unless_present = "config")]
+    urls: Vec<String>,
+    /// TOML or YAML crawl configuration, flags override its values
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// URL prefix to stay within, defaults to the directory of the first start URL
+    #[arg(short, long)]
+    scope: Option<String>,
+    /// Directory the pages are stored in [default: data]
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Number of download threads [default: 16]
+    #[arg(short, long)]
+    threads: Option<u16>,
+    /// Seconds to wait between requests to the same host [default: 0]
+    #[arg(short, long)]
+    delay: Option<f64>,
+    /// Retries for failed requests [default: 3]
+    #[arg(short, long)]
+    retries: Option<u32>,
+    /// User-Agent header sent with every request
+    #[arg(short = 'A', long)]
+    user_agent: Option<String>,
+    /// Download again even when a page is cached
+    #[arg(short, long)]
+    force: bool,
+    /// Also fetch page requisites and write a browsable copy with local links to this directory
+    #[arg(short, long)]
+    mirror: Option<String>,
+}
+
+impl Args{
+    fn crawl_config(&self) -> anyhow::Result<CrawlConfig>{
+        let mut config = match &self.config{
+            Some(path) => CrawlConfig::load(path)?,
+            None => CrawlConfig::default(),
+        };
+        config.seeds.extend(self.urls.iter().cloned());
+        if self.scope.is_some(){
+            config.scope = self.scope.clone();
+        }
+        if let Some(output) = &self.output{
+            config.cache_dir = output.clone();
+        }
+        if let Some(threads) = self.threads{
+            config.threads = threads;
+        }
+        if let Some(delay) = self.delay{
+            config.politeness.delay = delay;
+        }
+        if let Some(retries) = self.retries{
+            config.retry.max = retries;
+        }
+        if self.user_agent.is_some(){
+            config.user_agent = self.user_agent.clone();
+        }
+        config.cache.force |= self.force;
+        if self.mirror.is_some(){
+            config.mirror = self.mirror.clone();
+        }
+        if self.config.is_none(){
+            config.follow.push(FollowRule{allow: vec![format!("^{}", regex::escape(&config.scope()?))], ..FollowRule::default()});
+        }
+        Ok(config)
+    }
+}
+
+fn main() -> anyhow::Result<()>{
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+    let args = Args::parse();
+    let config = args.crawl_config()?;
+    let scope = config.scope()?;
+    let started = Instant::now();
+    let download = config.downloader()?;
+    #[cfg(feature = "progress-bar")]
+    let download = download.with_progress_bar(Duration::from_millis(500));
+    #[cfg(not(feature = "progress-bar"))]
+    let download = download.with_progress(Duration::from_secs(10), log_progress);
+    // items go to the configured export, nothing needs them in memory
+    let engine = config.engine_with(download)?.on_item(drop);
+    let download = Arc::clone(engine.downloader());
+    let (_, summary) = engine.run_with_summary()?;
+    let exported = match (config.mirror()?, &config.mirror){
+        (Some(m), Some(dir)) => Some((m.export(dir)?, dir)),
+        _ => None,
+    };
+    let progress = download.progress();
+    println!("scope      {}", scope);
+    println!("output     {}", config.cache_dir);
+    println!("pages      {}", summary.pages);
+    println!("requests   {}", progress.connected);
+    println!("skipped    {}", summary.skipped);
+    println!("duplicates {}", summary.duplicates);
+    println!("failed     {}", summary.failed);
+    println!("items      {}", summary.items);
+    println!("bytes      {}", summary.bytes);
+    if let Some((files, dir)) = exported{
+        println!("mirror     {} files in {}", files, dir);
+    }
+    println!("elapsed    {:.1}s", started.elapsed().as_secs_f64());
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheMeta{
+    pub url: String,
+    pub status: u16,
+    pub charset: Option<String>,
+    pub hash: Option<String>,
+    pub fetched: u64,
+    pub headers: Vec<(String, String)>,
+    pub meta: BTreeMap<String, String>,
+}
+
+impl CacheMeta{
+    pub fn new(url: &str, status: u16, headers: &HeaderMap) -> CacheMeta{
+        CacheMeta{
+            url: url.to_string(),
+            status,
+            charset: None,
+            hash: None,
+            fetched: now(),
+            headers: headers.iter()
+                .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.as_str().to_string(), v.to_string())))
+                .collect(),
+            meta: BTreeMap::new(),
+        }
+    }
+    pub fn header_map(&self) -> HeaderMap{
+        let mut headers = HeaderMap::new();
+        for (k, v) in self.headers.iter(){
+            if let (Ok(k), Ok(v)) = (HeaderName::from_bytes(k.as_bytes()), HeaderValue::from_str(v)){
+                headers.append(k, v);
+            }
+        }
+        headers
+    }
+}
+
+pub fn now() -> u64{
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Relative file a url path is stored under, directory urls map to their `index.html`.
+pub fn file_path(path: &str) -> String{
+    if path.is_empty() || path.ends_with('/') {format!("{}index.html", path)} else {path.to_string()}
+}
+
+pub fn meta_path(path: &Path) -> PathBuf{
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.meta", name))
+}
+
+pub fn read_body(path: &Path) -> std::io::Result<Bytes>{
+    let mut file = File::open(path)?;
+    let size = file.metadata().map(|m| m.len() as usize).ok().unwrap_or(0);
+    let mut buffer = Vec::with_capacity(size);
+    file.read_to_end(&mut buffer)?;
+    Ok(Bytes::from(buffer))
+}
+
+pub fn write_body(path: &Path, body: &Bytes) -> std::io::Result<()>{
+    if let Some(p) = path.parent(){
+        fs::create_dir_all(p)?;
+    }
+    let mut file = File::create(path)?;
+    for chunk in body.chunks(4096){
+        file.write_all(chunk)?;
+    }
+    Ok(())
+}
+
+pub fn read_meta(path: &Path) -> Option<CacheMeta>{
+    let data = fs::read(meta_path(path)).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+pub fn write_meta(path: &Path, meta: &CacheMeta) -> std::io::Result<()>{
+    let data = serde_json::to_vec_pretty(meta)?;
+    fs::write(meta_path(path), data)
+}
@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use regex::Regex;
+use reqwest::header::{HeaderName, HeaderValue};
+use scraper::Html;
+use serde::Deserialize;
+use serde_json::Value;
+use crate::downloader::{Downloader, Response};
+use crate::extract::{ExtractRule, Extractor};
+use crate::link::{LinkExtractor, scope_of};
+use crate::mirror::{self, Mirror};
+use crate::pipeline::{JsonExporter, JsonLinesExporter, Pipeline};
+use crate::request::Request;
+use crate::spider::{Engine, Output, Spider};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Politeness{
+    pub delay: f64,
+    pub hosts: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy{
+    pub max: u32,
+    pub require: Option<String>,
+    pub reject: Option<String>,
+}
+
+impl Default for RetryPolicy{
+    fn default() -> Self{
+        RetryPolicy{max: 3, require: None, reject: None}
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CachePolicy{
+    pub force: bool,
+    pub revalidate: bool,
+    pub offline: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FollowRule{
+    pub pages: Option<String>,
+    pub tags: Vec<(String, String)>,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ItemRule{
+    pub pages: Option<String>,
+    #[serde(flatten)]
+    pub extract: ExtractRule,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExportConfig{
+    pub path: String,
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CrawlConfig{
+    pub seeds: Vec<String>,
+    pub scope: Option<String>,
+    pub cache_dir: String,
+    pub threads: u16,
+    pub parse_threads: usize,
+    pub user_agent: Option<String>,
+    pub proxy: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    pub politeness: Politeness,
+    pub retry: RetryPolicy,
+    pub cache: CachePolicy,
+    pub follow: Vec<FollowRule>,
+    pub items: Vec<ItemRule>,
+    pub export: Option<ExportConfig>,
+    pub requisites: bool,
+    pub mirror: Option<String>,
+}
+
+impl Default for CrawlConfig{
+    fn default() -> Self{
+        CrawlConfig{
+            seeds: Vec::new(),
+            scope: None,
+            cache_dir: String::from("data"),
+            threads: 16,
+            parse_threads: 16,
+            user_agent: None,
+            proxy: None,
+            headers: BTreeMap::new(),
+            politeness: Politeness::default(),
+            retry: RetryPolicy::default(),
+            cache: CachePolicy::default(),
+            follow: Vec::new(),
+            items: Vec::new(),
+            export: None,
+            requisites: false,
+            mirror: None,
+        }
+    }
+}
+
+fn seconds(secs: f64) -> Duration{
+    Duration::from_secs_f64(secs.max(0.0))
+}
+
+fn optional_regex(pattern: &Option<String>) -> anyhow::Result<Option<Regex>>{
+    Ok(match pattern{
+        Some(p) => Some(Regex::new(p)?),
+        None => None,
+    })
+}
+
+impl CrawlConfig{
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<CrawlConfig>{
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()){
+            Some("yaml") | Some("yml") => CrawlConfig::from_yaml(&text),
+            _ => CrawlConfig::from_toml(&text),
+        }
+    }
+    pub fn from_toml(text: &str) -> anyhow::Result<CrawlConfig>{
+        Ok(toml::from_str(text)?)
+    }
+    pub fn from_yaml(text: &str) -> anyhow::Result<CrawlConfig>{
+        Ok(serde_yaml::from_str(text)?)
+    }
+
+    pub fn scope(&self) -> anyhow::Result<String>{
+        match (&self.scope, self.seeds.first()){
+            (Some(scope), _) => Ok(scope.clone()),
+            (None, Some(seed)) => scope_of(seed),
+            (None, None) => anyhow::bail!("crawl config has no seeds"),
+        }
+    }
+
+    pub fn downloader<E: Send + Sync + 'static>(&self) -> anyhow::Result<Downloader<E>>{
+        let mut downloader = Downloader::new(self.cache_dir.clone(), self.scope()?)
+            .with_retries(self.retry.max)
+            .with_delay(seconds(self.politeness.delay))
+            .with_revalidate(self.cache.revalidate)
+            .with_offline(self.cache.offline);
+        for (host, delay) in self.politeness.hosts.iter(){
+            downloader = downloader.with_host_delay(host.as_str(), seconds(*delay));
+        }
+        for (name, value) in self.headers.iter(){
+            downloader = downloader.with_header(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+        }
+        if let Some(user_agent) = &self.user_agent{
+            downloader = downloader.with_user_agent(user_agent.as_str());
+        }
+        if let Some(proxy) = &self.proxy{
+            downloader = downloader.with_proxy(proxy)?;
+        }
+        let require = optional_regex(&self.retry.require)?;
+        let reject = optional_regex(&self.retry.reject)?;
+        if require.is_some() || reject.is_some(){
+            downloader = downloader.with_validator(move |page| {
+                let text = page.text();
+                require.as_ref().map(|r| r.is_match(&text)).unwrap_or(true)
+                    && !reject.as_ref().map(|r| r.is_match(&text)).unwrap_or(false)
+            });
+        }
+        Ok(downloader)
+    }
+
+    pub fn spider(&self) -> anyhow::Result<ConfigSpider>{
+        let mut follow = Vec::new();
+        for rule in self.follow.iter(){
+            let mut links = LinkExtractor::new();
+            if !rule.tags.is_empty(){
+                let tags: Vec<(&str, &str)> = rule.tags.iter().map(|(t, a)| (t.as_str(), a.as_str())).collect();
+                links = links.with_tags(&tags);
+            }
+            for pattern in rule.allow.iter(){
+                links = links.allow(pattern)?;
+            }
+            for pattern in rule.deny.iter(){
+                links = links.deny(pattern)?;
+            }
+            follow.push((optional_regex(&rule.pages)?, links));
+        }
+        let mut items = Vec::new();
+        for rule in self.items.iter(){
+            items.push((optional_regex(&rule.pages)?, rule.extract.compile()?));
+        }
+        Ok(ConfigSpider{seeds: self.seeds.clone(), force: self.cache.force, requisites: self.requisites || self.mirror.is_some(), follow, items})
+    }
+
+    pub fn pipeline(&self) -> anyhow::Result<Option<Pipeline<Value>>>{
+        let Some(export) = &self.export else {
+            return Ok(None);
+        };
+        let format = match &export.format{
+            Some(format) => format.clone(),
+            None => Path::new(&export.path).extension().and_then(|e| e.to_str()).unwrap_or_default().to_string(),
+        };
+        let pipeline = Pipeline::new();
+        Ok(Some(match format.as_str(){
+            "json" => pipeline.with_exporter(JsonExporter::create(&export.path)?),
+            "jsonl" | "ndjson" | "" => pipeline.with_exporter(JsonLinesExporter::create(&export.path)?),
+            f => anyhow::bail!("unsupported export format: {}", f),
+        }))
+    }
+
+    pub fn mirror(&self) -> anyhow::Result<Option<Mirror>>{
+        Ok(match &self.mirror{
+            Some(_) => Some(Mirror::new(self.cache_dir.as_str(), self.scope()?)),
+            None => None,
+        })
+    }
+
+    pub fn engine(&self) -> anyhow::Result<Engine<ConfigSpider>>{
+        self.engine_with(self.downloader()?)
+    }
+
+    /// Like `engine`, with a downloader built by `downloader` and then customised by the caller.
+    pub fn engine_with(&self, downloader: Downloader<()>) -> anyhow::Result<Engine<ConfigSpider>>{
+        let engine = Engine::new(self.spider()?, downloader).with_threads(self.threads, self.parse_threads);
+        Ok(match self.pipeline()?{
+            Some(pipeline) => engine.with_pipeline(pipeline),
+            None => engine,
+        })
+    }
+}
+
+pub struct ConfigSpider{
+    seeds: Vec<String>,
+    force: bool,
+    requisites: bool,
+    follow: Vec<(Option<Regex>, LinkExtractor)>,
+    items: Vec<(Option<Regex>, Extractor)>,
+}
+
+fn applies(pages: &Option<Regex>, url: &str) -> bool{
+    pages.as_ref().map(|r| r.is_match(url)).unwrap_or(true)
+}
+
+impl Spider for ConfigSpider{
+    type Flag = ();
+    type Item = Value;
+
+    fn start_urls(&self) -> Vec<Request>{
+        self.seeds.iter().map(|url| Request::new(url.as_str()).with_force(self.force)).collect()
+    }
+
+    fn parse(&self, response: &Response<()>) -> anyhow::Result<Vec<Output<(), Value>>>{
+        let mut outputs = Vec::new();
+        let text = match response.text(){
+            Some(text) => text,
+            None => return Ok(outputs),
+        };
+        // error pages and non-html bodies are neither followed nor scraped
+        let success = response.status.map(|status| (200..300).contains(&status)).unwrap_or(false);
+        if !success{
+            return Ok(outputs);
+        }
+        if mirror::is_css(&response.headers, &response.url){
+            if self.requisites{
+                outputs.extend(mirror::css_links(&response.url, &text).into_iter().map(|url| Output::Request(Request::new(url).with_force(self.force))));
+            }
+            return Ok(outputs);
+        }
+        if !mirror::is_html(&response.headers, &response.url){
+            return Ok(outputs);
+        }
+        if self.requisites{
+            outputs.extend(mirror::requisites(&response.url, &text).into_iter().map(|url| Output::Request(Request::new(url).with_force(self.force))));
+        }
+        for (_, links) in self.follow.iter().filter(|(pages, _)| applies(pages, &response.url)){
+            for url in links.extract(&response.url, &text)?{
+                outputs.push(Output::Request(Request::new(url).with_force(self.force)));
+            }
+        }
+        let extractors: Vec<&Extractor> = self.items.iter()
+            .filter(|(pages, _)| applies(pages, &response.url))
+            .map(|(_, extractor)| extractor)
+            .collect();
+        if extractors.is_empty(){
+            return Ok(outputs);
+        }
+        let doc = Html::parse_document(&text);
+        for extractor in extractors{
+            for mut item in extractor.extract_document(&doc, &response.url){
+                if let Value::Object(map) = &mut item{
+                    map.entry("url").or_insert_with(|| Value::String(response.url.clone()));
+                }
+                outputs.push(Output::Item(item));
+            }
+        }
+        Ok(outputs)
+    }
+}
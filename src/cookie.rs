@@ -0,0 +1,213 @@
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+use cookie_store::{CookieDomain, CookieExpiration, CookieStore, RawCookie};
+use reqwest::Url;
+use reqwest::header::HeaderValue;
+use crate::cache::now;
+
+pub struct CookieJar{
+    store: RwLock<CookieStore>,
+}
+
+impl CookieJar{
+    pub fn new() -> CookieJar{
+        CookieJar{store: RwLock::new(CookieStore::default())}
+    }
+
+    pub fn add(&self, cookie: &str, url: &str) -> anyhow::Result<()>{
+        let url = Url::parse(url)?;
+        self.store.write().unwrap().parse(cookie, &url)?;
+        Ok(())
+    }
+
+    pub fn get(&self, url: &str) -> Vec<(String, String)>{
+        match Url::parse(url){
+            Ok(url) => self.store.read().unwrap().get_request_values(&url)
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn clear(&self){
+        self.store.write().unwrap().clear();
+    }
+
+    pub fn import_netscape(&self, data: &str) -> anyhow::Result<usize>{
+        let mut store = self.store.write().unwrap();
+        let mut count = 0;
+        for line in data.lines(){
+            let (line, http_only) = match line.strip_prefix("#HttpOnly_"){
+                Some(l) => (l, true),
+                None => (line, false),
+            };
+            if line.trim().is_empty() || line.starts_with('#'){
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 7{
+                continue;
+            }
+            let (domain, subdomains, path, secure, expires, name, value) =
+                (fields[0], fields[1], fields[2], fields[3], fields[4], fields[5], fields[6]);
+            let host = domain.trim_start_matches('.');
+            let secure = secure.eq_ignore_ascii_case("TRUE");
+            let mut cookie = format!("{}={}; Path={}", name, value, path);
+            if subdomains.eq_ignore_ascii_case("TRUE"){
+                cookie.push_str(&format!("; Domain={}", host));
+            }
+            let expires = expires.parse::<i64>().unwrap_or(0);
+            if expires > 0{
+                let max_age = expires - now() as i64;
+                if max_age <= 0{
+                    continue;
+                }
+                cookie.push_str(&format!("; Max-Age={}", max_age));
+            }
+            if secure{
+                cookie.push_str("; Secure");
+            }
+            if http_only{
+                cookie.push_str("; HttpOnly");
+            }
+            let url = Url::parse(&format!("{}://{}{}", if secure {"https"} else {"http"}, host, path))?;
+            if store.parse(&cookie, &url).is_ok(){
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn export_netscape(&self) -> String{
+        let mut out = String::from("# Netscape HTTP Cookie File\n");
+        for cookie in self.store.read().unwrap().iter_unexpired(){
+            let (domain, subdomains) = match &cookie.domain{
+                CookieDomain::HostOnly(h) => (h.clone(), "FALSE"),
+                CookieDomain::Suffix(d) => (format!(".{}", d), "TRUE"),
+                _ => continue,
+            };
+            let expires = match &cookie.expires{
+                CookieExpiration::AtUtc(t) => t.unix_timestamp(),
+                CookieExpiration::SessionEnd => 0,
+            };
+            let prefix = if cookie.http_only().unwrap_or(false) {"#HttpOnly_"} else {""};
+            let secure = if cookie.secure().unwrap_or(false) {"TRUE"} else {"FALSE"};
+            let path: &str = cookie.path.as_ref();
+            out.push_str(&format!("{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                prefix, domain, subdomains, path, secure, expires, cookie.name(), cookie.value()));
+        }
+        out
+    }
+
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<usize>{
+        self.import_netscape(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()>{
+        let path = path.as_ref();
+        if let Some(p) = path.parent(){
+            fs::create_dir_all(p)?;
+        }
+        fs::write(path, self.export_netscape())?;
+        Ok(())
+    }
+}
+
+impl Default for CookieJar{
+    fn default() -> Self{
+        CookieJar::new()
+    }
+}
+
+impl reqwest::cookie::CookieStore for CookieJar{
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url){
+        let cookies = cookie_headers
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(|v| RawCookie::parse(v.to_string()).ok())
+            .collect::<Vec<_>>();
+        self.store.write().unwrap().store_response_cookies(cookies.into_iter(), url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue>{
+        let value = self.store.read().unwrap().get_request_values(url)
+            .map(|(n, v)| format!("{}={}", n, v))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if value.is_empty(){
+            return None;
+        }
+        HeaderValue::from_str(&value).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn names(jar: &CookieJar, url: &str) -> Vec<String>{
+        let mut names: Vec<String> = jar.get(url).into_iter().map(|(n, v)| format!("{}={}", n, v)).collect();
+        names.sort();
+        names
+    }
+
+    fn cookies_txt() -> String{
+        let later = now() + 3600;
+        [
+            "# Netscape HTTP Cookie File".to_string(),
+            String::new(),
+            format!(".example.com\tTRUE\t/\tFALSE\t{}\tshared\t1", later),
+            format!("example.com\tFALSE\t/app\tTRUE\t{}\tsecure\t2", later),
+            "#HttpOnly_example.com\tFALSE\t/\tFALSE\t0\tsession\t3".to_string(),
+            format!("example.com\tFALSE\t/\tFALSE\t{}\texpired\t4", now() - 60),
+            "example.com\tFALSE\t/".to_string(),
+        ].join("\n")
+    }
+
+    #[test]
+    fn imports_netscape_cookies(){
+        let jar = CookieJar::new();
+        assert_eq!(jar.import_netscape(&cookies_txt()).unwrap(), 3);
+        assert_eq!(names(&jar, "http://example.com/"), vec!["session=3", "shared=1"]);
+        assert_eq!(names(&jar, "https://example.com/app/page"), vec!["secure=2", "session=3", "shared=1"]);
+        assert_eq!(names(&jar, "http://example.com/app/page"), vec!["session=3", "shared=1"]);
+        assert_eq!(names(&jar, "http://www.example.com/"), vec!["shared=1"]);
+        assert!(names(&jar, "http://example.org/").is_empty());
+    }
+
+    #[test]
+    fn exports_what_it_imports(){
+        let jar = CookieJar::new();
+        jar.import_netscape(&cookies_txt()).unwrap();
+        let exported = jar.export_netscape();
+        let mut lines: Vec<Vec<&str>> = exported.lines().filter(|l| !l.starts_with("# ")).map(|l| l.split('\t').collect()).collect();
+        lines.sort_by_key(|fields| fields[5]);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0][..4], ["example.com", "FALSE", "/app", "TRUE"]);
+        assert_eq!(lines[0][5..], ["secure", "2"]);
+        assert_eq!(lines[1], ["#HttpOnly_example.com", "FALSE", "/", "FALSE", "0", "session", "3"]);
+        assert_eq!(lines[2][..4], [".example.com", "TRUE", "/", "FALSE"]);
+        let expires: u64 = lines[2][4].parse().unwrap();
+        assert!(expires.abs_diff(now() + 3600) <= 2);
+
+        let copy = CookieJar::new();
+        assert_eq!(copy.import_netscape(&exported).unwrap(), 3);
+        for url in ["http://example.com/", "https://example.com/app/page", "http://www.example.com/"]{
+            assert_eq!(names(&copy, url), names(&jar, url), "{}", url);
+        }
+        assert_eq!(copy.export_netscape().lines().count(), exported.lines().count());
+    }
+
+    #[test]
+    fn stores_response_cookies(){
+        use reqwest::cookie::CookieStore as _;
+        let jar = CookieJar::new();
+        let url = Url::parse("http://example.com/login").unwrap();
+        let headers = [HeaderValue::from_static("a=1; Path=/"), HeaderValue::from_static("b=2; Path=/other"), HeaderValue::from_static("not a cookie")];
+        jar.set_cookies(&mut headers.iter(), &url);
+        assert_eq!(jar.cookies(&Url::parse("http://example.com/").unwrap()).unwrap(), "a=1");
+        assert!(jar.cookies(&Url::parse("http://example.org/").unwrap()).is_none());
+        jar.clear();
+        assert!(jar.cookies(&url).is_none());
+    }
+}
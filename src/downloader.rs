@@ -1,33 +1,136 @@
-use std::fs::File;  
-use std::io::{Write, Read};
-use std::sync::Arc; 
-use std::thread::{self, sleep};
-use std::time::Duration; 
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, sleep, ThreadId};
+use std::time::{Duration, Instant};
 use bytes::Bytes;
 use std::fs;
-use anyhow;  
-use std::path::Path;
+use anyhow;
+use std::path::{Path, PathBuf};
 use reqwest;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use flume::{Sender, Receiver};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::progress::{Progress, ProgressCallback};
+use crate::cache::{self, CacheMeta};
+use crate::encoding;
+use crate::request::{hex_digest, Request};
+use crate::cookie::CookieJar;
+use crate::fingerprint::{self, Dedup, DedupIndex};
+use crate::warc::{self, WarcArchive, WarcWriter};
+use crate::auth::{Auth, LoggedOut};
+use reqwest::header::{AUTHORIZATION, COOKIE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, USER_AGENT};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use tracing::{debug, error, info, info_span, warn, field};
 
 struct ReqMessage<E>{
-    url:String,
-    force: bool,
-    flag: Arc<E>,
+    request: Request<E>,
+    attempt: u32,
 }
 pub struct ResMessage<E>{
     pub url:String,
     pub data: anyhow::Result<Option<Bytes>>,
+    pub status: Option<u16>,
+    pub headers: HeaderMap,
+    pub charset: Option<String>,
+    pub hash: Option<String>,
+    pub fingerprint: Option<u64>,
+    pub duplicate_of: Option<String>,
     pub flag: Arc<E>,
+    pub attempt: u32,
+    request: Request<E>,
     downloader:Arc<Downloader<E>>
 }
+pub type Response<E> = ResMessage<E>;
+
+#[derive(Clone)]
+pub struct Page{
+    pub url: String,
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub charset: Option<String>,
+    pub hash: String,
+    pub fingerprint: Option<u64>,
+}
+
+impl Page{
+    pub fn text(&self) -> String{
+        encoding::decode(&self.body, encoding::resolve(self.charset.as_deref(), &self.headers, &self.body))
+    }
+}
+
+pub type Validator = Arc<dyn Fn(&Page) -> bool + Send + Sync>;
+#[derive(Default)]
+struct LoginState{
+    generation: u64,
+    running: Option<ThreadId>,
+}
+
+pub type LoginFn<E> = Arc<dyn Fn(&Downloader<E>) -> anyhow::Result<()> + Send + Sync>;
+
+#[derive(Debug)]
+pub struct InvalidContent{
+    pub url: String,
+}
+
+impl std::fmt::Display for InvalidContent{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        write!(f, "invalid content from {}", self.url)
+    }
+}
+
+impl std::error::Error for InvalidContent{}
+
+#[derive(Debug)]
+pub struct NotCached{
+    pub url: String,
+}
+
+impl std::fmt::Display for NotCached{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        write!(f, "{} is not cached", self.url)
+    }
+}
+
+impl std::error::Error for NotCached{}
+
+#[derive(Debug)]
+pub struct ServerError{
+    pub url: String,
+    pub status: u16,
+}
+
+impl std::fmt::Display for ServerError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        write!(f, "server error {} from {}", self.status, self.url)
+    }
+}
+
+impl std::error::Error for ServerError{}
+
 impl<E> ReqMessage<E>{
-    fn gen_res(&self, data: anyhow::Result<Option<Bytes>>, downloader:&Arc<Downloader<E>>) -> ResMessage<E>{
+    fn gen_res(&self, page: anyhow::Result<Option<Page>>, duplicate_of: Option<String>, downloader:&Arc<Downloader<E>>) -> ResMessage<E>{
+        let (data, status, headers, charset, hash, fingerprint) = match page{
+            Ok(Some(p)) => (Ok(Some(p.body)), Some(p.status), p.headers, p.charset, Some(p.hash), p.fingerprint),
+            Ok(None) => (Ok(None), None, HeaderMap::new(), None, None, None),
+            Err(e) => {
+                let status = e.downcast_ref::<ServerError>().map(|e| e.status);
+                (Err(e), status, HeaderMap::new(), None, None, None)
+            },
+        };
         ResMessage{
-            url: self.url.clone(),
-            data: data,
-            flag: Arc::clone(&self.flag),
+            url: self.request.full_url(),
+            data,
+            status,
+            headers,
+            charset,
+            hash,
+            fingerprint,
+            duplicate_of,
+            flag: Arc::clone(&self.request.flag),
+            attempt: self.attempt,
+            request: self.request.clone(),
             downloader: Arc::clone(downloader),
         }
     }
@@ -42,104 +145,658 @@ impl<E> Drop for ResMessage<E>{
 pub struct Downloader<E>{
     root_path: String,
     base_url: String,
+    created: Instant,
+    progress_interval: Duration,
+    progress_callback: Option<ProgressCallback>,
+    validator: Option<Validator>,
+    max_retries: u32,
+    cookies: Arc<CookieJar>,
+    cookie_file: Option<PathBuf>,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    auth: Option<Auth>,
+    login: Option<LoginFn<E>>,
+    logged_out: Option<Validator>,
+    login_state: Arc<(Mutex<LoginState>, Condvar)>,
+    revalidate: bool,
+    hashes: Arc<Mutex<BTreeMap<String, String>>>,
+    dedup: Option<Dedup>,
+    dedup_index: Arc<Mutex<DedupIndex>>,
+    warc: Option<Arc<Mutex<WarcWriter>>>,
+    warc_source: Option<Arc<WarcArchive>>,
+    offline: bool,
+    delay: Option<Duration>,
+    host_delays: HashMap<String, Duration>,
+    next_slot: Arc<Mutex<HashMap<String, Instant>>>,
+    user_agent: Option<String>,
+    headers: HeaderMap,
+    proxy: Option<reqwest::Proxy>,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>,
+    finished: Arc<AtomicBool>,
+    closed: Arc<AtomicBool>,
+    request_num: Arc<AtomicUsize>,
     start_index: Arc<AtomicUsize>,
     end_index: Arc<AtomicUsize>,
     download_num: Arc<AtomicUsize>,
     connect_num: Arc<AtomicUsize>,
     parse_num: Arc<AtomicUsize>,
-    req_sender:Sender<ReqMessage<E>>, 
-    req_receiver:Receiver<ReqMessage<E>>, 
-    res_sender:Sender<ResMessage<E>>, 
+    req_sender:Sender<ReqMessage<E>>,
+    req_receiver:Receiver<ReqMessage<E>>,
+    res_sender:Sender<ResMessage<E>>,
     res_receiver:Receiver<ResMessage<E>>
 }
 
+#[derive(Clone)]
+struct Session{
+    cookies: Arc<CookieJar>,
+    client: reqwest::blocking::Client,
+}
+
 struct ReqThreadArg<E>{
-    receiver:Receiver<ReqMessage<E>>, 
+    receiver:Receiver<ReqMessage<E>>,
     sender: Sender<ResMessage<E>>
 
 }
 pub struct ResThreadArg<E>{
-    receiver:Receiver<ResMessage<E>>, 
+    receiver:Receiver<ResMessage<E>>,
     sender: Sender<ReqMessage<E>>,
     downloader:Arc<Downloader<E>>
 }
 
 fn req_run<E: Send + Sync + 'static>(arg: ReqThreadArg<E>, downloader:Arc<Downloader<E>>){
-    loop{
-        match arg.receiver.recv() {
-            Ok(msg) => {
-                downloader.start_index.fetch_add(1, Ordering::Relaxed);
-                let data = downloader.download(msg.url.clone(), msg.force);
-                downloader.download_num.fetch_add(1, Ordering::Relaxed);
-                match arg.sender.send(msg.gen_res(data, &downloader)){
-                    Ok(_)=>{},
-                    Err(_)=>{}
-                };
+    while !downloader.is_closed() {
+        if let Ok(msg) = arg.receiver.recv_timeout(Duration::from_millis(100)) {
+            downloader.start_index.fetch_add(1, Ordering::Relaxed);
+            let (data, duplicate_of) = downloader.download(&msg.request, msg.attempt);
+            downloader.download_num.fetch_add(1, Ordering::Relaxed);
+            let invalid = matches!(&data, Err(e) if e.is::<InvalidContent>() || e.is::<LoggedOut>() || e.is::<ServerError>());
+            if invalid && msg.attempt < downloader.max_retries{
+                let attempt = msg.attempt + 1;
+                info!(url = %msg.request.url, attempt, "retry");
+                #[cfg(feature = "metrics")]
+                downloader.metrics.observe_retry();
+                if let Err(e) = downloader.send(ReqMessage{attempt, ..msg}){
+                    error!(error = %e, "request channel closed");
+                }
                 downloader.end_index.fetch_add(1, Ordering::Relaxed);
-            },
-            Err(_) => {},
+                continue;
+            }
+            if let Err(e) = arg.sender.send(msg.gen_res(data, duplicate_of, &downloader)){
+                error!(url = %e.0.url, "response channel closed");
+            }
+            downloader.end_index.fetch_add(1, Ordering::Relaxed);
         }
     }
 }
 
+fn progress_run<E: Send + Sync + 'static>(downloader:Arc<Downloader<E>>, callback: ProgressCallback){
+    loop{
+        sleep(downloader.progress_interval);
+        if downloader.finished.load(Ordering::Relaxed) || downloader.is_closed(){
+            break
+        }
+        callback(&downloader.progress());
+    }
+}
+
 impl<E: Send + Sync + 'static>  Downloader<E>
 {
     pub fn new(root_path: String, base_url: String) -> Downloader<E>{
         let (req_sender, req_receiver) = flume::unbounded();
         let (res_sender, res_receiver) = flume::unbounded();
         Downloader{
-            root_path: root_path,
-            base_url: base_url,
+            root_path,
+            base_url,
+            created: Instant::now(),
+            progress_interval: Duration::from_secs(10),
+            progress_callback: None,
+            validator: None,
+            max_retries: 3,
+            cookies: Arc::new(CookieJar::new()),
+            cookie_file: None,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            auth: None,
+            login: None,
+            logged_out: None,
+            login_state: Arc::new((Mutex::new(LoginState::default()), Condvar::new())),
+            revalidate: false,
+            hashes: Arc::new(Mutex::new(BTreeMap::new())),
+            dedup: None,
+            dedup_index: Arc::new(Mutex::new(DedupIndex::default())),
+            warc: None,
+            warc_source: None,
+            offline: false,
+            delay: None,
+            host_delays: HashMap::new(),
+            next_slot: Arc::new(Mutex::new(HashMap::new())),
+            user_agent: None,
+            headers: HeaderMap::new(),
+            proxy: None,
+            #[cfg(feature = "metrics")]
+            metrics: Arc::new(Metrics::new()),
+            finished: Arc::new(AtomicBool::new(false)),
+            closed: Arc::new(AtomicBool::new(false)),
+            request_num:Arc::new(AtomicUsize::new(0)),
             start_index:Arc::new(AtomicUsize::new(0)),
             end_index:Arc::new(AtomicUsize::new(0)),
             download_num:Arc::new(AtomicUsize::new(0)),
             connect_num:Arc::new(AtomicUsize::new(0)),
             parse_num:Arc::new(AtomicUsize::new(0)),
-            req_sender: req_sender,
-            req_receiver: req_receiver,
-            res_sender:res_sender,
-            res_receiver: res_receiver,
+            req_sender,
+            req_receiver,
+            res_sender,
+            res_receiver,
+        }
+    }
+    pub fn with_progress<F>(mut self, interval: Duration, callback: F) -> Downloader<E>
+    where F: Fn(&Progress) + Send + Sync + 'static
+    {
+        self.progress_interval = interval;
+        self.progress_callback = Some(Arc::new(callback));
+        self
+    }
+    #[cfg(feature = "progress-bar")]
+    pub fn with_progress_bar(self, interval: Duration) -> Downloader<E>{
+        let bar = crate::progress::ProgressBar::new();
+        self.with_progress(interval, move |p| bar.update(p))
+    }
+    pub fn with_validator<F>(mut self, validator: F) -> Downloader<E>
+    where F: Fn(&Page) -> bool + Send + Sync + 'static
+    {
+        self.validator = Some(Arc::new(validator));
+        self
+    }
+    pub fn with_retries(mut self, max_retries: u32) -> Downloader<E>{
+        self.max_retries = max_retries;
+        self
+    }
+    pub fn max_retries(&self) -> u32{
+        self.max_retries
+    }
+    pub fn with_cookie_file<P: Into<PathBuf>>(mut self, path: P) -> Downloader<E>{
+        let path = path.into();
+        if path.exists(){
+            if let Err(e) = self.cookies.load(&path){
+                warn!(path = %path.display(), error = %e, "load cookies failed");
+            }
+        }
+        self.cookie_file = Some(path);
+        self
+    }
+    pub fn cookies(&self) -> &Arc<CookieJar>{
+        &self.cookies
+    }
+    pub fn session(&self, name: &str) -> anyhow::Result<Arc<CookieJar>>{
+        Ok(self.get_session(Some(name))?.cookies)
+    }
+    pub fn save_cookies(&self) -> anyhow::Result<()>{
+        if let Some(path) = &self.cookie_file{
+            self.cookies.save(path)?;
+        }
+        Ok(())
+    }
+    pub fn with_auth(mut self, auth: Auth) -> Downloader<E>{
+        self.auth = Some(auth);
+        self
+    }
+    pub fn with_login<F>(mut self, login: F) -> Downloader<E>
+    where F: Fn(&Downloader<E>) -> anyhow::Result<()> + Send + Sync + 'static
+    {
+        self.login = Some(Arc::new(login));
+        self
+    }
+    pub fn with_logged_out<F>(mut self, logged_out: F) -> Downloader<E>
+    where F: Fn(&Page) -> bool + Send + Sync + 'static
+    {
+        self.logged_out = Some(Arc::new(logged_out));
+        self
+    }
+    /// Runs the form login and the login hook. The hook may fetch through this
+    /// downloader; requests from other threads wait until it returns.
+    pub fn login(&self) -> anyhow::Result<()>{
+        self.run_login(None)
+    }
+    fn run_login(&self, seen: Option<u64>) -> anyhow::Result<()>{
+        let (state, changed) = &*self.login_state;
+        let current = thread::current().id();
+        let mut guard = state.lock().unwrap();
+        while guard.running.is_some_and(|id| id != current){
+            guard = changed.wait(guard).unwrap();
+        }
+        if guard.running.is_some(){
+            if seen.is_some(){
+                return Ok(());
+            }
+            anyhow::bail!("login called from the login hook");
+        }
+        if seen.is_some_and(|seen| seen != guard.generation){
+            return Ok(());
+        }
+        guard.running = Some(current);
+        drop(guard);
+        let res = self.login_once();
+        let mut guard = state.lock().unwrap();
+        guard.running = None;
+        if res.is_ok(){
+            guard.generation += 1;
+        }
+        changed.notify_all();
+        res
+    }
+    fn login_once(&self) -> anyhow::Result<()>{
+        if let Some(Auth::Form{url, fields}) = &self.auth{
+            let page = self.fetch(&Request::new(url.clone()).with_form(fields)?)?;
+            if !(200..300).contains(&page.status){
+                anyhow::bail!("form login to {} failed with status {}", url, page.status);
+            }
+            info!(url = %url, status = page.status, "form login");
+        }
+        if let Some(login) = &self.login{
+            login(self)?;
+            info!("login");
         }
+        Ok(())
+    }
+    fn relogin(&self, seen: u64) -> anyhow::Result<()>{
+        self.run_login(Some(seen))
+    }
+    /// Current login generation, waiting while another thread logs in.
+    fn login_generation(&self) -> u64{
+        let (state, changed) = &*self.login_state;
+        let current = thread::current().id();
+        let mut guard = state.lock().unwrap();
+        while guard.running.is_some_and(|id| id != current){
+            guard = changed.wait(guard).unwrap();
+        }
+        guard.generation
+    }
+    fn has_login(&self) -> bool{
+        self.login.is_some() || matches!(self.auth, Some(Auth::Form{..}))
+    }
+    pub fn with_revalidate(mut self, revalidate: bool) -> Downloader<E>{
+        self.revalidate = revalidate;
+        self
+    }
+    pub fn with_dedup(mut self, dedup: Dedup) -> Downloader<E>{
+        self.dedup = Some(dedup);
+        self
+    }
+    pub fn with_warc(mut self, writer: WarcWriter) -> Downloader<E>{
+        self.warc = Some(Arc::new(Mutex::new(writer)));
+        self
     }
-    fn connect_real(&self, url:String, proxy:Option<reqwest::Proxy>) -> anyhow::Result<Bytes>{
-        let mut builder = reqwest::blocking::Client::builder();
-        match proxy {
-            Some(p)=>{
-                builder = builder.proxy(p);
+    pub fn with_warc_source(mut self, archive: WarcArchive) -> Downloader<E>{
+        self.warc_source = Some(Arc::new(archive));
+        self
+    }
+    pub fn with_offline(mut self, offline: bool) -> Downloader<E>{
+        self.offline = offline;
+        self
+    }
+    pub fn with_delay(mut self, delay: Duration) -> Downloader<E>{
+        self.delay = Some(delay).filter(|d| !d.is_zero());
+        self
+    }
+    pub fn with_host_delay<H: Into<String>>(mut self, host: H, delay: Duration) -> Downloader<E>{
+        self.host_delays.insert(host.into(), delay);
+        self
+    }
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Downloader<E>{
+        self.headers.insert(name, value);
+        self
+    }
+    pub fn with_proxy(mut self, url: &str) -> anyhow::Result<Downloader<E>>{
+        self.proxy = Some(reqwest::Proxy::all(url)?);
+        Ok(self)
+    }
+    pub fn with_user_agent<S: Into<String>>(mut self, user_agent: S) -> Downloader<E>{
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+    pub fn is_offline(&self) -> bool{
+        self.offline
+    }
+    pub fn content_hashes(&self) -> BTreeMap<String, String>{
+        self.hashes.lock().unwrap().clone()
+    }
+    pub fn in_scope(&self, url: &str) -> bool{
+        url.starts_with(self.base_url.as_str())
+    }
+    pub fn fetch<F>(&self, request: &Request<F>) -> anyhow::Result<Page>{
+        if self.offline{
+            return self.stored_page(request).ok_or_else(|| NotCached{url: request.full_url()}.into());
+        }
+        let res = self.connect_real(request, None)?;
+        let status = res.status().as_u16();
+        let headers = res.headers().clone();
+        let body = res.bytes()?;
+        let charset = if encoding::is_text(&headers){
+            Some(encoding::detect(&headers, &body).name().to_string())
+        }else{
+            None
+        };
+        let hash = hex_digest(&body);
+        Ok(Page{url: request.full_url(), status, headers, body, charset, hash, fingerprint: None})
+    }
+    // what an offline fetch can serve: the cached body, else the response in the warc source
+    fn stored_page<F>(&self, request: &Request<F>) -> Option<Page>{
+        let url = request.full_url();
+        let path = self.cache_path(request);
+        if let Ok(body) = cache::read_body(&path){
+            let meta = cache::read_meta(&path).unwrap_or_else(|| CacheMeta{status: 200, ..CacheMeta::default()});
+            if meta.status < 500{
+                let hash = meta.hash.clone().unwrap_or_else(|| hex_digest(&body));
+                return Some(Page{url, status: meta.status, headers: meta.header_map(), body, charset: meta.charset.clone(), hash, fingerprint: None});
+            }
+        }
+        let (status, headers, body) = self.warc_source.as_ref()?.get(&url).filter(|(status, _, _)| *status < 500)?;
+        let charset = if encoding::is_text(headers) {Some(encoding::detect(headers, body).name().to_string())} else {None};
+        Some(Page{url, status, headers: headers.clone(), body: body.clone(), charset, hash: hex_digest(body), fingerprint: None})
+    }
+    pub fn cache_path<F>(&self, request: &Request<F>) -> PathBuf{
+        let key = request.cache_key();
+        Path::join(Path::new(self.root_path.as_str()), cache::file_path(&key.chars().skip(self.base_url.len()).collect::<String>()))
+    }
+    pub fn cached_meta<F>(&self, request: &Request<F>) -> Option<CacheMeta>{
+        let path = self.cache_path(request);
+        if !path.is_file(){
+            return None;
+        }
+        cache::read_meta(&path)
+    }
+    fn build_client(&self, cookies: &Arc<CookieJar>, proxy:Option<reqwest::Proxy>) -> anyhow::Result<reqwest::blocking::Client>{
+        let mut builder = reqwest::blocking::Client::builder().cookie_provider(Arc::clone(cookies));
+        if let Some(user_agent) = &self.user_agent{
+            builder = builder.user_agent(user_agent.as_str());
+        }
+        if !self.headers.is_empty(){
+            builder = builder.default_headers(self.headers.clone());
+        }
+        if let Some(p) = proxy.or_else(|| self.proxy.clone()) {
+            builder = builder.proxy(p);
+        }
+        Ok(builder.build()?)
+    }
+    fn get_session(&self, name: Option<&str>) -> anyhow::Result<Session>{
+        let name = name.unwrap_or_default();
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get(name){
+            return Ok(session.clone());
+        }
+        let cookies = if name.is_empty(){
+            Arc::clone(&self.cookies)
+        }else{
+            Arc::new(CookieJar::new())
+        };
+        let session = Session{client: self.build_client(&cookies, None)?, cookies};
+        sessions.insert(name.to_string(), session.clone());
+        Ok(session)
+    }
+    fn throttle(&self, url: &str){
+        let host = reqwest::Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_string)).unwrap_or_default();
+        let Some(delay) = self.host_delays.get(&host).copied().or(self.delay).filter(|d| !d.is_zero()) else {
+            return;
+        };
+        let slot = {
+            let mut slots = self.next_slot.lock().unwrap();
+            let now = Instant::now();
+            let slot = slots.get(&host).copied().filter(|s| *s > now).unwrap_or(now);
+            slots.insert(host, slot + delay);
+            slot
+        };
+        let wait = slot.saturating_duration_since(Instant::now());
+        if !wait.is_zero(){
+            debug!(wait_ms = wait.as_millis() as u64, "politeness delay");
+            sleep(wait);
+        }
+    }
+    fn is_valid(&self, page: &Page) -> bool{
+        match &self.validator{
+            Some(validator) => validator(page),
+            None => true,
+        }
+    }
+    pub fn progress(&self) -> Progress{
+        let start_index = self.start_index.load(Ordering::Relaxed);
+        let end_index = self.end_index.load(Ordering::Relaxed);
+        Progress{
+            elapsed: self.created.elapsed(),
+            requested: self.request_num.load(Ordering::Relaxed),
+            downloaded: self.download_num.load(Ordering::Relaxed),
+            connected: self.connect_num.load(Ordering::Relaxed),
+            parsed: self.parse_num.load(Ordering::Relaxed),
+            queued: self.req_sender.len() + self.res_sender.len(),
+            in_flight: start_index.saturating_sub(end_index),
+            finished: self.finished.load(Ordering::Relaxed),
+        }
+    }
+    fn connect_real<F>(&self, request:&Request<F>, proxy:Option<reqwest::Proxy>) -> anyhow::Result<reqwest::blocking::Response>{
+        self.throttle(&request.full_url());
+        let (client, req, _) = self.prepare(request, proxy)?;
+        Ok(client.execute(req)?)
+    }
+
+    fn prepare<F>(&self, request:&Request<F>, proxy:Option<reqwest::Proxy>) -> anyhow::Result<(reqwest::blocking::Client, reqwest::blocking::Request, Arc<CookieJar>)>{
+        let session = self.get_session(request.session.as_deref())?;
+        let client = match proxy{
+            Some(p) => self.build_client(&session.cookies, Some(p))?,
+            None => session.client,
+        };
+        let mut req = client.request(request.method.clone(), request.full_url()).headers(request.headers.clone());
+        if let Some(body) = &request.body{
+            req = req.body(body.clone());
+        }
+        if let Some(timeout) = request.timeout{
+            req = req.timeout(timeout);
+        }
+        if !request.headers.contains_key(AUTHORIZATION) && self.in_scope(&request.full_url()){
+            match &self.auth{
+                Some(Auth::Basic{username, password}) => req = req.basic_auth(username, password.as_ref()),
+                Some(Auth::Bearer(token)) => req = req.bearer_auth(token),
+                _ => {},
+            }
+        }
+        Ok((client, req.build()?, session.cookies))
+    }
+
+    fn request_record(&self, req: &reqwest::blocking::Request, cookies: &CookieJar) -> Vec<u8>{
+        // the client adds its default headers and user agent when sending, record them too
+        let mut headers = self.headers.clone();
+        if let Some(user_agent) = self.user_agent.as_ref().and_then(|ua| HeaderValue::from_str(ua).ok()){
+            headers.insert(USER_AGENT, user_agent);
+        }
+        for name in req.headers().keys(){
+            headers.remove(name);
+        }
+        for (name, value) in req.headers().iter(){
+            headers.append(name, value.clone());
+        }
+        if let Some(cookie) = reqwest::cookie::CookieStore::cookies(cookies, req.url()){
+            headers.insert(COOKIE, cookie);
+        }
+        warc::http_request_block(req.method(), req.url(), &headers, req.body().and_then(|b| b.as_bytes()))
+    }
+
+    fn archive(&self, record: Option<Vec<u8>>, page: &Page, meta: &CacheMeta){
+        let (Some(warc), Some(record)) = (&self.warc, record) else {
+            return;
+        };
+        let mut fields: Vec<(String, String)> = meta.meta.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        if let Some(charset) = &meta.charset{
+            fields.push(("charset".to_string(), charset.clone()));
+        }
+        let mut writer = warc.lock().unwrap();
+        let result = if page.status == 304{
+            writer.write_not_modified(record, &page.url, &page.headers, &fields)
+        }else{
+            writer.write_exchange(record, &page.url, page.status, &page.headers, &page.body, &fields)
+        };
+        if let Err(e) = result{
+            warn!(error = %e, "warc write failed");
+        }
+    }
+
+    /// Returns the page, or `Ok(None)` with the original url when the content duplicates an earlier page.
+    fn download(&self, request:&Request<E>, attempt:u32) -> (anyhow::Result<Option<Page>>, Option<String>){
+        let span = info_span!("request", url = %request.full_url(), method = %request.method, attempt, force = request.force, status = field::Empty, cache_hit = field::Empty, bytes = field::Empty, duration_ms = field::Empty);
+        let _enter = span.enter();
+        let started = Instant::now();
+        let mut result = self.download_inner(request, &span);
+        span.record("duration_ms", started.elapsed().as_millis() as u64);
+        match &mut result{
+            Ok(Some(page)) => {
+                span.record("bytes", page.body.len());
+                self.hashes.lock().unwrap().insert(page.url.clone(), page.hash.clone());
+                debug!("request finished");
+                if let Some(dedup) = self.dedup{
+                    if let Dedup::Near(_) = dedup{
+                        page.fingerprint = fingerprint::page_fingerprint(page);
+                    }
+                    let duplicate = self.dedup_index.lock().unwrap().check(dedup, &page.url, &page.hash, page.fingerprint);
+                    if let Some(original) = duplicate{
+                        debug!(duplicate_of = %original, "duplicate content suppressed");
+                        return (Ok(None), Some(original));
+                    }
+                }
             },
-            None=>{}
+            Ok(None) => {},
+            Err(e) => warn!(error = %e, "request failed"),
         }
-        Ok(builder.build()?.get(url).send()?.bytes()?)
+        (result, None)
     }
-    
-    fn download(&self, url:String, force:bool) -> anyhow::Result<Option<Bytes>>{
-        if url.len() < self.base_url.len() || url[0..self.base_url.len()] != self.base_url{
+
+    fn download_inner(&self, request:&Request<E>, span:&tracing::Span) -> anyhow::Result<Option<Page>>{
+        let full_url = request.full_url();
+        let url = full_url.as_str();
+        if !self.in_scope(url){
+            debug!(base_url = %self.base_url, "rejected by scope");
             return Ok(None);
         }
-        let path = Path::join(Path::new(self.root_path.as_str()), url.as_str().chars().skip(self.base_url.len()).collect::<String>());
-        match path.parent(){
-            Some(p) =>  fs::create_dir_all(p)?,
-            None => {},
-        };
-        if !force {
-            match File::open(&path){
-                Ok(mut file) => {
-                    let size = file.metadata().map(|m| m.len() as usize).ok().unwrap_or(0);
-                    let mut buffer = Vec::with_capacity(size);
-                    file.read_to_end(&mut buffer)?;
-                    return Ok(Some(Bytes::from(buffer)));
-                },
-                Err(_) =>{}
+        let path = self.cache_path(request);
+        if let Some(p) = path.parent(){
+            if let Err(e) = fs::create_dir_all(p){
+                warn!(path = %p.display(), error = %e, "storage failed");
+                return Err(e.into());
+            }
+        }
+        let mut cached = None;
+        if !request.force || self.offline {
+            if let Ok(body) = cache::read_body(&path){
+                // bodies cached without metadata predate status tracking, treat them as successful
+                let meta = cache::read_meta(&path).unwrap_or_else(|| CacheMeta{status: 200, ..CacheMeta::default()});
+                let hash = meta.hash.clone().unwrap_or_else(|| hex_digest(&body));
+                let page = Page{url: url.to_string(), status: meta.status, headers: meta.header_map(), body, charset: meta.charset.clone(), hash, fingerprint: None};
+                if page.status >= 500{
+                    debug!(path = %path.display(), status = page.status, "cached server error ignored");
+                }else if !self.is_valid(&page){
+                    warn!(path = %path.display(), "cached content rejected by validator");
+                }else if self.revalidate && !self.offline && (page.headers.contains_key(ETAG) || page.headers.contains_key(LAST_MODIFIED)){
+                    cached = Some((page, meta));
+                }else{
+                    span.record("cache_hit", true);
+                    #[cfg(feature = "metrics")]
+                    self.metrics.observe_cache_hit();
+                    return Ok(Some(page));
+                }
+            }
+        }
+        if let (false, Some(archive)) = (request.force && !self.offline, &self.warc_source){
+            if let Some((status, headers, body)) = archive.get(url).filter(|(status, _, _)| *status < 500 && request.cache_key() == full_url){
+                let charset = if encoding::is_text(headers) {Some(encoding::detect(headers, body).name().to_string())} else {None};
+                let page = Page{url: url.to_string(), status, headers: headers.clone(), body: body.clone(), charset, hash: hex_digest(body), fingerprint: None};
+                if self.is_valid(&page){
+                    span.record("cache_hit", true);
+                    return Ok(Some(page));
+                }
             }
         }
+        span.record("cache_hit", false);
+        if self.offline{
+            debug!("not cached");
+            return Err(NotCached{url: url.to_string()}.into());
+        }
+        let conditional;
+        let request = match &cached{
+            Some((page, _)) => {
+                let mut r = request.clone();
+                if let Some(etag) = page.headers.get(ETAG){
+                    r.headers.insert(IF_NONE_MATCH, etag.clone());
+                }
+                if let Some(modified) = page.headers.get(LAST_MODIFIED){
+                    r.headers.insert(IF_MODIFIED_SINCE, modified.clone());
+                }
+                conditional = r;
+                &conditional
+            },
+            None => request,
+        };
 
-        let body = self.connect_real(url.clone(), None)?;
+        self.throttle(url);
+        let generation = self.login_generation();
+        let mut record = None;
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        let res = match self.prepare(request, None){
+            Ok((client, req, cookies)) => {
+                record = self.warc.as_ref().map(|_| self.request_record(&req, &cookies));
+                client.execute(req).map_err(anyhow::Error::from)
+            },
+            Err(e) => Err(e),
+        };
+        let res = match res{
+            Ok(res) => res,
+            Err(e) => {
+                #[cfg(feature = "metrics")]
+                self.metrics.observe_response(url, "error", started.elapsed(), 0);
+                return Err(e);
+            }
+        };
+        let status = res.status();
+        span.record("status", status.as_u16());
+        let headers = res.headers().clone();
+        let body = res.bytes()?;
+        #[cfg(feature = "metrics")]
+        self.metrics.observe_response(url, status.as_str(), started.elapsed(), body.len());
         self.connect_num.fetch_add(1, Ordering::Relaxed);
-        let mut file = File::create(path)?;
-        for chunk in body.chunks(4096){
-            file.write(chunk)?;
+        if let (304, Some((page, mut meta))) = (status.as_u16(), cached){
+            span.record("cache_hit", true);
+            debug!("not modified");
+            self.archive(record, &Page{status: 304, headers, ..page.clone()}, &meta);
+            meta.fetched = cache::now();
+            if let Err(e) = cache::write_meta(&path, &meta){
+                warn!(path = %path.display(), error = %e, "storage failed");
+            }
+            return Ok(Some(page));
         }
-        Ok(Some(body))
+        let mut meta = CacheMeta::new(url, status.as_u16(), &headers);
+        meta.meta = request.meta.clone();
+        if encoding::is_text(&headers){
+            meta.charset = Some(encoding::detect(&headers, &body).name().to_string());
+        }
+        let hash = hex_digest(&body);
+        meta.hash = Some(hash.clone());
+        let page = Page{url: url.to_string(), status: status.as_u16(), headers, body, charset: meta.charset.clone(), hash, fingerprint: None};
+        if status.is_server_error(){
+            self.archive(record, &page, &meta);
+            return Err(ServerError{url: url.to_string(), status: page.status}.into());
+        }
+        if self.logged_out.as_ref().map(|f| f(&page)).unwrap_or(false){
+            warn!("logged out");
+            if let Err(e) = self.relogin(generation){
+                error!(error = %e, "login failed");
+            }
+            return Err(LoggedOut{url: url.to_string()}.into());
+        }
+        if !self.is_valid(&page){
+            warn!("content rejected by validator");
+            return Err(InvalidContent{url: url.to_string()}.into());
+        }
+        if let Err(e) = cache::write_body(&path, &page.body).and_then(|_| cache::write_meta(&path, &meta)){
+            warn!(path = %path.display(), error = %e, "storage failed");
+            return Err(e.into());
+        }
+        self.archive(record, &page, &meta);
+        Ok(Some(page))
     }
     pub fn wait_finish(&self){
         loop{
@@ -152,37 +809,102 @@ impl<E: Send + Sync + 'static>  Downloader<E>
                 break
             }
         }
+        self.finished.store(true, Ordering::Relaxed);
+        if let Err(e) = self.save_cookies(){
+            warn!(error = %e, "save cookies failed");
+        }
+        if let Some(callback) = &self.progress_callback{
+            callback(&self.progress());
+        }
+    }
+    #[cfg(feature = "metrics")]
+    pub fn render_metrics(&self) -> String{
+        self.metrics.render(&self.progress())
+    }
+    pub fn close(&self){
+        self.closed.store(true, Ordering::Relaxed);
+    }
+    pub fn is_closed(&self) -> bool{
+        self.closed.load(Ordering::Relaxed)
     }
     pub fn start_url(&self, url:String, force:bool, url_flag: Arc<E>)-> anyhow::Result<()>{
-        let msg = ReqMessage{url: url, force: force, flag: url_flag};
+        self.start_request(Request::new(url).with_force(force).with_shared_flag(url_flag))
+    }
+    pub fn start_request(&self, request: Request<E>)-> anyhow::Result<()>{
+        self.send(ReqMessage{request, attempt: 0})
+    }
+    fn send(&self, msg: ReqMessage<E>) -> anyhow::Result<()>{
         self.req_sender.send(msg)?;
+        self.request_num.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 }
 
 impl<E: Send + Sync + 'static> ResMessage<E>{
+    pub fn request(&self) -> &Request<E>{
+        &self.request
+    }
+    pub fn body(&self) -> Option<&Bytes>{
+        match &self.data{
+            Ok(Some(body)) => Some(body),
+            _ => None,
+        }
+    }
+    pub fn encoding(&self) -> Option<&'static encoding_rs::Encoding>{
+        Some(encoding::resolve(self.charset.as_deref(), &self.headers, self.body()?))
+    }
+    pub fn can_retry(&self) -> bool{
+        self.attempt < self.downloader.max_retries && !matches!(&self.data, Err(e) if e.is::<NotCached>())
+    }
+    pub fn text(&self) -> Option<String>{
+        Some(encoding::decode(self.body()?, self.encoding()?))
+    }
     pub fn retry(&self, force:bool)-> anyhow::Result<()>{
-        self.downloader.start_url(self.url.clone(), force, Arc::clone(&self.flag))
+        let attempt = self.attempt + 1;
+        info!(url = %self.url, attempt, force, "retry");
+        #[cfg(feature = "metrics")]
+        self.downloader.metrics.observe_retry();
+        self.downloader.send(ReqMessage{request: self.request.clone().with_force(force), attempt})
     }
 }
 pub fn start_crawl<E:Send + Sync + 'static>(downloader:&Arc<Downloader<E>>, thread_num:u16){
+    if downloader.has_login() && !downloader.offline{
+        if let Err(e) = downloader.login(){
+            error!(error = %e, "login failed");
+        }
+    }
     for _ in 0..thread_num {
-        let d = Arc::clone(&downloader);
+        let d = Arc::clone(downloader);
         let t = ReqThreadArg{receiver: downloader.req_receiver.clone(), sender: downloader.res_sender.clone()};
         thread::spawn(move || req_run(t, d));
     }
+    if let Some(callback) = &downloader.progress_callback{
+        let d = Arc::clone(downloader);
+        let c = Arc::clone(callback);
+        thread::spawn(move || progress_run(d, c));
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub fn serve_metrics<E: Send + Sync + 'static, A: std::net::ToSocketAddrs>(downloader:&Arc<Downloader<E>>, addr: A) -> std::io::Result<()>{
+    let d = Arc::clone(downloader);
+    crate::metrics::serve(addr, move || d.render_metrics())
 }
 
 pub fn get_res_thread_arg<E>(downloader: &Arc<Downloader<E>>) -> ResThreadArg<E>{
     let sender: Sender<ReqMessage<E>> = downloader.req_sender.clone();
     let receiver = downloader.res_receiver.clone();
-    ResThreadArg{receiver: receiver, sender: sender, downloader:Arc::clone(downloader)}
+    ResThreadArg{receiver, sender, downloader:Arc::clone(downloader)}
 }
 
 impl<E: Send + Sync + 'static > ResThreadArg<E>{
     pub fn start_url(&self, url:String, force:bool, url_flag: Arc<E>)-> anyhow::Result<()>{
-        let msg = ReqMessage{url: url, force: force, flag: url_flag};
+        self.start_request(Request::new(url).with_force(force).with_shared_flag(url_flag))
+    }
+    pub fn start_request(&self, request: Request<E>)-> anyhow::Result<()>{
+        let msg = ReqMessage{request, attempt: 0};
         self.sender.send(msg)?;
+        self.downloader.request_num.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
@@ -191,4 +913,10 @@ impl<E: Send + Sync + 'static > ResThreadArg<E>{
         self.downloader.start_index.fetch_add(1, Ordering::Relaxed);
         Ok(msg)
     }
+
+    pub fn get_msg_timeout(&self, timeout: Duration) -> anyhow::Result<ResMessage<E>>{
+        let msg = self.receiver.recv_timeout(timeout)?;
+        self.downloader.start_index.fetch_add(1, Ordering::Relaxed);
+        Ok(msg)
+    }
 }
@@ -0,0 +1,116 @@
+use std::sync::OnceLock;
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8, UTF_16BE, UTF_16LE};
+use regex::bytes::Regex;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+
+const META_SCAN_LEN: usize = 4096;
+
+fn meta_regex() -> &'static Regex{
+    static META: OnceLock<Regex> = OnceLock::new();
+    META.get_or_init(|| Regex::new(r#"(?i)<meta[^>]+charset\s*=\s*["']?\s*([a-z0-9_\-:.]+)"#).unwrap())
+}
+
+pub fn charset_from_content_type(value: &str) -> Option<&'static Encoding>{
+    value.split(';')
+        .filter_map(|part| part.trim().split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, label)| Encoding::for_label(label.trim().trim_matches('"').as_bytes()))
+}
+
+pub fn charset_from_meta(body: &[u8]) -> Option<&'static Encoding>{
+    let head = &body[..body.len().min(META_SCAN_LEN)];
+    let label = meta_regex().captures(head)?.get(1)?;
+    let encoding = Encoding::for_label(label.as_bytes())?;
+    if encoding == UTF_16LE || encoding == UTF_16BE{
+        return Some(UTF_8);
+    }
+    Some(encoding)
+}
+
+pub fn is_text(headers: &HeaderMap) -> bool{
+    match headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()){
+        Some(value) => {
+            let value = value.to_ascii_lowercase();
+            ["text", "html", "xml", "json", "javascript"].iter().any(|t| value.contains(t))
+        },
+        None => true,
+    }
+}
+
+pub fn detect(headers: &HeaderMap, body: &[u8]) -> &'static Encoding{
+    if let Some((encoding, _)) = Encoding::for_bom(body){
+        return encoding;
+    }
+    if let Some(encoding) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).and_then(charset_from_content_type){
+        return encoding;
+    }
+    if let Some(encoding) = charset_from_meta(body){
+        return encoding;
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(body, true);
+    detector.guess(None, true)
+}
+
+pub fn resolve(charset: Option<&str>, headers: &HeaderMap, body: &[u8]) -> &'static Encoding{
+    match charset.and_then(|c| Encoding::for_label(c.as_bytes())){
+        Some(encoding) => encoding,
+        None => detect(headers, body),
+    }
+}
+
+pub fn decode(body: &[u8], encoding: &'static Encoding) -> String{
+    let (text, _, _) = encoding.decode(body);
+    text.into_owned()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use encoding_rs::{GB18030, GBK, SHIFT_JIS, WINDOWS_1252};
+    use reqwest::header::HeaderValue;
+
+    fn content_type(value: &str) -> HeaderMap{
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn prefers_bom_then_header_then_meta(){
+        let meta = b"<html><head><meta charset=\"shift_jis\"></head><body>hi</body></html>";
+        let mut bom = b"\xEF\xBB\xBF".to_vec();
+        bom.extend_from_slice(meta);
+        assert_eq!(detect(&content_type("text/html; charset=gbk"), &bom), UTF_8);
+        assert_eq!(detect(&content_type("text/html; charset=\"GBK\""), meta), GBK);
+        assert_eq!(detect(&content_type("text/html"), meta), SHIFT_JIS);
+        assert_eq!(detect(&content_type("text/html; charset=bogus"), meta), SHIFT_JIS);
+        assert_eq!(detect(&HeaderMap::new(), b"\xFF\xFEh\0i\0"), UTF_16LE);
+        assert_eq!(resolve(Some("windows-1252"), &content_type("text/html; charset=gbk"), meta), WINDOWS_1252);
+        assert_eq!(resolve(Some("unknown"), &HeaderMap::new(), meta), SHIFT_JIS);
+    }
+
+    #[test]
+    fn reads_meta_http_equiv_and_treats_utf16_as_utf8(){
+        let equiv = b"<meta http-equiv='Content-Type' content='text/html; charset=gb2312'>";
+        assert_eq!(charset_from_meta(equiv), Some(GBK));
+        assert_eq!(charset_from_meta(b"<META CHARSET=utf-16le>"), Some(UTF_8));
+        assert_eq!(detect(&HeaderMap::new(), b"<meta charset=\"utf-16\"><p>plain ascii</p>"), UTF_8);
+        let mut late = vec![b' '; META_SCAN_LEN];
+        late.extend_from_slice(b"<meta charset=\"gbk\">");
+        assert_eq!(charset_from_meta(&late), None);
+    }
+
+    #[test]
+    fn guesses_gb18030_pages_without_a_header(){
+        let text = "北京市东城区人民政府统计用区划代码和城乡划分代码，本页面列出所有街道办事处和乡镇的名称。".repeat(4);
+        let (body, _, _) = GB18030.encode(&text);
+        let encoding = detect(&HeaderMap::new(), &body);
+        assert!(encoding == GBK || encoding == GB18030, "{}", encoding.name());
+        assert_eq!(decode(&body, encoding), text);
+        assert!(is_text(&HeaderMap::new()));
+        assert!(is_text(&content_type("application/json")));
+        assert!(!is_text(&content_type("image/png")));
+    }
+}
@@ -0,0 +1,847 @@
+use std::collections::{BTreeMap, HashSet};
+use regex::Regex;
+use reqwest::Url;
+use scraper::{ElementRef, Html, Node};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use crate::downloader::Response;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Axis{
+    Child,
+    Descendant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Test{
+    Name(String),
+    Any,
+    Text,
+    Attr(String),
+    Current,
+    Parent,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand{
+    Attr(String),
+    Text,
+    Current,
+    Child(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Predicate{
+    Position(usize),
+    Last,
+    Exists(Operand),
+    Equals(Operand, String),
+    NotEquals(Operand, String),
+    Contains(Operand, String),
+    StartsWith(Operand, String),
+    Not(Box<Predicate>),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Step{
+    axis: Axis,
+    test: Test,
+    predicates: Vec<Predicate>,
+}
+
+/// The XPath subset used for extraction rules:
+///
+/// - steps `/` and `//`, element names, `*`, `.`, `..`, and a final `text()` or `@attr`
+/// - predicates `[n]`, `[last()]`, `[@a]`, `[b]`, `[text()]`, comparisons `=` and `!=` with a string,
+///   `contains(x, 's')`, `starts-with(x, 's')`, `not(...)`, `and`, `or` and parentheses,
+///   where `x` is `@a`, `text()`, `.` or a child name
+///
+/// Other axes, functions, unions and numeric comparisons are parse errors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct XPath{
+    absolute: bool,
+    steps: Vec<Step>,
+}
+
+struct Parser<'a>{
+    expr: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a>{
+    fn rest(&self) -> &'a str{
+        &self.expr[self.pos..]
+    }
+    fn skip_space(&mut self){
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+    fn eat(&mut self, token: &str) -> bool{
+        self.skip_space();
+        if self.rest().starts_with(token){
+            self.pos += token.len();
+            true
+        }else{
+            false
+        }
+    }
+    fn keyword(&mut self, word: &str) -> bool{
+        self.skip_space();
+        let rest = self.rest();
+        let boundary = rest.strip_prefix(word)
+            .map(|after| !after.starts_with(|c: char| c.is_alphanumeric() || c == '-' || c == '_' || c == ':'))
+            .unwrap_or(false);
+        if boundary{
+            self.pos += word.len();
+        }
+        boundary
+    }
+    fn expect(&mut self, token: &str) -> anyhow::Result<()>{
+        if !self.eat(token){
+            anyhow::bail!("expected {} at {} in xpath {}", token, self.pos, self.expr);
+        }
+        Ok(())
+    }
+    fn name(&mut self) -> anyhow::Result<String>{
+        self.skip_space();
+        let len = self.rest().find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_' || c == ':')).unwrap_or(self.rest().len());
+        if len == 0{
+            anyhow::bail!("expected name at {} in xpath {}", self.pos, self.expr);
+        }
+        let name = self.rest()[..len].to_string();
+        if name.contains("::"){
+            anyhow::bail!("unsupported axis {} in xpath {}", name, self.expr);
+        }
+        self.pos += len;
+        Ok(name)
+    }
+    fn literal(&mut self) -> anyhow::Result<String>{
+        self.skip_space();
+        let quote = match self.rest().chars().next(){
+            Some(q) if q == '\'' || q == '"' => q,
+            _ => anyhow::bail!("expected string at {} in xpath {}", self.pos, self.expr),
+        };
+        let end = match self.rest()[1..].find(quote){
+            Some(end) => end,
+            None => anyhow::bail!("unterminated string in xpath {}", self.expr),
+        };
+        let value = self.rest()[1..=end].to_string();
+        self.pos += end + 2;
+        Ok(value)
+    }
+
+    fn path(&mut self) -> anyhow::Result<XPath>{
+        self.skip_space();
+        let absolute = self.rest().starts_with('/');
+        let mut steps = Vec::new();
+        let mut axis = if self.eat("//") {Axis::Descendant} else {self.eat("/"); Axis::Child};
+        loop{
+            steps.push(self.step(axis)?);
+            if self.eat("//"){
+                axis = Axis::Descendant;
+            }else if self.eat("/"){
+                axis = Axis::Child;
+            }else{
+                break;
+            }
+        }
+        self.skip_space();
+        if !self.rest().is_empty(){
+            anyhow::bail!("unexpected {} in xpath {}", self.rest(), self.expr);
+        }
+        let values = steps.iter().position(|s| matches!(s.test, Test::Text | Test::Attr(_)));
+        if values.map(|i| i + 1 < steps.len()).unwrap_or(false){
+            anyhow::bail!("text() and @attribute must be the last step in xpath {}", self.expr);
+        }
+        Ok(XPath{absolute, steps})
+    }
+
+    fn step(&mut self, axis: Axis) -> anyhow::Result<Step>{
+        let test = if self.eat(".."){
+            Test::Parent
+        }else if self.eat("."){
+            Test::Current
+        }else if self.eat("*"){
+            Test::Any
+        }else if self.eat("@"){
+            Test::Attr(self.name()?)
+        }else if self.eat("text()"){
+            Test::Text
+        }else{
+            Test::Name(self.name()?.to_ascii_lowercase())
+        };
+        let mut predicates = Vec::new();
+        if matches!(test, Test::Text | Test::Attr(_)) && self.eat("["){
+            anyhow::bail!("predicates on text() or @attribute are not supported in xpath {}", self.expr);
+        }
+        while self.eat("["){
+            predicates.push(self.or()?);
+            self.expect("]")?;
+        }
+        Ok(Step{axis, test, predicates})
+    }
+
+    fn or(&mut self) -> anyhow::Result<Predicate>{
+        let mut left = self.and()?;
+        while self.keyword("or"){
+            left = Predicate::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+    fn and(&mut self) -> anyhow::Result<Predicate>{
+        let mut left = self.term()?;
+        while self.keyword("and"){
+            left = Predicate::And(Box::new(left), Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+    fn operand(&mut self) -> anyhow::Result<Operand>{
+        Ok(if self.eat("@"){
+            Operand::Attr(self.name()?)
+        }else if self.eat("text()"){
+            Operand::Text
+        }else if self.eat("."){
+            Operand::Current
+        }else{
+            Operand::Child(self.name()?.to_ascii_lowercase())
+        })
+    }
+    fn term(&mut self) -> anyhow::Result<Predicate>{
+        self.skip_space();
+        let digits = self.rest().find(|c: char| !c.is_ascii_digit()).unwrap_or(self.rest().len());
+        if digits > 0{
+            let position = self.rest()[..digits].parse()?;
+            self.pos += digits;
+            return Ok(Predicate::Position(position));
+        }
+        if self.eat("last()"){
+            return Ok(Predicate::Last);
+        }
+        if self.eat("("){
+            let inner = self.or()?;
+            self.expect(")")?;
+            return Ok(inner);
+        }
+        if self.eat("not("){
+            let inner = self.or()?;
+            self.expect(")")?;
+            return Ok(Predicate::Not(Box::new(inner)));
+        }
+        for (function, contains) in [("contains(", true), ("starts-with(", false)]{
+            if self.eat(function){
+                let operand = self.operand()?;
+                self.expect(",")?;
+                let value = self.literal()?;
+                self.expect(")")?;
+                return Ok(if contains {Predicate::Contains(operand, value)} else {Predicate::StartsWith(operand, value)});
+            }
+        }
+        let operand = self.operand()?;
+        if self.eat("!="){
+            return Ok(Predicate::NotEquals(operand, self.literal()?));
+        }
+        if self.eat("="){
+            return Ok(Predicate::Equals(operand, self.literal()?));
+        }
+        Ok(Predicate::Exists(operand))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Matched<'a>{
+    Element(ElementRef<'a>),
+    Value(String),
+}
+
+fn own_text(element: ElementRef<'_>) -> String{
+    element.children().filter_map(|n| match n.value(){
+        Node::Text(text) => Some(&**text),
+        _ => None,
+    }).collect()
+}
+
+fn operand_value(element: ElementRef<'_>, operand: &Operand) -> Option<String>{
+    match operand{
+        Operand::Attr(name) => element.value().attr(name).map(str::to_string),
+        Operand::Text => Some(own_text(element)),
+        Operand::Current => Some(element.text().collect()),
+        Operand::Child(name) => element.child_elements().find(|e| e.value().name() == name).map(|e| e.text().collect()),
+    }
+}
+
+fn matches(element: ElementRef<'_>, predicate: &Predicate, position: usize, size: usize) -> bool{
+    match predicate{
+        Predicate::Position(p) => position == *p,
+        Predicate::Last => position == size,
+        Predicate::Exists(operand) => match operand{
+            Operand::Text => !own_text(element).trim().is_empty(),
+            o => operand_value(element, o).is_some(),
+        },
+        Predicate::Equals(o, v) => operand_value(element, o).map(|s| s.trim() == v).unwrap_or(false),
+        Predicate::NotEquals(o, v) => operand_value(element, o).map(|s| s.trim() != v).unwrap_or(false),
+        Predicate::Contains(o, v) => operand_value(element, o).map(|s| s.contains(v.as_str())).unwrap_or(false),
+        Predicate::StartsWith(o, v) => operand_value(element, o).map(|s| s.trim_start().starts_with(v.as_str())).unwrap_or(false),
+        Predicate::Not(p) => !matches(element, p, position, size),
+        Predicate::And(a, b) => matches(element, a, position, size) && matches(element, b, position, size),
+        Predicate::Or(a, b) => matches(element, a, position, size) || matches(element, b, position, size),
+    }
+}
+
+fn filter<'a>(candidates: Vec<ElementRef<'a>>, predicates: &[Predicate]) -> Vec<ElementRef<'a>>{
+    let mut candidates = candidates;
+    for predicate in predicates{
+        let size = candidates.len();
+        candidates = candidates.into_iter().enumerate()
+            .filter(|(i, e)| matches(*e, predicate, i + 1, size))
+            .map(|(_, e)| e)
+            .collect();
+    }
+    candidates
+}
+
+impl XPath{
+    pub fn parse(expr: &str) -> anyhow::Result<XPath>{
+        Parser{expr, pos: 0}.path()
+    }
+
+    pub fn select<'a>(&self, element: ElementRef<'a>) -> Vec<Matched<'a>>{
+        // None stands for the document node above the root element
+        let top = element.ancestors().filter_map(ElementRef::wrap).last().unwrap_or(element);
+        let mut contexts: Vec<Option<ElementRef<'a>>> = vec![if self.absolute {None} else {Some(element)}];
+        let mut values = Vec::new();
+        for (index, step) in self.steps.iter().enumerate(){
+            let mut next = Vec::new();
+            let mut seen = HashSet::new();
+            for context in contexts.iter(){
+                let parents: Vec<Option<ElementRef<'a>>> = match (step.axis, context){
+                    (Axis::Child, _) => vec![*context],
+                    (Axis::Descendant, None) => std::iter::once(None).chain(top.descendent_elements().map(Some)).collect(),
+                    (Axis::Descendant, Some(c)) => c.descendent_elements().map(Some).collect(),
+                };
+                for parent in parents{
+                    let children: Vec<ElementRef<'a>> = match parent{
+                        Some(p) => p.child_elements().collect(),
+                        None => vec![top],
+                    };
+                    let candidates: Vec<ElementRef<'a>> = match &step.test{
+                        Test::Current | Test::Text | Test::Attr(_) => parent.into_iter().collect(),
+                        Test::Parent => parent.and_then(|p| p.parent()).and_then(ElementRef::wrap).into_iter().collect(),
+                        Test::Any => children,
+                        Test::Name(name) => children.into_iter().filter(|e| e.value().name() == name).collect(),
+                    };
+                    if index + 1 == self.steps.len(){
+                        match (&step.test, parent){
+                            (Test::Text, Some(p)) => {
+                                values.extend(p.children().filter_map(|n| match n.value(){
+                                    Node::Text(text) => Some(text.to_string()),
+                                    _ => None,
+                                }));
+                                continue;
+                            },
+                            (Test::Attr(name), Some(p)) => {
+                                values.extend(p.value().attr(name).map(str::to_string));
+                                continue;
+                            },
+                            _ => {},
+                        }
+                    }
+                    for element in filter(candidates, &step.predicates){
+                        if seen.insert(element.id()){
+                            next.push(Some(element));
+                        }
+                    }
+                }
+            }
+            contexts = next;
+        }
+        if matches!(self.steps.last().map(|s| &s.test), Some(Test::Text) | Some(Test::Attr(_))){
+            return values.into_iter().map(Matched::Value).collect();
+        }
+        contexts.into_iter().flatten().map(Matched::Element).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Query{
+    Css(scraper::Selector),
+    XPath(XPath),
+}
+
+impl Query{
+    pub fn css(query: &str) -> anyhow::Result<Query>{
+        scraper::Selector::parse(query)
+            .map(Query::Css)
+            .map_err(|e| anyhow::anyhow!("invalid selector {}: {}", query, e))
+    }
+    pub fn xpath(expr: &str) -> anyhow::Result<Query>{
+        Ok(Query::XPath(XPath::parse(expr)?))
+    }
+    pub fn select<'a>(&self, element: ElementRef<'a>) -> Vec<Matched<'a>>{
+        match self{
+            Query::Css(selector) => element.select(selector).map(Matched::Element).collect(),
+            Query::XPath(xpath) => xpath.select(element),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind{
+    #[default]
+    String,
+    Integer,
+    Float,
+    Bool,
+}
+
+impl Kind{
+    fn convert(self, value: String) -> Option<Value>{
+        match self{
+            Kind::String => Some(Value::String(value)),
+            Kind::Integer => value.replace(',', "").parse::<i64>().ok().map(Value::from),
+            Kind::Float => value.replace(',', "").parse::<f64>().ok().map(Value::from),
+            Kind::Bool => match value.to_ascii_lowercase().as_str(){
+                "true" | "yes" | "1" | "on" => Some(Value::Bool(true)),
+                "false" | "no" | "0" | "off" | "" => Some(Value::Bool(false)),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source{
+    Text,
+    Html,
+    Attr(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Field{
+    name: String,
+    query: Option<Query>,
+    source: Source,
+    regex: Option<Regex>,
+    kind: Kind,
+    url: bool,
+    many: bool,
+    fields: Vec<Field>,
+}
+
+fn join_url(base: &str, value: &str) -> String{
+    match Url::parse(base).and_then(|b| b.join(value.trim())){
+        Ok(url) => url.to_string(),
+        Err(_) => value.to_string(),
+    }
+}
+
+impl Field{
+    pub fn new(name: &str, query: Option<Query>) -> Field{
+        Field{name: name.to_string(), query, source: Source::Text, regex: None, kind: Kind::String, url: false, many: false, fields: Vec::new()}
+    }
+    pub fn css(name: &str, query: &str) -> anyhow::Result<Field>{
+        Ok(Field::new(name, Some(Query::css(query)?)))
+    }
+    pub fn xpath(name: &str, expr: &str) -> anyhow::Result<Field>{
+        Ok(Field::new(name, Some(Query::xpath(expr)?)))
+    }
+    pub fn attr(mut self, attr: &str) -> Field{
+        self.source = Source::Attr(attr.to_string());
+        self
+    }
+    pub fn html(mut self) -> Field{
+        self.source = Source::Html;
+        self
+    }
+    pub fn regex(mut self, pattern: &str) -> anyhow::Result<Field>{
+        self.regex = Some(Regex::new(pattern)?);
+        Ok(self)
+    }
+    pub fn kind(mut self, kind: Kind) -> Field{
+        self.kind = kind;
+        self
+    }
+    pub fn url(mut self) -> Field{
+        self.url = true;
+        self
+    }
+    pub fn many(mut self) -> Field{
+        self.many = true;
+        self
+    }
+    pub fn nested(mut self, fields: Vec<Field>) -> Field{
+        self.fields = fields;
+        self
+    }
+
+    fn value(&self, matched: Matched<'_>, url: &str) -> Option<Value>{
+        let element = match matched{
+            Matched::Element(element) if !self.fields.is_empty() => {
+                return Some(Value::Object(extract_fields(&self.fields, element, url)));
+            },
+            Matched::Element(element) => element,
+            Matched::Value(value) => return self.finish(value, url),
+        };
+        let value = match &self.source{
+            Source::Text => element.text().collect::<Vec<_>>().join(" ").split_whitespace().collect::<Vec<_>>().join(" "),
+            Source::Html => element.inner_html(),
+            Source::Attr(attr) => element.value().attr(attr)?.to_string(),
+        };
+        self.finish(value, url)
+    }
+
+    fn finish(&self, value: String, url: &str) -> Option<Value>{
+        let mut value = value.trim().to_string();
+        if let Some(regex) = &self.regex{
+            let captures = regex.captures(&value)?;
+            value = captures.get(1).or_else(|| captures.get(0))?.as_str().to_string();
+        }
+        if self.url{
+            value = join_url(url, &value);
+        }
+        self.kind.convert(value)
+    }
+
+    fn extract(&self, element: ElementRef<'_>, url: &str) -> Value{
+        let matched = match &self.query{
+            Some(query) => query.select(element),
+            None => vec![Matched::Element(element)],
+        };
+        let mut values = matched.into_iter().filter_map(|m| self.value(m, url));
+        if self.many{
+            Value::Array(values.collect())
+        }else{
+            values.next().unwrap_or(Value::Null)
+        }
+    }
+}
+
+fn extract_fields(fields: &[Field], element: ElementRef<'_>, url: &str) -> Map<String, Value>{
+    fields.iter().map(|f| (f.name.clone(), f.extract(element, url))).collect()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Extractor{
+    root: Option<Query>,
+    fields: Vec<Field>,
+}
+
+impl Extractor{
+    pub fn new() -> Extractor{
+        Extractor::default()
+    }
+    pub fn with_root(mut self, root: Query) -> Extractor{
+        self.root = Some(root);
+        self
+    }
+    pub fn with_field(mut self, field: Field) -> Extractor{
+        self.fields.push(field);
+        self
+    }
+
+    pub fn extract_document(&self, doc: &Html, url: &str) -> Vec<Value>{
+        let top = doc.root_element();
+        let roots: Vec<ElementRef<'_>> = match &self.root{
+            Some(root) => root.select(top).into_iter().filter_map(|m| match m{
+                Matched::Element(e) => Some(e),
+                Matched::Value(_) => None,
+            }).collect(),
+            None => vec![top],
+        };
+        roots.into_iter().map(|root| Value::Object(extract_fields(&self.fields, root, url))).collect()
+    }
+    pub fn extract(&self, html: &str, url: &str) -> Vec<Value>{
+        self.extract_document(&Html::parse_document(html), url)
+    }
+    pub fn extract_response<E: Send + Sync + 'static>(&self, response: &Response<E>) -> Vec<Value>{
+        match response.text(){
+            Some(text) => self.extract(&text, &response.url),
+            None => Vec::new(),
+        }
+    }
+    pub fn extract_as<T: DeserializeOwned>(&self, html: &str, url: &str) -> anyhow::Result<Vec<T>>{
+        let mut items = Vec::new();
+        for value in self.extract(html, url){
+            items.push(serde_json::from_value(value)?);
+        }
+        Ok(items)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum FieldRule{
+    Css(String),
+    Rule(FieldSpec),
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FieldSpec{
+    #[serde(alias = "selector")]
+    pub css: Option<String>,
+    pub xpath: Option<String>,
+    pub attr: Option<String>,
+    pub html: bool,
+    pub regex: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    pub url: bool,
+    pub many: bool,
+    pub fields: BTreeMap<String, FieldRule>,
+}
+
+fn query(css: &Option<String>, xpath: &Option<String>) -> anyhow::Result<Option<Query>>{
+    Ok(match (css, xpath){
+        (Some(_), Some(_)) => anyhow::bail!("use either css or xpath, not both"),
+        (Some(css), None) => Some(Query::css(css)?),
+        (None, Some(xpath)) => Some(Query::xpath(xpath)?),
+        (None, None) => None,
+    })
+}
+
+impl FieldRule{
+    pub fn compile(&self, name: &str) -> anyhow::Result<Field>{
+        let spec = match self{
+            FieldRule::Css(css) => return Field::css(name, css),
+            FieldRule::Rule(spec) => spec,
+        };
+        let mut field = Field::new(name, query(&spec.css, &spec.xpath)?).kind(spec.kind);
+        if let Some(attr) = &spec.attr{
+            field = field.attr(attr);
+        }
+        if spec.html{
+            field = field.html();
+        }
+        if let Some(regex) = &spec.regex{
+            field = field.regex(regex)?;
+        }
+        if spec.url{
+            field = field.url();
+        }
+        if spec.many{
+            field = field.many();
+        }
+        if !spec.fields.is_empty(){
+            field = field.nested(spec.fields.iter().map(|(n, f)| f.compile(n)).collect::<anyhow::Result<_>>()?);
+        }
+        Ok(field)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ExtractRule{
+    pub selector: Option<String>,
+    pub xpath: Option<String>,
+    pub fields: BTreeMap<String, FieldRule>,
+}
+
+impl ExtractRule{
+    pub fn compile(&self) -> anyhow::Result<Extractor>{
+        let mut extractor = Extractor::new();
+        if let Some(root) = query(&self.selector, &self.xpath)?{
+            extractor = extractor.with_root(root);
+        }
+        for (name, field) in self.fields.iter(){
+            extractor = extractor.with_field(field.compile(name)?);
+        }
+        Ok(extractor)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const PAGE: &str = r#"<html><body>
+        <table id="cities">
+            <tr class="head"><th>code</th><th>name</th></tr>
+            <tr class="citytr"><td>1101</td><td><a href="11/1101.html">Dongcheng</a></td></tr>
+            <tr class="citytr hot"><td>1102</td><td><a href="11/1102.html">Xicheng</a></td></tr>
+            <tr class="countytr"><td>1103</td><td>Chaoyang <b>district</b></td></tr>
+        </table>
+        <p>intro</p>
+    </body></html>"#;
+
+    fn select(expr: &str) -> Vec<String>{
+        let doc = Html::parse_document(PAGE);
+        XPath::parse(expr).unwrap().select(doc.root_element()).into_iter().map(|m| match m{
+            Matched::Element(e) => e.text().collect::<String>().trim().to_string(),
+            Matched::Value(v) => v.trim().to_string(),
+        }).collect()
+    }
+
+    fn parse_error(expr: &str) -> String{
+        XPath::parse(expr).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn selects_child_and_descendant_steps(){
+        assert_eq!(select("/html/body/p"), vec!["intro"]);
+        assert_eq!(select("//tr/td[1]"), vec!["1101", "1102", "1103"]);
+        assert_eq!(select("body/p"), vec!["intro"]);
+        assert_eq!(select("//table//a"), vec!["Dongcheng", "Xicheng"]);
+    }
+
+    #[test]
+    fn selects_wildcard_current_and_parent(){
+        assert_eq!(select("//tr[1]/*"), vec!["code", "name"]);
+        assert_eq!(select("//p/."), vec!["intro"]);
+        assert_eq!(select("//a/../../td[1]"), vec!["1101", "1102"]);
+    }
+
+    #[test]
+    fn selects_text_and_attributes(){
+        assert_eq!(select("//tr[4]/td[2]/text()"), vec!["Chaoyang"]);
+        assert_eq!(select("//a/@href"), vec!["11/1101.html", "11/1102.html"]);
+        assert_eq!(select("//table/@id"), vec!["cities"]);
+    }
+
+    #[test]
+    fn filters_by_position(){
+        assert_eq!(select("//td[1]"), vec!["1101", "1102", "1103"]);
+        assert_eq!(select("//tr[last()]/td[1]"), vec!["1103"]);
+        assert_eq!(select("//tr[contains(@class, 'tr')][2]/td[1]"), vec!["1102"]);
+    }
+
+    #[test]
+    fn filters_by_comparison(){
+        assert_eq!(select("//tr[@class='countytr']/td[1]"), vec!["1103"]);
+        assert_eq!(select("//tr[@class!='head']/td[1]"), vec!["1101", "1102", "1103"]);
+        assert_eq!(select("//td[text()='1102']"), vec!["1102"]);
+        assert_eq!(select("//td[.='Chaoyang district']/../td[1]"), vec!["1103"]);
+        assert_eq!(select("//tr[td='1101']/td[2]"), vec!["Dongcheng"]);
+    }
+
+    #[test]
+    fn filters_by_existence(){
+        assert_eq!(select("//tr[@class]/td[1]"), vec!["1101", "1102", "1103"]);
+        assert_eq!(select("//tr[th]/th[1]"), vec!["code"]);
+        assert_eq!(select("//td[text()]"), vec!["1101", "1102", "1103", "Chaoyang district"]);
+    }
+
+    #[test]
+    fn filters_by_functions(){
+        assert_eq!(select("//tr[contains(@class, 'hot')]/td[1]"), vec!["1102"]);
+        assert_eq!(select("//a[starts-with(@href, '11/1101')]"), vec!["Dongcheng"]);
+        assert_eq!(select("//tr[not(@class='head')][not(contains(@class, 'city'))]/td[1]"), vec!["1103"]);
+    }
+
+    #[test]
+    fn combines_predicates(){
+        assert_eq!(select("//tr[@class='citytr' or @class='countytr']/td[1]"), vec!["1101", "1103"]);
+        assert_eq!(select("//tr[contains(@class, 'citytr') and contains(@class, 'hot')]/td[1]"), vec!["1102"]);
+        assert_eq!(select("//tr[(@class='head' or @class='countytr') and td]/td[1]"), vec!["1103"]);
+        assert_eq!(select("//tr[@class='head' or(@class='countytr')]/td[1]"), vec!["1103"]);
+        assert_eq!(select("//tr[td and(contains(@class, 'hot'))]/td[1]"), vec!["1102"]);
+        assert_eq!(select("//tr[td and not(@class='head')]/td[1]"), vec!["1101", "1102", "1103"]);
+        assert!(parse_error("//tr[td andalso]").contains("expected ]"));
+    }
+
+    #[test]
+    fn rejects_unsupported_syntax(){
+        assert!(parse_error("//td/following-sibling::td").contains("unsupported axis"));
+        assert!(parse_error("//a/@href/text()").contains("last step"));
+        assert!(parse_error("//a/@href[1]").contains("predicates"));
+        assert!(parse_error("//a | //b").contains("unexpected"));
+        assert!(parse_error("count(//a)").contains("unexpected"));
+        assert!(parse_error("//tr[position()>1]").contains("expected ]"));
+        assert!(parse_error("//tr[@n > 1]").contains("expected ]"));
+        assert!(parse_error("//a/@*").contains("expected name"));
+        assert!(parse_error("//tr[@class='x").contains("unterminated"));
+        assert!(parse_error("//tr[1").contains("expected ]"));
+        assert!(parse_error("").contains("expected name"));
+    }
+
+    const URL: &str = "http://example.com/data/index.html";
+
+    #[test]
+    fn post_processes_fields(){
+        let extractor = Extractor::new()
+            .with_root(Query::css("tr.citytr").unwrap())
+            .with_field(Field::css("code", "td").unwrap().kind(Kind::Integer))
+            .with_field(Field::css("province", "td").unwrap().regex(r"^(\d\d)").unwrap())
+            .with_field(Field::css("link", "a").unwrap().attr("href").url())
+            .with_field(Field::css("html", "td:nth-child(2)").unwrap().html())
+            .with_field(Field::css("missing", "td").unwrap().regex("x+").unwrap());
+        let items = extractor.extract(PAGE, URL);
+        assert_eq!(items, vec![
+            serde_json::json!({"code": 1101, "province": "11", "link": "http://example.com/data/11/1101.html", "html": r#"<a href="11/1101.html">Dongcheng</a>"#, "missing": null}),
+            serde_json::json!({"code": 1102, "province": "11", "link": "http://example.com/data/11/1102.html", "html": r#"<a href="11/1102.html">Xicheng</a>"#, "missing": null}),
+        ]);
+    }
+
+    #[test]
+    fn converts_kinds(){
+        assert_eq!(Kind::String.convert(" 1 ".to_string()), Some(Value::from(" 1 ")));
+        assert_eq!(Kind::Integer.convert("1,234".to_string()), Some(Value::from(1234)));
+        assert_eq!(Kind::Integer.convert("1.5".to_string()), None);
+        assert_eq!(Kind::Float.convert("1,234.5".to_string()), Some(Value::from(1234.5)));
+        assert_eq!(Kind::Bool.convert("Yes".to_string()), Some(Value::Bool(true)));
+        assert_eq!(Kind::Bool.convert("".to_string()), Some(Value::Bool(false)));
+        assert_eq!(Kind::Bool.convert("maybe".to_string()), None);
+    }
+
+    #[test]
+    fn joins_relative_urls(){
+        assert_eq!(join_url(URL, "page.html"), "http://example.com/data/page.html");
+        assert_eq!(join_url(URL, " ../up.html "), "http://example.com/up.html");
+        assert_eq!(join_url(URL, "/root.html"), "http://example.com/root.html");
+        assert_eq!(join_url(URL, "https://other.org/x"), "https://other.org/x");
+        assert_eq!(join_url("not a url", "page.html"), "page.html");
+    }
+
+    #[test]
+    fn collects_many_and_nested_fields(){
+        let extractor = Extractor::new()
+            .with_field(Field::xpath("codes", "//tr/td[1]/text()").unwrap().kind(Kind::Integer).many())
+            .with_field(Field::css("rows", "tr[class]").unwrap().many().nested(vec![
+                Field::css("code", "td").unwrap(),
+                Field::css("links", "a").unwrap().attr("href").url().many(),
+            ]))
+            .with_field(Field::css("none", "li").unwrap().many());
+        let items = extractor.extract(PAGE, URL);
+        assert_eq!(items, vec![serde_json::json!({
+            "codes": [1101, 1102, 1103],
+            "rows": [
+                {"code": null, "links": []},
+                {"code": "1101", "links": ["http://example.com/data/11/1101.html"]},
+                {"code": "1102", "links": ["http://example.com/data/11/1102.html"]},
+                {"code": "1103", "links": []},
+            ],
+            "none": [],
+        })]);
+    }
+
+    #[test]
+    fn compiles_rules(){
+        let rule: ExtractRule = serde_json::from_value(serde_json::json!({
+            "selector": "tr.citytr",
+            "fields": {
+                "name": "a",
+                "code": {"xpath": "td[1]/text()", "type": "integer"},
+                "link": {"css": "a", "attr": "href", "url": true},
+                "cells": {"css": "td", "many": true, "regex": "^(\\d+)$"},
+                "anchor": {"css": "a", "fields": {"text": "b", "target": {"attr": "href"}}},
+            },
+        })).unwrap();
+        let items = rule.compile().unwrap().extract(PAGE, URL);
+        assert_eq!(items[0], serde_json::json!({
+            "name": "Dongcheng",
+            "code": 1101,
+            "link": "http://example.com/data/11/1101.html",
+            "cells": ["1101"],
+            "anchor": {"text": null, "target": "11/1101.html"},
+        }));
+        assert_eq!(items.len(), 2);
+
+        let both: ExtractRule = serde_json::from_value(serde_json::json!({"selector": "tr", "xpath": "//tr"})).unwrap();
+        assert!(both.compile().unwrap_err().to_string().contains("either css or xpath"));
+        let bad: ExtractRule = serde_json::from_value(serde_json::json!({"fields": {"code": {"css": "td", "regex": "("}}})).unwrap();
+        assert!(bad.compile().is_err());
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::{Namespace, ResolveResult};
+use quick_xml::NsReader;
+use reqwest::Url;
+use tracing::{debug, info, warn};
+use crate::downloader::{Downloader, Response};
+use crate::request::Request;
+use crate::sitemap::parse_w3c_datetime;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeedItem{
+    pub id: String,
+    pub url: String,
+    pub title: Option<String>,
+    pub updated: Option<String>,
+}
+
+impl FeedItem{
+    pub fn updated_time(&self) -> Option<u64>{
+        let updated = self.updated.as_deref()?;
+        parse_w3c_datetime(updated).or_else(|| parse_rfc822_datetime(updated))
+    }
+}
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+pub fn parse_rfc822_datetime(value: &str) -> Option<u64>{
+    let value = value.split_once(',').map(|(_, v)| v).unwrap_or(value);
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.len() < 4{
+        return None;
+    }
+    let day = parts[0];
+    let month = MONTHS.iter().position(|m| parts[1].to_ascii_lowercase().starts_with(m))? + 1;
+    let year = match parts[2].parse::<u32>().ok()?{
+        y if y < 50 => y + 2000,
+        y if y < 100 => y + 1900,
+        y => y,
+    };
+    let offset = match parts.get(4).copied().unwrap_or("GMT"){
+        "GMT" | "UT" | "UTC" | "Z" => "Z".to_string(),
+        "EST" => "-05:00".to_string(),
+        "EDT" => "-04:00".to_string(),
+        "CST" => "-06:00".to_string(),
+        "CDT" => "-05:00".to_string(),
+        "MST" => "-07:00".to_string(),
+        "MDT" => "-06:00".to_string(),
+        "PST" => "-08:00".to_string(),
+        "PDT" => "-07:00".to_string(),
+        o if o.len() == 5 && (o.starts_with('+') || o.starts_with('-')) => format!("{}:{}", &o[..3], &o[3..]),
+        _ => "Z".to_string(),
+    };
+    parse_w3c_datetime(&format!("{:04}-{:02}-{:0>2}T{}{}", year, month, day, parts[3], offset))
+}
+
+fn attr(e: &BytesStart, name: &str) -> Option<String>{
+    e.try_get_attribute(name).ok()??.unescape_value().ok().map(|v| v.to_string())
+}
+
+const FEED_NAMESPACES: [&[u8]; 4] = [
+    b"http://www.w3.org/2005/Atom",
+    b"http://purl.org/rss/1.0/",
+    b"http://purl.org/dc/elements/1.1/",
+    b"http://purl.org/dc/terms/",
+];
+
+fn is_feed_ns(ns: &ResolveResult) -> bool{
+    match ns{
+        ResolveResult::Bound(Namespace(ns)) => FEED_NAMESPACES.contains(ns),
+        ResolveResult::Unbound => true,
+        ResolveResult::Unknown(_) => false,
+    }
+}
+
+/// Parses RSS 1.0/2.0 items and Atom entries. Only direct children of an item are read,
+/// so `source/title` or `media:title` never replace the item title. Items without a link are skipped.
+pub fn parse(body: &[u8], base: &str) -> anyhow::Result<Vec<FeedItem>>{
+    let base = Url::parse(base).ok();
+    let mut reader = NsReader::from_reader(body);
+    reader.config_mut().trim_text(true);
+    let mut items = Vec::new();
+    let mut buf = Vec::new();
+    let mut depth = 0;
+    let mut item: Option<(usize, FeedItem)> = None;
+    let mut field: Option<String> = None;
+    loop{
+        match reader.read_resolved_event_into(&mut buf)?{
+            (ns, Event::Start(e)) => {
+                depth += 1;
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                let feed_ns = is_feed_ns(&ns);
+                field = None;
+                match item.as_mut(){
+                    None if feed_ns && (name == "item" || name == "entry") => item = Some((depth, FeedItem::default())),
+                    Some((item_depth, item)) if feed_ns && depth == *item_depth + 1 => {
+                        if let ("link", Some(href)) = (name.as_str(), attr(&e, "href")){
+                            set_link(item, &e, href);
+                        }
+                        field = Some(name);
+                    },
+                    _ => {},
+                }
+            },
+            (ns, Event::Empty(e)) => {
+                if let Some((item_depth, item)) = item.as_mut(){
+                    if *item_depth == depth && is_feed_ns(&ns) && e.local_name().as_ref() == b"link"{
+                        if let Some(href) = attr(&e, "href"){
+                            set_link(item, &e, href);
+                        }
+                    }
+                }
+            },
+            (_, Event::Text(e)) => {
+                if let (Some((_, item)), Some(field)) = (item.as_mut(), field.as_deref()){
+                    set_field(item, field, e.unescape()?.trim().to_string());
+                }
+            },
+            (_, Event::CData(e)) => {
+                if let (Some((_, item)), Some(field)) = (item.as_mut(), field.as_deref()){
+                    set_field(item, field, String::from_utf8_lossy(&e).trim().to_string());
+                }
+            },
+            (_, Event::End(_)) => {
+                field = None;
+                if item.as_ref().is_some_and(|(d, _)| *d == depth){
+                    if let Some((_, mut item)) = item.take().filter(|(_, i)| !i.url.is_empty()){
+                        if let Some(url) = base.as_ref().and_then(|b| b.join(&item.url).ok()){
+                            item.url = url.to_string();
+                        }
+                        if item.id.is_empty(){
+                            item.id = item.url.clone();
+                        }
+                        items.push(item);
+                    }
+                }
+                depth -= 1;
+            },
+            (_, Event::Eof) => break,
+            _ => {},
+        }
+        buf.clear();
+    }
+    Ok(items)
+}
+
+fn set_link(item: &mut FeedItem, e: &BytesStart, href: String){
+    let rel = attr(e, "rel").unwrap_or_else(|| "alternate".to_string());
+    if rel == "alternate" && (item.url.is_empty() || attr(e, "type").as_deref() == Some("text/html")){
+        item.url = href;
+    }
+}
+
+fn set_field(item: &mut FeedItem, field: &str, text: String){
+    match field{
+        "link" if item.url.is_empty() => item.url = text,
+        "guid" | "id" => item.id = text,
+        "title" => item.title = Some(text),
+        "pubDate" | "published" | "date" if item.updated.is_none() => item.updated = Some(text),
+        "updated" | "modified" => item.updated = Some(text),
+        _ => {},
+    }
+}
+
+pub struct FeedSource{
+    feeds: Vec<String>,
+    state_path: Option<PathBuf>,
+    seen: Mutex<BTreeMap<String, String>>,
+}
+
+impl FeedSource{
+    pub fn new() -> FeedSource{
+        FeedSource{feeds: Vec::new(), state_path: None, seen: Mutex::new(BTreeMap::new())}
+    }
+    pub fn with_feed<U: Into<String>>(mut self, url: U) -> FeedSource{
+        self.feeds.push(url.into());
+        self
+    }
+    pub fn with_state_file<P: Into<PathBuf>>(mut self, path: P) -> FeedSource{
+        let path = path.into();
+        match fs::read(&path){
+            Ok(data) => match serde_json::from_slice(&data){
+                Ok(seen) => self.seen = Mutex::new(seen),
+                Err(e) => warn!(path = %path.display(), error = %e, "feed state load failed"),
+            },
+            Err(e) => debug!(path = %path.display(), error = %e, "no feed state"),
+        }
+        self.state_path = Some(path);
+        self
+    }
+
+    pub fn is_seen(&self, item: &FeedItem) -> bool{
+        self.seen.lock().unwrap().get(&item.id) == Some(&item.updated.clone().unwrap_or_default())
+    }
+
+    pub fn mark_seen(&self, item: &FeedItem){
+        self.seen.lock().unwrap().insert(item.id.clone(), item.updated.clone().unwrap_or_default());
+    }
+
+    pub fn poll<E: Send + Sync + 'static>(&self, downloader: &Downloader<E>) -> anyhow::Result<Vec<FeedItem>>{
+        let mut items = Vec::new();
+        let mut failed = 0;
+        for url in self.feeds.iter(){
+            let page = match downloader.fetch(&Request::new(url.as_str())){
+                Ok(page) if page.status == 200 => page,
+                Ok(page) => {
+                    warn!(url = %url, status = page.status, "feed fetch failed");
+                    failed += 1;
+                    continue;
+                },
+                Err(e) => {
+                    warn!(url = %url, error = %e, "feed fetch failed");
+                    failed += 1;
+                    continue;
+                }
+            };
+            match parse(&page.body, url){
+                Ok(found) => {
+                    debug!(url = %url, items = found.len(), "feed");
+                    items.extend(found.into_iter().filter(|item| !self.is_seen(item)));
+                },
+                Err(e) => {
+                    warn!(url = %url, error = %e, "feed parse failed");
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 && failed == self.feeds.len(){
+            anyhow::bail!("all feeds failed");
+        }
+        Ok(items)
+    }
+
+    /// Queues the pages of new and updated items. An item only counts as seen once
+    /// `mark_fetched` gets its page back, so pages that fail are queued again on the next poll.
+    pub fn seed<E: Send + Sync + 'static>(&self, downloader: &Downloader<E>, flag: Arc<E>) -> anyhow::Result<usize>{
+        let items = self.poll(downloader)?;
+        for item in items.iter(){
+            let mut request = Request::new(item.url.as_str())
+                .with_meta("feed_id", item.id.as_str())
+                .with_force(self.seen.lock().unwrap().contains_key(&item.id));
+            if let Some(updated) = &item.updated{
+                request = request.with_meta("feed_updated", updated.as_str());
+            }
+            downloader.start_request(request.with_shared_flag(Arc::clone(&flag)))?;
+        }
+        info!(urls = items.len(), "seeded from feeds");
+        Ok(items.len())
+    }
+
+    /// Marks the item behind a seeded response as seen and saves the state, if the page was fetched.
+    pub fn mark_fetched<E: Send + Sync + 'static>(&self, response: &Response<E>) -> anyhow::Result<bool>{
+        let meta = &response.request().meta;
+        let id = match meta.get("feed_id"){
+            Some(id) => id,
+            None => return Ok(false),
+        };
+        let success = response.status.map(|status| (200..300).contains(&status)).unwrap_or(false);
+        if !success || response.body().is_none(){
+            debug!(url = %response.url, status = ?response.status, "feed item not fetched");
+            return Ok(false);
+        }
+        self.seen.lock().unwrap().insert(id.clone(), meta.get("feed_updated").cloned().unwrap_or_default());
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()>{
+        if let Some(path) = &self.state_path{
+            if let Some(p) = path.parent(){
+                fs::create_dir_all(p)?;
+            }
+            fs::write(path, serde_json::to_vec_pretty(&*self.seen.lock().unwrap())?)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for FeedSource{
+    fn default() -> Self{
+        FeedSource::new()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn parses_rss(){
+        let xml = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:media="http://search.yahoo.com/mrss/" xmlns:dc="http://purl.org/dc/elements/1.1/">
+<channel>
+  <title>Feed</title>
+  <link>https://ex.com/</link>
+  <item>
+    <title>First</title>
+    <link>/posts/1</link>
+    <guid isPermaLink="false">post-1</guid>
+    <pubDate>Tue, 02 Jan 2024 10:00:00 GMT</pubDate>
+    <source url="https://other.com/rss">Other <title>nested</title></source>
+    <media:title>Media title</media:title>
+  </item>
+  <item>
+    <title>No link</title>
+    <description>skipped</description>
+  </item>
+  <item>
+    <title><![CDATA[Second & more]]></title>
+    <link>https://ex.com/posts/2</link>
+    <dc:date>2024-01-03T00:00:00Z</dc:date>
+  </item>
+</channel>
+</rss>"#;
+        let items = parse(xml.as_bytes(), "https://ex.com/feed.xml").unwrap();
+        assert_eq!(items, vec![
+            FeedItem{
+                id: "post-1".to_string(),
+                url: "https://ex.com/posts/1".to_string(),
+                title: Some("First".to_string()),
+                updated: Some("Tue, 02 Jan 2024 10:00:00 GMT".to_string()),
+            },
+            FeedItem{
+                id: "https://ex.com/posts/2".to_string(),
+                url: "https://ex.com/posts/2".to_string(),
+                title: Some("Second & more".to_string()),
+                updated: Some("2024-01-03T00:00:00Z".to_string()),
+            },
+        ]);
+        assert_eq!(items[0].updated_time(), Some(1704189600));
+        assert_eq!(items[1].updated_time(), Some(1704240000));
+    }
+
+    #[test]
+    fn parses_atom(){
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:media="http://search.yahoo.com/mrss/">
+  <title>Feed</title>
+  <link href="https://ex.com/"/>
+  <entry>
+    <id>urn:1</id>
+    <title>Entry</title>
+    <link rel="self" href="https://ex.com/api/1"/>
+    <link rel="alternate" type="text/html" href="entries/1"/>
+    <link rel="enclosure" href="https://ex.com/1.mp3"/>
+    <published>2024-01-01T00:00:00Z</published>
+    <updated>2024-01-05T00:00:00Z</updated>
+    <source><id>urn:source</id><title>Source feed</title><link href="https://other.com/"/></source>
+    <media:group><media:title>Media</media:title></media:group>
+  </entry>
+  <entry>
+    <id>urn:2</id>
+    <title>Without link</title>
+  </entry>
+</feed>"#;
+        let items = parse(xml.as_bytes(), "https://ex.com/feed.atom").unwrap();
+        assert_eq!(items, vec![FeedItem{
+            id: "urn:1".to_string(),
+            url: "https://ex.com/entries/1".to_string(),
+            title: Some("Entry".to_string()),
+            updated: Some("2024-01-05T00:00:00Z".to_string()),
+        }]);
+    }
+
+    #[test]
+    fn parses_rss1(){
+        let xml = r#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#" xmlns="http://purl.org/rss/1.0/">
+  <channel rdf:about="https://ex.com/"><title>Feed</title><link>https://ex.com/</link></channel>
+  <item rdf:about="https://ex.com/a"><title>A</title><link>https://ex.com/a</link></item>
+</rdf:RDF>"#;
+        let items = parse(xml.as_bytes(), "https://ex.com/index.rdf").unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].url, "https://ex.com/a");
+        assert_eq!(items[0].title.as_deref(), Some("A"));
+    }
+
+    #[test]
+    fn parses_rfc822_datetimes(){
+        assert_eq!(parse_rfc822_datetime("Thu, 01 Jan 1970 00:01:00 GMT"), Some(60));
+        assert_eq!(parse_rfc822_datetime("1 Jan 70 01:00:00 +0100"), Some(0));
+        assert_eq!(parse_rfc822_datetime("Thu, 01 Jan 1970 00:00:00 EST"), Some(18000));
+        assert_eq!(parse_rfc822_datetime("yesterday"), None);
+    }
+}
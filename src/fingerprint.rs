@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use reqwest::header::CONTENT_TYPE;
+use scraper::{Html, Node};
+use crate::downloader::Page;
+use crate::encoding;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dedup{
+    Exact,
+    Near(u32),
+}
+
+const SHINGLE: usize = 5;
+
+fn fnv1a(data: &[u8]) -> u64{
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data{
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn visible_text(html: &str) -> String{
+    let doc = Html::parse_document(html);
+    let mut out = String::new();
+    for node in doc.tree.nodes(){
+        if let Node::Text(text) = node.value(){
+            let hidden = node.parent()
+                .and_then(|p| p.value().as_element())
+                .map(|e| matches!(e.name(), "script" | "style" | "noscript" | "template"))
+                .unwrap_or(false);
+            if !hidden{
+                out.push_str(text);
+                out.push(' ');
+            }
+        }
+    }
+    out
+}
+
+pub fn simhash(text: &str) -> u64{
+    let chars: Vec<char> = text.split_whitespace()
+        .flat_map(|w| w.chars().flat_map(char::to_lowercase).chain(std::iter::once(' ')))
+        .collect();
+    let mut weights = [0i64; 64];
+    for shingle in chars.windows(SHINGLE.min(chars.len().max(1))){
+        let hash = fnv1a(shingle.iter().collect::<String>().as_bytes());
+        for (i, w) in weights.iter_mut().enumerate(){
+            if hash >> i & 1 == 1 {*w += 1} else {*w -= 1}
+        }
+    }
+    weights.iter().enumerate()
+        .filter(|(_, w)| **w > 0)
+        .fold(0, |acc, (i, _)| acc | 1 << i)
+}
+
+pub fn hamming(a: u64, b: u64) -> u32{
+    (a ^ b).count_ones()
+}
+
+pub fn page_fingerprint(page: &Page) -> Option<u64>{
+    if !encoding::is_text(&page.headers){
+        return None;
+    }
+    let text = page.text();
+    let html = match page.headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()){
+        Some(value) => value.to_ascii_lowercase().contains("html"),
+        None => text.trim_start().starts_with('<'),
+    };
+    Some(simhash(&if html {visible_text(&text)} else {text}))
+}
+
+fn band(fingerprint: u64, index: usize, count: usize) -> u64{
+    let start = index * 64 / count;
+    let width = (index + 1) * 64 / count - start;
+    let mask = if width == 64 {u64::MAX} else {(1 << width) - 1};
+    fingerprint >> start & mask
+}
+
+/// Fingerprints within `distance` bits agree on at least one of `distance + 1` bands,
+/// so candidates are looked up by band instead of scanning every stored fingerprint.
+#[derive(Default)]
+pub(crate) struct DedupIndex{
+    hashes: HashMap<String, String>,
+    fingerprints: Vec<(u64, String)>,
+    bands: Vec<HashMap<u64, Vec<usize>>>,
+}
+
+impl DedupIndex{
+    pub(crate) fn check(&mut self, mode: Dedup, url: &str, hash: &str, fingerprint: Option<u64>) -> Option<String>{
+        match self.hashes.get(hash){
+            Some(u) if u != url => return Some(u.clone()),
+            Some(_) => return None,
+            None => {},
+        }
+        self.hashes.insert(hash.to_string(), url.to_string());
+        match (mode, fingerprint){
+            (Dedup::Near(distance), Some(fingerprint)) => self.check_near(distance, url, fingerprint),
+            _ => None,
+        }
+    }
+
+    fn check_near(&mut self, distance: u32, url: &str, fingerprint: u64) -> Option<String>{
+        let count = (distance as usize + 1).min(64);
+        if self.bands.len() != count{
+            self.bands = vec![HashMap::new(); count];
+            for (id, (f, _)) in self.fingerprints.iter().enumerate(){
+                for (i, band_index) in self.bands.iter_mut().enumerate(){
+                    band_index.entry(band(*f, i, count)).or_default().push(id);
+                }
+            }
+        }
+        let original = self.bands.iter().enumerate()
+            .filter_map(|(i, band_index)| band_index.get(&band(fingerprint, i, count)))
+            .flatten()
+            .copied()
+            .filter(|id| {
+                let (f, u) = &self.fingerprints[*id];
+                u != url && hamming(*f, fingerprint) <= distance
+            })
+            .min();
+        if let Some(id) = original{
+            return Some(self.fingerprints[id].1.clone());
+        }
+        let id = self.fingerprints.len();
+        self.fingerprints.push((fingerprint, url.to_string()));
+        for (i, band_index) in self.bands.iter_mut().enumerate(){
+            band_index.entry(band(fingerprint, i, count)).or_default().push(id);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn pseudo_random(seed: u64) -> u64{
+        fnv1a(&seed.to_le_bytes())
+    }
+
+    #[test]
+    fn finds_near_duplicates_by_band(){
+        let mut index = DedupIndex::default();
+        let base = pseudo_random(1);
+        assert_eq!(index.check(Dedup::Near(3), "a", "h1", Some(base)), None);
+        assert_eq!(index.check(Dedup::Near(3), "b", "h2", Some(base ^ 0b1011)), Some("a".to_string()));
+        assert_eq!(index.check(Dedup::Near(3), "c", "h3", Some(base ^ 0b11110)), None);
+        assert_eq!(index.check(Dedup::Near(3), "d", "h1", None), Some("a".to_string()));
+        assert_eq!(index.check(Dedup::Near(3), "a", "h1", Some(base)), None);
+    }
+
+    #[test]
+    fn band_lookup_matches_linear_scan(){
+        for distance in [0, 3, 6, 12]{
+            let mut index = DedupIndex::default();
+            let mut stored: Vec<(u64, String)> = Vec::new();
+            for i in 0..500u64{
+                let mut fingerprint = pseudo_random(i % 40);
+                for flip in 0..(i % 9){
+                    fingerprint ^= 1 << (pseudo_random(i * 31 + flip) % 64);
+                }
+                let url = format!("u{}", i);
+                let expected = stored.iter().find(|(f, _)| hamming(*f, fingerprint) <= distance).map(|(_, u)| u.clone());
+                let found = index.check(Dedup::Near(distance), &url, &format!("h{}", i), Some(fingerprint));
+                assert_eq!(found, expected, "distance {} item {}", distance, i);
+                if expected.is_none(){
+                    stored.push((fingerprint, url));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn simhash_of_similar_text_is_close(){
+        let a = simhash("the quick brown fox jumps over the lazy dog near the river bank today");
+        let b = simhash("the quick brown fox jumps over the lazy dog near the river bank");
+        let c = simhash("completely different content about parquet exporters and sqlite");
+        assert!(hamming(a, b) < hamming(a, c));
+    }
+}
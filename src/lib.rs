@@ -1 +1,28 @@
-pub mod downloader;
\ No newline at end of file
+pub mod downloader;
+pub mod request;
+pub mod cookie;
+pub mod auth;
+pub mod progress;
+pub mod cache;
+pub mod encoding;
+pub mod spider;
+pub mod pipeline;
+pub mod schema;
+#[cfg(feature = "parquet")]
+pub mod parquet_export;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_export;
+pub mod link;
+pub mod extract;
+pub mod mirror;
+pub mod fingerprint;
+pub mod warc;
+pub mod sitemap;
+pub mod feed;
+pub mod schedule;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "testing")]
+pub mod testing;
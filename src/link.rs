@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use regex::Regex;
+use reqwest::Url;
+use scraper::{Html, Selector};
+use crate::downloader::Response;
+use crate::mirror;
+
+const DEFAULT_TAGS: [(&str, &str); 9] = [
+    ("a", "href"),
+    ("area", "href"),
+    ("link", "href"),
+    ("img", "src"),
+    ("img", "srcset"),
+    ("source", "srcset"),
+    ("script", "src"),
+    ("iframe", "src"),
+    ("frame", "src"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link{
+    pub url: String,
+    pub tag: String,
+    pub text: String,
+    pub nofollow: bool,
+}
+
+#[derive(Clone)]
+pub struct LinkExtractor{
+    tags: Vec<(String, String)>,
+    allow: Vec<Regex>,
+    deny: Vec<Regex>,
+    follow_nofollow: bool,
+}
+
+pub fn canonicalize(base: &Url, href: &str) -> Option<Url>{
+    let href = href.trim();
+    if href.is_empty(){
+        return None;
+    }
+    let mut url = base.join(href).ok()?;
+    if url.scheme() != "http" && url.scheme() != "https"{
+        return None;
+    }
+    url.set_fragment(None);
+    if url.query() == Some(""){
+        url.set_query(None);
+    }
+    Some(url)
+}
+
+pub fn scope_of(url: &str) -> anyhow::Result<String>{
+    Ok(Url::parse(url)?.join("./")?.to_string())
+}
+
+fn parse_srcset(srcset: &str) -> Vec<&str>{
+    srcset.split(',')
+        .filter_map(|candidate| candidate.split_whitespace().next())
+        .collect()
+}
+
+impl LinkExtractor{
+    pub fn new() -> LinkExtractor{
+        LinkExtractor{
+            tags: DEFAULT_TAGS.iter().map(|(t, a)| (t.to_string(), a.to_string())).collect(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            follow_nofollow: false,
+        }
+    }
+    pub fn with_tags(mut self, tags: &[(&str, &str)]) -> LinkExtractor{
+        self.tags = tags.iter().map(|(t, a)| (t.to_string(), a.to_string())).collect();
+        self
+    }
+    pub fn allow(mut self, pattern: &str) -> anyhow::Result<LinkExtractor>{
+        self.allow.push(Regex::new(pattern)?);
+        Ok(self)
+    }
+    pub fn deny(mut self, pattern: &str) -> anyhow::Result<LinkExtractor>{
+        self.deny.push(Regex::new(pattern)?);
+        Ok(self)
+    }
+    pub fn follow_nofollow(mut self, follow: bool) -> LinkExtractor{
+        self.follow_nofollow = follow;
+        self
+    }
+
+    pub fn matches(&self, url: &str) -> bool{
+        if !self.allow.is_empty() && !self.allow.iter().any(|r| r.is_match(url)){
+            return false;
+        }
+        !self.deny.iter().any(|r| r.is_match(url))
+    }
+
+    pub fn extract_links(&self, page_url: &str, html: &str) -> anyhow::Result<Vec<Link>>{
+        let doc = Html::parse_document(html);
+        let mut base = Url::parse(page_url)?;
+        if let Ok(selector) = Selector::parse("base[href]"){
+            if let Some(href) = doc.select(&selector).next().and_then(|n| n.value().attr("href")){
+                if let Ok(b) = base.join(href.trim()){
+                    base = b;
+                }
+            }
+        }
+        let mut seen = HashSet::new();
+        let mut links = Vec::new();
+        let query = self.tags.iter().map(|(t, a)| format!("{}[{}]", t, a)).collect::<Vec<_>>().join(", ");
+        let selector = match Selector::parse(&query){
+            Ok(selector) => selector,
+            Err(_) => return Ok(links),
+        };
+        for node in doc.select(&selector){
+            let tag = node.value().name();
+            let nofollow = node.value().attr("rel")
+                .map(|rel| rel.split_whitespace().any(|r| r.eq_ignore_ascii_case("nofollow")))
+                .unwrap_or(false);
+            if nofollow && !self.follow_nofollow{
+                continue;
+            }
+            for (_, attr) in self.tags.iter().filter(|(t, _)| t.eq_ignore_ascii_case(tag)){
+                let value = match node.value().attr(attr){
+                    Some(v) => v,
+                    None => continue,
+                };
+                let hrefs = if attr == "srcset"{
+                    parse_srcset(value)
+                }else{
+                    vec![value]
+                };
+                for href in hrefs{
+                    let url = match canonicalize(&base, href){
+                        Some(u) => u.to_string(),
+                        None => continue,
+                    };
+                    if !self.matches(&url) || !seen.insert(url.clone()){
+                        continue;
+                    }
+                    links.push(Link{
+                        url,
+                        tag: tag.to_string(),
+                        text: node.text().collect::<String>().trim().to_string(),
+                        nofollow,
+                    });
+                }
+            }
+        }
+        Ok(links)
+    }
+
+    pub fn extract(&self, page_url: &str, html: &str) -> anyhow::Result<Vec<String>>{
+        Ok(self.extract_links(page_url, html)?.into_iter().map(|l| l.url).collect())
+    }
+
+    pub fn extract_response<E: Send + Sync + 'static>(&self, response: &Response<E>) -> anyhow::Result<Vec<String>>{
+        if !mirror::is_html(&response.headers, &response.url){
+            return Ok(Vec::new());
+        }
+        match response.text(){
+            Some(text) => self.extract(&response.url, &text),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+impl Default for LinkExtractor{
+    fn default() -> Self{
+        LinkExtractor::new()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const URL: &str = "http://example.com/dir/page.html?x=1";
+
+    fn urls(extractor: &LinkExtractor, html: &str) -> Vec<String>{
+        extractor.extract(URL, html).unwrap()
+    }
+
+    #[test]
+    fn canonicalizes_urls(){
+        let base = Url::parse(URL).unwrap();
+        let canonical = |href: &str| canonicalize(&base, href).map(|u| u.to_string());
+        assert_eq!(canonical(" next.html#top "), Some("http://example.com/dir/next.html".to_string()));
+        assert_eq!(canonical("../up.html?"), Some("http://example.com/up.html".to_string()));
+        assert_eq!(canonical("?page=2"), Some("http://example.com/dir/page.html?page=2".to_string()));
+        assert_eq!(canonical("//cdn.example.com/a.js"), Some("http://cdn.example.com/a.js".to_string()));
+        assert_eq!(canonical("HTTPS://Example.COM/A"), Some("https://example.com/A".to_string()));
+        assert_eq!(canonical("#top"), Some("http://example.com/dir/page.html?x=1".to_string()));
+        assert_eq!(canonical("mailto:me@example.com"), None);
+        assert_eq!(canonical("javascript:void(0)"), None);
+        assert_eq!(canonical("  "), None);
+        assert_eq!(scope_of(URL).unwrap(), "http://example.com/dir/");
+    }
+
+    #[test]
+    fn resolves_against_the_base_element(){
+        let html = r#"<head><base href="/other/"></head><body><a href="a.html">a</a><a href="/b.html">b</a></body>"#;
+        assert_eq!(urls(&LinkExtractor::new(), html), vec!["http://example.com/other/a.html", "http://example.com/b.html"]);
+    }
+
+    #[test]
+    fn skips_nofollow_unless_asked(){
+        let html = r#"<a href="a.html" rel="external NoFollow">a</a><a href="b.html">b</a>"#;
+        assert_eq!(urls(&LinkExtractor::new(), html), vec!["http://example.com/dir/b.html"]);
+        let links = LinkExtractor::new().follow_nofollow(true).extract_links(URL, html).unwrap();
+        assert_eq!(links.iter().map(|l| (l.url.as_str(), l.nofollow)).collect::<Vec<_>>(), vec![
+            ("http://example.com/dir/a.html", true),
+            ("http://example.com/dir/b.html", false),
+        ]);
+    }
+
+    #[test]
+    fn reads_srcset_candidates(){
+        let html = r#"<img src="small.png" srcset="small.png 1x, large.png 2x,huge.png 3x">
+            <picture><source srcset=" wide.webp 800w , narrow.webp 400w"></picture>"#;
+        assert_eq!(urls(&LinkExtractor::new(), html), vec![
+            "http://example.com/dir/small.png",
+            "http://example.com/dir/large.png",
+            "http://example.com/dir/huge.png",
+            "http://example.com/dir/wide.webp",
+            "http://example.com/dir/narrow.webp",
+        ]);
+    }
+
+    #[test]
+    fn filters_by_allow_and_deny_patterns(){
+        let html = r#"<a href="/news/1.html">1</a><a href="/news/2.html#c">2</a><a href="/news/login.html">l</a>
+            <a href="/about.html">a</a><a href="/news/1.html">again</a>"#;
+        let extractor = LinkExtractor::new().allow(r"/news/").unwrap().deny(r"login").unwrap();
+        assert_eq!(urls(&extractor, html), vec!["http://example.com/news/1.html", "http://example.com/news/2.html"]);
+        let extractor = LinkExtractor::new().deny(r"\.html$").unwrap();
+        assert!(urls(&extractor, html).is_empty());
+        let links = LinkExtractor::new().with_tags(&[("a", "href")]).extract_links(URL, r#"<a href="x.html"> Next <b>page</b> </a><img src="i.png">"#).unwrap();
+        assert_eq!(links, vec![Link{url: "http://example.com/dir/x.html".to_string(), tag: "a".to_string(), text: "Next page".to_string(), nofollow: false}]);
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::{Read, Write as IoWrite};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use crate::progress::Progress;
+
+const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const SIZE_BUCKETS: [f64; 8] = [1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0];
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Histogram{
+    buckets: &'static [f64],
+    counts: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: Mutex<f64>,
+}
+
+impl Histogram{
+    fn new(buckets: &'static [f64]) -> Histogram{
+        Histogram{
+            buckets,
+            counts: buckets.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: Mutex::new(0.0),
+        }
+    }
+    fn observe(&self, value: f64){
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter()){
+            if value <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        *self.sum.lock().unwrap() += value;
+    }
+    fn render(&self, out: &mut String, name: &str, help: &str){
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter()){
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count.load(Ordering::Relaxed));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.lock().unwrap());
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+pub struct Metrics{
+    responses: Mutex<BTreeMap<(String, String), u64>>,
+    latency: Histogram,
+    size: Histogram,
+    bytes: AtomicU64,
+    retries: AtomicU64,
+    cache_hits: AtomicU64,
+}
+
+fn escape_label(value: &str) -> String{
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render_value(out: &mut String, name: &str, kind: &str, help: &str, value: u64){
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+impl Metrics{
+    pub fn new() -> Metrics{
+        Metrics{
+            responses: Mutex::new(BTreeMap::new()),
+            latency: Histogram::new(&LATENCY_BUCKETS),
+            size: Histogram::new(&SIZE_BUCKETS),
+            bytes: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+        }
+    }
+    pub fn observe_response(&self, url: &str, status: &str, latency: Duration, bytes: usize){
+        let host = reqwest::Url::parse(url).ok().and_then(|u| u.host_str().map(String::from)).unwrap_or_default();
+        *self.responses.lock().unwrap().entry((host, status.to_string())).or_insert(0) += 1;
+        self.latency.observe(latency.as_secs_f64());
+        self.size.observe(bytes as f64);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub fn observe_retry(&self){
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn observe_cache_hit(&self){
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn render(&self, progress: &Progress) -> String{
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP crawl_responses_total Network responses by host and status.");
+        let _ = writeln!(out, "# TYPE crawl_responses_total counter");
+        for ((host, status), count) in self.responses.lock().unwrap().iter(){
+            let _ = writeln!(out, "crawl_responses_total{{host=\"{}\",status=\"{}\"}} {}", escape_label(host), escape_label(status), count);
+        }
+        self.latency.render(&mut out, "crawl_request_duration_seconds", "Network request latency.");
+        self.size.render(&mut out, "crawl_response_size_bytes", "Network response body size.");
+        render_value(&mut out, "crawl_response_bytes_total", "counter", "Bytes received from the network.", self.bytes.load(Ordering::Relaxed));
+        render_value(&mut out, "crawl_retries_total", "counter", "Requests sent again after a failure.", self.retries.load(Ordering::Relaxed));
+        render_value(&mut out, "crawl_cache_hits_total", "counter", "Requests served from the cache.", self.cache_hits.load(Ordering::Relaxed));
+        render_value(&mut out, "crawl_requested_total", "counter", "Requests queued.", progress.requested as u64);
+        render_value(&mut out, "crawl_downloaded_total", "counter", "Requests downloaded.", progress.downloaded as u64);
+        render_value(&mut out, "crawl_parsed_total", "counter", "Responses handled by parsers.", progress.parsed as u64);
+        render_value(&mut out, "crawl_queue_depth", "gauge", "Messages waiting in the request and response queues.", progress.queued as u64);
+        render_value(&mut out, "crawl_in_flight", "gauge", "Messages being downloaded or parsed.", progress.in_flight as u64);
+        out
+    }
+}
+
+impl Default for Metrics{
+    fn default() -> Self{
+        Metrics::new()
+    }
+}
+
+pub(crate) fn serve<A, F>(addr: A, render: F) -> std::io::Result<()>
+where A: ToSocketAddrs, F: Fn() -> String + Send + Sync + 'static
+{
+    let listener = TcpListener::bind(addr)?;
+    let render = Arc::new(render);
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten(){
+            let render = Arc::clone(&render);
+            // one thread per scrape, so a client that never sends its request can't block the others
+            std::thread::spawn(move || {
+                let _ = stream.set_read_timeout(Some(SCRAPE_TIMEOUT));
+                let _ = stream.set_write_timeout(Some(SCRAPE_TIMEOUT));
+                let mut buffer = [0u8; 1024];
+                if stream.read(&mut buffer).is_err(){
+                    return;
+                }
+                let body = render();
+                let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::net::TcpStream;
+
+    fn progress() -> Progress{
+        Progress{elapsed: Duration::from_secs(1), requested: 5, downloaded: 3, connected: 2, parsed: 2, queued: 2, in_flight: 1, finished: false}
+    }
+
+    #[test]
+    fn renders_prometheus_text(){
+        let metrics = Metrics::new();
+        metrics.observe_response("http://example.com/a", "200", Duration::from_millis(30), 2000);
+        metrics.observe_response("http://example.com/b", "200", Duration::from_millis(300), 100);
+        metrics.observe_response("http://other.org/", "error", Duration::from_secs(20), 0);
+        metrics.observe_retry();
+        metrics.observe_cache_hit();
+        metrics.observe_cache_hit();
+        let text = metrics.render(&progress());
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in [
+            "# TYPE crawl_responses_total counter",
+            "crawl_responses_total{host=\"example.com\",status=\"200\"} 2",
+            "crawl_responses_total{host=\"other.org\",status=\"error\"} 1",
+            "# TYPE crawl_request_duration_seconds histogram",
+            "crawl_request_duration_seconds_bucket{le=\"0.01\"} 0",
+            "crawl_request_duration_seconds_bucket{le=\"0.05\"} 1",
+            "crawl_request_duration_seconds_bucket{le=\"0.5\"} 2",
+            "crawl_request_duration_seconds_bucket{le=\"10\"} 2",
+            "crawl_request_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "crawl_request_duration_seconds_count 3",
+            "crawl_response_size_bytes_bucket{le=\"1024\"} 2",
+            "crawl_response_size_bytes_bucket{le=\"4096\"} 3",
+            "crawl_response_size_bytes_sum 2100",
+            "crawl_response_bytes_total 2100",
+            "crawl_retries_total 1",
+            "crawl_cache_hits_total 2",
+            "crawl_requested_total 5",
+            "# TYPE crawl_queue_depth gauge",
+            "crawl_queue_depth 2",
+            "crawl_in_flight 1",
+        ]{
+            assert!(lines.contains(&expected), "missing {}\n{}", expected, text);
+        }
+        for line in lines.iter().filter(|l| !l.starts_with('#')){
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+        }
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn serves_scrapes_while_a_client_is_idle(){
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        serve(addr, || "crawl_in_flight 0\n".to_string()).unwrap();
+        let _idle = TcpStream::connect(addr).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\ncrawl_in_flight 0\n"), "{}", response);
+    }
+}
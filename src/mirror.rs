@@ -0,0 +1,340 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use regex::{Captures, Regex};
+use reqwest::Url;
+use reqwest::header::{CONTENT_TYPE, HeaderMap};
+use tracing::{debug, warn};
+use crate::cache;
+use crate::encoding;
+use crate::link::canonicalize;
+
+fn content_type(headers: &HeaderMap) -> Option<String>{
+    headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(|v| v.to_ascii_lowercase())
+}
+
+fn extension(url: &str) -> String{
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    path.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase()).unwrap_or_default()
+}
+
+pub fn is_html(headers: &HeaderMap, url: &str) -> bool{
+    match content_type(headers){
+        Some(t) => t.contains("html"),
+        None => matches!(extension(url).as_str(), "html" | "htm" | "xhtml"),
+    }
+}
+
+pub fn is_css(headers: &HeaderMap, url: &str) -> bool{
+    match content_type(headers){
+        Some(t) => t.contains("text/css"),
+        None => extension(url) == "css",
+    }
+}
+
+fn tag_regex() -> &'static Regex{
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    TAG.get_or_init(|| Regex::new(r#"(?is)<!--.*?-->|<(script|style)\b((?:[^>"']|"[^"]*"|'[^']*')*)>(.*?)</(?:script|style)\s*>|<([a-z][a-z0-9]*)\b((?:[^>"']|"[^"]*"|'[^']*')*)>"#).unwrap())
+}
+
+fn attr_regex() -> &'static Regex{
+    static ATTR: OnceLock<Regex> = OnceLock::new();
+    ATTR.get_or_init(|| Regex::new(r#"(?i)(\s)([^\s"'>/=]+)(\s*=\s*)("[^"]*"|'[^']*'|[^\s"'>]+)"#).unwrap())
+}
+
+fn css_regex() -> &'static Regex{
+    static CSS: OnceLock<Regex> = OnceLock::new();
+    CSS.get_or_init(|| Regex::new(r#"(?i)url\(\s*(?:"([^"]*)"|'([^']*)'|([^)\s]*))\s*\)|@import\s+(?:"([^"]*)"|'([^']*)')"#).unwrap())
+}
+
+fn css_url<'a>(caps: &Captures<'a>) -> Option<regex::Match<'a>>{
+    (1..=5).find_map(|i| caps.get(i))
+}
+
+fn unescape(value: &str) -> String{
+    value.replace("&amp;", "&").replace("&#38;", "&")
+}
+
+fn is_link_attr(tag: &str, attr: &str) -> bool{
+    match attr{
+        "href" | "src" | "srcset" | "poster" | "background" => true,
+        "data" => tag == "object",
+        _ => false,
+    }
+}
+
+fn is_requisite(tag: &str, attrs: &str) -> bool{
+    match tag{
+        "img" | "script" | "source" | "video" | "audio" | "input" | "embed" | "track" => true,
+        "link" => attr_regex().captures_iter(attrs)
+            .find(|c| c[2].eq_ignore_ascii_case("rel"))
+            .map(|c| {
+                let rel = c[4].trim_matches(['"', '\'']).to_ascii_lowercase();
+                rel.split_whitespace().any(|r| matches!(r, "stylesheet" | "icon" | "preload" | "manifest" | "apple-touch-icon"))
+            })
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn split_srcset(srcset: &str) -> Vec<(&str, &str)>{
+    srcset.split(',')
+        .filter_map(|candidate| {
+            let candidate = candidate.trim();
+            let (url, descriptor) = candidate.split_once(char::is_whitespace).unwrap_or((candidate, ""));
+            (!url.is_empty()).then_some((url, descriptor.trim()))
+        })
+        .collect()
+}
+
+fn document_base(page_url: &str, html: &str) -> Option<Url>{
+    let page = Url::parse(page_url).ok()?;
+    let base = tag_regex().captures_iter(html)
+        .filter(|c| c.get(4).map(|t| t.as_str().eq_ignore_ascii_case("base")).unwrap_or(false))
+        .find_map(|c| attr_regex().captures_iter(c.get(5)?.as_str())
+            .find(|a| a[2].eq_ignore_ascii_case("href"))
+            .map(|a| unescape(a[4].trim_matches(['"', '\'']))));
+    match base{
+        Some(href) => page.join(href.trim()).ok().or(Some(page)),
+        None => Some(page),
+    }
+}
+
+pub fn css_links(page_url: &str, css: &str) -> Vec<String>{
+    let Ok(base) = Url::parse(page_url) else {
+        return Vec::new();
+    };
+    css_regex().captures_iter(css)
+        .filter_map(|c| css_url(&c).and_then(|m| canonicalize(&base, m.as_str())))
+        .map(|u| u.to_string())
+        .collect()
+}
+
+pub fn requisites(page_url: &str, html: &str) -> Vec<String>{
+    let Some(base) = document_base(page_url, html) else {
+        return Vec::new();
+    };
+    let mut urls = Vec::new();
+    for caps in tag_regex().captures_iter(html){
+        let (tag, attrs) = match (caps.get(1).or(caps.get(4)), caps.get(2).or(caps.get(5))){
+            (Some(t), Some(a)) => (t.as_str().to_ascii_lowercase(), a.as_str()),
+            _ => continue,
+        };
+        if let Some(style) = caps.get(3).filter(|_| tag == "style"){
+            urls.extend(css_links(base.as_str(), style.as_str()));
+        }
+        for attr in attr_regex().captures_iter(attrs){
+            let name = attr[2].to_ascii_lowercase();
+            let value = unescape(attr[4].trim_matches(['"', '\'']));
+            if name == "style"{
+                urls.extend(css_links(base.as_str(), &value));
+            }else if is_link_attr(&tag, &name) && (is_requisite(&tag, attrs) || name != "href"){
+                let hrefs: Vec<&str> = if name == "srcset" {split_srcset(&value).into_iter().map(|(u, _)| u).collect()} else {vec![value.as_str()]};
+                urls.extend(hrefs.into_iter().filter_map(|h| canonicalize(&base, h)).map(|u| u.to_string()));
+            }
+        }
+    }
+    urls.sort();
+    urls.dedup();
+    urls
+}
+
+fn escape_path(path: &str) -> String{
+    path.replace('%', "%25").replace('?', "%3F").replace('#', "%23").replace(' ', "%20")
+}
+
+pub fn relative_path(from: &str, to: &str) -> String{
+    let from: Vec<&str> = from.split('/').collect();
+    let to: Vec<&str> = to.split('/').collect();
+    let from_dir = &from[..from.len() - 1];
+    let common = from_dir.iter().zip(to.iter()).take_while(|(a, b)| a == b).count();
+    let mut parts: Vec<&str> = vec![".."; from_dir.len() - common];
+    parts.extend_from_slice(&to[common..]);
+    parts.join("/")
+}
+
+pub struct Mirror{
+    root: PathBuf,
+    base_url: String,
+}
+
+impl Mirror{
+    pub fn new<P: Into<PathBuf>, S: Into<String>>(root: P, base_url: S) -> Mirror{
+        Mirror{root: root.into(), base_url: base_url.into()}
+    }
+
+    pub fn local_path(&self, url: &str) -> Option<String>{
+        url.strip_prefix(self.base_url.as_str()).map(cache::file_path)
+    }
+
+    fn link(&self, from: &str, base: &Url, href: &str) -> Option<String>{
+        let href = unescape(href.trim());
+        if href.is_empty() || href.starts_with('#'){
+            return None;
+        }
+        let url = canonicalize(base, &href)?;
+        let fragment = href.split_once('#').map(|(_, f)| format!("#{}", f)).unwrap_or_default();
+        match self.local_path(url.as_str()).filter(|p| self.root.join(p).is_file()){
+            Some(path) => Some(format!("{}{}", escape_path(&relative_path(from, &path)), fragment)),
+            None => Some(format!("{}{}", url, fragment)),
+        }
+    }
+
+    pub fn rewrite_css(&self, from: &str, page_url: &str, css: &str) -> String{
+        let Ok(base) = Url::parse(page_url) else {
+            return css.to_string();
+        };
+        css_regex().replace_all(css, |caps: &Captures<'_>| {
+            let whole = &caps[0];
+            match css_url(caps).and_then(|m| self.link(from, &base, m.as_str()).map(|l| (m, l))){
+                Some((m, link)) => {
+                    let start = m.start() - caps.get(0).map(|c| c.start()).unwrap_or(0);
+                    format!("{}{}{}", &whole[..start], link, &whole[start + m.len()..])
+                },
+                None => whole.to_string(),
+            }
+        }).into_owned()
+    }
+
+    fn rewrite_attrs(&self, from: &str, base: &Url, page_url: &str, tag: &str, attrs: &str) -> String{
+        attr_regex().replace_all(attrs, |caps: &Captures<'_>| {
+            let name = caps[2].to_ascii_lowercase();
+            let raw = &caps[4];
+            let quote = if raw.starts_with('\'') {"'"} else {"\""};
+            let value = raw.trim_matches(['"', '\'']);
+            let rewritten = if name == "style"{
+                self.rewrite_css(from, base.as_str(), &unescape(value))
+            }else if is_link_attr(tag, &name) && name == "srcset"{
+                split_srcset(&unescape(value)).into_iter()
+                    .map(|(u, d)| {
+                        let link = self.link(from, base, u).unwrap_or_else(|| u.to_string());
+                        if d.is_empty() {link} else {format!("{} {}", link, d)}
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }else if is_link_attr(tag, &name){
+                match self.link(from, base, value){
+                    Some(link) => link,
+                    None => return caps[0].to_string(),
+                }
+            }else{
+                return caps[0].to_string();
+            };
+            debug!(page = %page_url, attr = %name, "link rewritten");
+            format!("{}{}{}{}{}{}", &caps[1], &caps[2], &caps[3], quote, rewritten.replace('&', "&amp;").replace(quote, if quote == "'" {"&#39;"} else {"&quot;"}), quote)
+        }).into_owned()
+    }
+
+    pub fn rewrite_html(&self, from: &str, page_url: &str, html: &str) -> String{
+        let Some(base) = document_base(page_url, html) else {
+            return html.to_string();
+        };
+        tag_regex().replace_all(html, |caps: &Captures<'_>| {
+            if let (Some(tag), Some(attrs), Some(body)) = (caps.get(1), caps.get(2), caps.get(3)){
+                let name = tag.as_str().to_ascii_lowercase();
+                let attrs = self.rewrite_attrs(from, &base, page_url, &name, attrs.as_str());
+                let body = if name == "style" {self.rewrite_css(from, base.as_str(), body.as_str())} else {body.as_str().to_string()};
+                return format!("<{}{}>{}</{}>", tag.as_str(), attrs, body, tag.as_str());
+            }
+            match (caps.get(4), caps.get(5)){
+                (Some(tag), _) if tag.as_str().eq_ignore_ascii_case("base") => String::new(),
+                (Some(tag), Some(attrs)) => {
+                    let name = tag.as_str().to_ascii_lowercase();
+                    format!("<{}{}>", tag.as_str(), self.rewrite_attrs(from, &base, page_url, &name, attrs.as_str()))
+                },
+                _ => caps[0].to_string(),
+            }
+        }).into_owned()
+    }
+
+    fn export_file(&self, path: &Path, rel: &str, out: &Path) -> anyhow::Result<()>{
+        let target = out.join(rel);
+        if let Some(p) = target.parent(){
+            fs::create_dir_all(p)?;
+        }
+        let body = cache::read_body(path)?;
+        let meta = cache::read_meta(path).unwrap_or_default();
+        let headers = meta.header_map();
+        let url = if meta.url.is_empty() {format!("{}{}", self.base_url, rel)} else {meta.url.clone()};
+        let (html, css) = (is_html(&headers, &url), is_css(&headers, &url));
+        if !html && !css{
+            fs::write(&target, &body)?;
+            return Ok(());
+        }
+        let encoding = encoding::resolve(meta.charset.as_deref(), &headers, &body);
+        let text = encoding::decode(&body, encoding);
+        let rewritten = if html {self.rewrite_html(rel, &url, &text)} else {self.rewrite_css(rel, &url, &text)};
+        let (bytes, _, _) = encoding.encode(&rewritten);
+        fs::write(&target, bytes)?;
+        Ok(())
+    }
+
+    pub fn export<P: AsRef<Path>>(&self, out: P) -> anyhow::Result<usize>{
+        let out = out.as_ref();
+        let mut count = 0;
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop(){
+            for entry in fs::read_dir(&dir)?{
+                let path = entry?.path();
+                if path.is_dir(){
+                    dirs.push(path);
+                    continue;
+                }
+                let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                if name.starts_with('.') && name.ends_with(".meta"){
+                    continue;
+                }
+                let rel = match path.strip_prefix(&self.root){
+                    Ok(rel) => rel.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect::<Vec<_>>().join("/"),
+                    Err(_) => continue,
+                };
+                match self.export_file(&path, &rel, out){
+                    Ok(()) => count += 1,
+                    Err(e) => warn!(path = %path.display(), error = %e, "mirror export failed"),
+                }
+            }
+        }
+        debug!(files = count, out = %out.display(), "mirror exported");
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn mirror(name: &str, files: &[&str]) -> Mirror{
+        let root = std::env::temp_dir().join(format!("crawl-mirror-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        for file in files{
+            cache::write_body(&root.join(file), &bytes::Bytes::from_static(b"")).unwrap();
+        }
+        Mirror::new(root, "https://example.com/book/")
+    }
+
+    #[test]
+    fn maps_directory_urls_to_index_html(){
+        let m = mirror("paths", &[]);
+        assert_eq!(m.local_path("https://example.com/book/").as_deref(), Some("index.html"));
+        assert_eq!(m.local_path("https://example.com/book/ch01/").as_deref(), Some("ch01/index.html"));
+        assert_eq!(m.local_path("https://example.com/book/ch01/intro.html").as_deref(), Some("ch01/intro.html"));
+        assert_eq!(m.local_path("https://example.com/other/"), None);
+    }
+
+    #[test]
+    fn rewrites_links_to_directory_urls(){
+        let m = mirror("index", &["index.html", "ch01/index.html", "ch01/intro.html"]);
+        let html = r#"<a href="../">up</a> <a href="./">here</a> <a href="intro.html#top">intro</a> <a href="missing.html">x</a>"#;
+        let rewritten = m.rewrite_html("ch01/intro.html", "https://example.com/book/ch01/intro.html", html);
+        assert_eq!(rewritten, r#"<a href="../index.html">up</a> <a href="index.html">here</a> <a href="intro.html#top">intro</a> <a href="https://example.com/book/ch01/missing.html">x</a>"#);
+    }
+
+    #[test]
+    fn keeps_quoted_angle_brackets_inside_tags(){
+        let m = mirror("quotes", &["a.html", "b.png"]);
+        let html = r#"<a title="1 > 0" href="https://example.com/book/a.html">a</a><img alt='x>y' data-note="see href=c.html" src="/book/b.png"><p>text > more</p>"#;
+        let rewritten = m.rewrite_html("index.html", "https://example.com/book/index.html", html);
+        assert_eq!(rewritten, r#"<a title="1 > 0" href="a.html">a</a><img alt='x>y' data-note="see href=c.html" src="b.png"><p>text > more</p>"#);
+        assert_eq!(requisites("https://example.com/book/index.html", html), vec!["https://example.com/book/b.png"]);
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use parquet::basic::{ConvertedType, Repetition, Type as PhysicalType};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::debug;
+use crate::pipeline::{Exporter, to_record};
+use crate::schema::{ColumnType, Schema};
+
+struct State{
+    writer: Option<SerializedFileWriter<File>>,
+    rows: Vec<Map<String, Value>>,
+}
+
+/// Writes items as Parquet row groups of `batch_size` rows. Every item is checked
+/// against the schema, a mismatch is returned as an error and the item is not written.
+pub struct ParquetExporter{
+    path: PathBuf,
+    schema: Schema,
+    batch_size: usize,
+    state: Mutex<State>,
+}
+
+impl ParquetExporter{
+    pub fn create<P: AsRef<Path>>(path: P, schema: Schema) -> anyhow::Result<ParquetExporter>{
+        if schema.columns.is_empty(){
+            anyhow::bail!("parquet schema has no columns");
+        }
+        if let Some(p) = path.as_ref().parent(){
+            fs::create_dir_all(p)?;
+        }
+        Ok(ParquetExporter{
+            path: path.as_ref().to_path_buf(),
+            schema,
+            batch_size: 10000,
+            state: Mutex::new(State{writer: None, rows: Vec::new()}),
+        })
+    }
+    pub fn with_batch_size(mut self, batch_size: usize) -> ParquetExporter{
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    fn open(&self) -> anyhow::Result<SerializedFileWriter<File>>{
+        let mut fields = Vec::new();
+        for column in self.schema.columns.iter(){
+            let name = column.name.as_str();
+            let builder = match column.kind{
+                ColumnType::Bool => Type::primitive_type_builder(name, PhysicalType::BOOLEAN),
+                ColumnType::Int => Type::primitive_type_builder(name, PhysicalType::INT64),
+                ColumnType::Double => Type::primitive_type_builder(name, PhysicalType::DOUBLE),
+                ColumnType::Text => Type::primitive_type_builder(name, PhysicalType::BYTE_ARRAY).with_converted_type(ConvertedType::UTF8),
+            };
+            let repetition = if column.nullable {Repetition::OPTIONAL} else {Repetition::REQUIRED};
+            fields.push(Arc::new(builder.with_repetition(repetition).build()?));
+        }
+        let schema = Type::group_type_builder("item").with_fields(fields).build()?;
+        let props = WriterProperties::builder().build();
+        let writer = SerializedFileWriter::new(File::create(&self.path)?, Arc::new(schema), Arc::new(props))?;
+        debug!(path = %self.path.display(), columns = self.schema.columns.len(), "parquet file opened");
+        Ok(writer)
+    }
+
+    fn flush(&self, state: &mut State) -> anyhow::Result<()>{
+        if state.rows.is_empty(){
+            return Ok(());
+        }
+        if state.writer.is_none(){
+            state.writer = Some(self.open()?);
+        }
+        let State{writer, rows} = state;
+        let Some(writer) = writer.as_mut() else {
+            return Ok(());
+        };
+        let mut group = writer.next_row_group()?;
+        let mut columns = self.schema.columns.iter();
+        while let (Some(mut writer), Some(column)) = (group.next_column()?, columns.next()){
+            let values: Vec<&Value> = rows.iter().map(|row| row.get(&column.name).unwrap_or(&Value::Null)).collect();
+            let defs: Vec<i16> = values.iter().map(|v| !v.is_null() as i16).collect();
+            let defs = if column.nullable {Some(defs.as_slice())} else {None};
+            let present = values.iter().filter(|v| !v.is_null());
+            match column.kind{
+                ColumnType::Bool => {
+                    let data: Vec<bool> = present.filter_map(|v| v.as_bool()).collect();
+                    writer.typed::<BoolType>().write_batch(&data, defs, None)?;
+                },
+                ColumnType::Int => {
+                    let data: Vec<i64> = present.filter_map(|v| v.as_i64()).collect();
+                    writer.typed::<Int64Type>().write_batch(&data, defs, None)?;
+                },
+                ColumnType::Double => {
+                    let data: Vec<f64> = present.filter_map(|v| v.as_f64()).collect();
+                    writer.typed::<DoubleType>().write_batch(&data, defs, None)?;
+                },
+                ColumnType::Text => {
+                    let data: Vec<ByteArray> = present.filter_map(|v| v.as_str()).map(|t| ByteArray::from(t.as_bytes().to_vec())).collect();
+                    writer.typed::<ByteArrayType>().write_batch(&data, defs, None)?;
+                },
+            }
+            writer.close()?;
+        }
+        group.close()?;
+        rows.clear();
+        Ok(())
+    }
+}
+
+impl<I: Serialize> Exporter<I> for ParquetExporter{
+    fn export(&self, item: &I) -> anyhow::Result<()>{
+        let record = to_record(item)?;
+        self.schema.check(&record)?;
+        let mut state = self.state.lock().unwrap();
+        state.rows.push(record);
+        if state.rows.len() >= self.batch_size{
+            self.flush(&mut state)?;
+        }
+        Ok(())
+    }
+    fn finish(&self) -> anyhow::Result<()>{
+        let mut state = self.state.lock().unwrap();
+        self.flush(&mut state)?;
+        if let Some(writer) = state.writer.take(){
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use super::*;
+
+    #[derive(Deserialize, Serialize)]
+    struct Row{
+        year: u16,
+        code: String,
+        parent: Option<String>,
+        share: f64,
+    }
+
+    #[test]
+    fn writes_typed_rows(){
+        let path = std::env::temp_dir().join(format!("crawl-test-{}-rows.parquet", std::process::id()));
+        let exporter = ParquetExporter::create(&path, Schema::of::<Row>().unwrap()).unwrap().with_batch_size(2);
+        for (year, parent) in [(2021, None), (2022, None), (2023, Some("11"))]{
+            let row = Row{year, code: format!("{}01", year), parent: parent.map(str::to_string), share: 0.5};
+            Exporter::export(&exporter, &row).unwrap();
+        }
+        Exporter::<Row>::finish(&exporter).unwrap();
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        let rows: Vec<String> = reader.get_row_iter(None).unwrap().map(|row| row.unwrap().to_string()).collect();
+        assert_eq!(rows, vec![
+            r#"{year: 2021, code: "202101", parent: null, share: 0.5}"#,
+            r#"{year: 2022, code: "202201", parent: null, share: 0.5}"#,
+            r#"{year: 2023, code: "202301", parent: "11", share: 0.5}"#,
+        ]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rejects_mismatched_records(){
+        let path = std::env::temp_dir().join(format!("crawl-test-{}-mismatch.parquet", std::process::id()));
+        let schema = Schema::new().with_column("id", ColumnType::Int).with_nullable("name", ColumnType::Text);
+        let exporter = ParquetExporter::create(&path, schema).unwrap();
+        assert!(exporter.export(&json!({"id": 1.5})).is_err());
+        assert!(exporter.export(&json!({"id": 1, "extra": true})).is_err());
+        assert!(exporter.export(&json!({"name": "a"})).is_err());
+        exporter.export(&json!({"id": 2})).unwrap();
+        Exporter::<Value>::finish(&exporter).unwrap();
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 1);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,516 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{debug, warn};
+
+pub trait Stage<I>: Send + Sync{
+    fn process(&self, item: &mut I, url: &str) -> anyhow::Result<bool>;
+    fn finish(&self) -> anyhow::Result<()>{
+        Ok(())
+    }
+}
+
+impl<I, F> Stage<I> for F
+where F: Fn(&mut I, &str) -> anyhow::Result<bool> + Send + Sync
+{
+    fn process(&self, item: &mut I, url: &str) -> anyhow::Result<bool>{
+        self(item, url)
+    }
+}
+
+pub trait Exporter<I>: Send + Sync{
+    fn export(&self, item: &I) -> anyhow::Result<()>;
+    fn finish(&self) -> anyhow::Result<()>{
+        Ok(())
+    }
+}
+
+type Output = Box<dyn Write + Send>;
+
+fn create<P: AsRef<Path>>(path: P) -> anyhow::Result<Output>{
+    if let Some(p) = path.as_ref().parent(){
+        std::fs::create_dir_all(p)?;
+    }
+    Ok(Box::new(File::create(path)?))
+}
+
+pub fn to_record<I: Serialize>(item: &I) -> anyhow::Result<serde_json::Map<String, serde_json::Value>>{
+    match serde_json::to_value(item)?{
+        serde_json::Value::Object(map) => Ok(map),
+        value => anyhow::bail!("item must serialize to a map, got {}", value),
+    }
+}
+
+type KeyFn<I> = Box<dyn Fn(&I) -> anyhow::Result<String> + Send + Sync>;
+
+pub struct DedupStage<I>{
+    key: KeyFn<I>,
+    seen: Mutex<HashSet<String>>,
+}
+
+impl<I> DedupStage<I>{
+    pub fn new<F: Fn(&I) -> String + Send + Sync + 'static>(key: F) -> DedupStage<I>{
+        DedupStage{key: Box::new(move |item| Ok(key(item))), seen: Mutex::new(HashSet::new())}
+    }
+    pub fn seen(&self) -> usize{
+        self.seen.lock().unwrap().len()
+    }
+}
+
+impl<I: Serialize> DedupStage<I>{
+    pub fn by_fields(fields: &[&str]) -> DedupStage<I>{
+        let fields: Vec<String> = fields.iter().map(|f| f.to_string()).collect();
+        DedupStage{
+            key: Box::new(move |item| {
+                let record = to_record(item)?;
+                let key: Vec<Value> = fields.iter().map(|f| record.get(f).cloned().unwrap_or(Value::Null)).collect();
+                Ok(Value::Array(key).to_string())
+            }),
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl<I> Stage<I> for DedupStage<I>{
+    fn process(&self, item: &mut I, url: &str) -> anyhow::Result<bool>{
+        let key = (self.key)(item)?;
+        let fresh = self.seen.lock().unwrap().insert(key);
+        if !fresh{
+            debug!(url = %url, "duplicate item");
+        }
+        Ok(fresh)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InvalidItem{
+    pub url: String,
+    pub errors: Vec<String>,
+    pub item: Value,
+}
+
+type CheckFn<I> = Box<dyn Fn(&I) -> bool + Send + Sync>;
+
+pub struct ValidateStage<I>{
+    required: Vec<String>,
+    checks: Vec<(String, CheckFn<I>)>,
+    errors: Vec<Box<dyn Exporter<InvalidItem>>>,
+    invalid: AtomicUsize,
+}
+
+fn is_missing(value: Option<&Value>) -> bool{
+    match value{
+        None | Some(Value::Null) => true,
+        Some(Value::String(s)) => s.trim().is_empty(),
+        Some(_) => false,
+    }
+}
+
+impl<I> ValidateStage<I>{
+    pub fn new() -> ValidateStage<I>{
+        ValidateStage{required: Vec::new(), checks: Vec::new(), errors: Vec::new(), invalid: AtomicUsize::new(0)}
+    }
+    pub fn with_required(mut self, fields: &[&str]) -> ValidateStage<I>{
+        self.required.extend(fields.iter().map(|f| f.to_string()));
+        self
+    }
+    pub fn with_check<F: Fn(&I) -> bool + Send + Sync + 'static>(mut self, name: &str, check: F) -> ValidateStage<I>{
+        self.checks.push((name.to_string(), Box::new(check)));
+        self
+    }
+    pub fn with_errors<X: Exporter<InvalidItem> + 'static>(mut self, exporter: X) -> ValidateStage<I>{
+        self.errors.push(Box::new(exporter));
+        self
+    }
+    pub fn invalid(&self) -> usize{
+        self.invalid.load(Ordering::Relaxed)
+    }
+}
+
+impl<I> Default for ValidateStage<I>{
+    fn default() -> Self{
+        ValidateStage::new()
+    }
+}
+
+impl<I: Serialize> Stage<I> for ValidateStage<I>{
+    fn process(&self, item: &mut I, url: &str) -> anyhow::Result<bool>{
+        let record = to_record(item)?;
+        let mut errors: Vec<String> = self.required.iter()
+            .filter(|f| is_missing(record.get(*f)))
+            .map(|f| format!("missing {}", f))
+            .collect();
+        errors.extend(self.checks.iter().filter(|(_, check)| !check(item)).map(|(name, _)| format!("failed {}", name)));
+        if errors.is_empty(){
+            return Ok(true);
+        }
+        self.invalid.fetch_add(1, Ordering::Relaxed);
+        warn!(url = %url, errors = %errors.join(", "), "invalid item");
+        let invalid = InvalidItem{url: url.to_string(), errors, item: Value::Object(record)};
+        for exporter in self.errors.iter(){
+            exporter.export(&invalid)?;
+        }
+        Ok(false)
+    }
+    fn finish(&self) -> anyhow::Result<()>{
+        for exporter in self.errors.iter(){
+            exporter.finish()?;
+        }
+        Ok(())
+    }
+}
+
+pub struct CsvExporter{
+    writer: Mutex<csv::Writer<Output>>,
+}
+
+impl CsvExporter{
+    pub fn new<W: Write + Send + 'static>(writer: W) -> CsvExporter{
+        CsvExporter{writer: Mutex::new(csv::Writer::from_writer(Box::new(writer)))}
+    }
+    pub fn create<P: AsRef<Path>>(path: P) -> anyhow::Result<CsvExporter>{
+        Ok(CsvExporter{writer: Mutex::new(csv::Writer::from_writer(create(path)?))})
+    }
+}
+
+impl<I: Serialize> Exporter<I> for CsvExporter{
+    fn export(&self, item: &I) -> anyhow::Result<()>{
+        self.writer.lock().unwrap().serialize(item)?;
+        Ok(())
+    }
+    fn finish(&self) -> anyhow::Result<()>{
+        self.writer.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
+
+pub struct JsonLinesExporter{
+    writer: Mutex<BufWriter<Output>>,
+}
+
+impl JsonLinesExporter{
+    pub fn new<W: Write + Send + 'static>(writer: W) -> JsonLinesExporter{
+        JsonLinesExporter{writer: Mutex::new(BufWriter::new(Box::new(writer)))}
+    }
+    pub fn create<P: AsRef<Path>>(path: P) -> anyhow::Result<JsonLinesExporter>{
+        Ok(JsonLinesExporter{writer: Mutex::new(BufWriter::new(create(path)?))})
+    }
+}
+
+impl<I: Serialize> Exporter<I> for JsonLinesExporter{
+    fn export(&self, item: &I) -> anyhow::Result<()>{
+        let mut writer = self.writer.lock().unwrap();
+        serde_json::to_writer(&mut *writer, item)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+    fn finish(&self) -> anyhow::Result<()>{
+        self.writer.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
+
+pub struct JsonExporter{
+    writer: Mutex<(BufWriter<Output>, usize)>,
+}
+
+impl JsonExporter{
+    pub fn new<W: Write + Send + 'static>(writer: W) -> JsonExporter{
+        JsonExporter{writer: Mutex::new((BufWriter::new(Box::new(writer)), 0))}
+    }
+    pub fn create<P: AsRef<Path>>(path: P) -> anyhow::Result<JsonExporter>{
+        Ok(JsonExporter{writer: Mutex::new((BufWriter::new(create(path)?), 0))})
+    }
+}
+
+impl<I: Serialize> Exporter<I> for JsonExporter{
+    fn export(&self, item: &I) -> anyhow::Result<()>{
+        let mut guard = self.writer.lock().unwrap();
+        let (writer, count) = &mut *guard;
+        writer.write_all(if *count == 0 {b"[\n"} else {b",\n"})?;
+        serde_json::to_writer(&mut *writer, item)?;
+        *count += 1;
+        Ok(())
+    }
+    fn finish(&self) -> anyhow::Result<()>{
+        let mut guard = self.writer.lock().unwrap();
+        let (writer, count) = &mut *guard;
+        writer.write_all(if *count == 0 {b"[]\n"} else {b"\n]\n"})?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Sorts a finished CSV file by the text of the given columns, keeping the export order for ties.
+/// Exporters stream rows to disk while crawling, this reads the whole file back to sort it.
+pub fn sort_csv<P: AsRef<Path>>(path: P, columns: &[&str]) -> anyhow::Result<()>{
+    let path = path.as_ref();
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let mut indexes = Vec::new();
+    for column in columns{
+        match headers.iter().position(|h| h == *column){
+            Some(index) => indexes.push(index),
+            None => anyhow::bail!("column {} is not in {}", column, path.display()),
+        }
+    }
+    let mut records = reader.records().collect::<Result<Vec<_>, _>>()?;
+    records.sort_by(|a, b| indexes.iter().map(|i| a.get(*i).cmp(&b.get(*i))).find(|o| o.is_ne()).unwrap_or(std::cmp::Ordering::Equal));
+    let sorted = path.with_extension("sorting");
+    let mut writer = csv::Writer::from_path(&sorted)?;
+    writer.write_record(&headers)?;
+    for record in records.iter(){
+        writer.write_record(record)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(&sorted, path)?;
+    debug!(path = %path.display(), rows = records.len(), "csv sorted");
+    Ok(())
+}
+
+pub struct Pipeline<I>{
+    stages: Vec<Box<dyn Stage<I>>>,
+    exporters: Vec<Box<dyn Exporter<I>>>,
+    processed: AtomicUsize,
+    dropped: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl<I> Pipeline<I>{
+    pub fn new() -> Pipeline<I>{
+        Pipeline{
+            stages: Vec::new(),
+            exporters: Vec::new(),
+            processed: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        }
+    }
+    pub fn with_stage<S: Stage<I> + 'static>(mut self, stage: S) -> Pipeline<I>{
+        self.stages.push(Box::new(stage));
+        self
+    }
+    pub fn with_exporter<X: Exporter<I> + 'static>(mut self, exporter: X) -> Pipeline<I>{
+        self.exporters.push(Box::new(exporter));
+        self
+    }
+
+    pub fn process(&self, item: &mut I, url: &str) -> anyhow::Result<bool>{
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        for stage in self.stages.iter(){
+            match stage.process(item, url){
+                Ok(true) => {},
+                Ok(false) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    debug!(url = %url, "item dropped");
+                    return Ok(false);
+                },
+                Err(e) => {
+                    self.failed.fetch_add(1, Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+        }
+        for exporter in self.exporters.iter(){
+            if let Err(e) = exporter.export(item){
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn finish(&self) -> anyhow::Result<()>{
+        let mut result = Ok(());
+        for stage in self.stages.iter(){
+            if let Err(e) = stage.finish(){
+                warn!(error = %e, "stage finish failed");
+                result = Err(e);
+            }
+        }
+        for exporter in self.exporters.iter(){
+            if let Err(e) = exporter.finish(){
+                warn!(error = %e, "exporter finish failed");
+                result = Err(e);
+            }
+        }
+        debug!(processed = self.processed(), dropped = self.dropped(), failed = self.failed(), "pipeline finished");
+        result
+    }
+
+    pub fn processed(&self) -> usize{
+        self.processed.load(Ordering::Relaxed)
+    }
+    pub fn dropped(&self) -> usize{
+        self.dropped.load(Ordering::Relaxed)
+    }
+    pub fn failed(&self) -> usize{
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+impl<I> Default for Pipeline<I>{
+    fn default() -> Self{
+        Pipeline::new()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::sync::Arc;
+    use serde_json::json;
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer{
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize>{
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()>{
+            Ok(())
+        }
+    }
+
+    impl Buffer{
+        fn text(&self) -> String{
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[derive(Clone, Serialize)]
+    struct City{
+        year: u16,
+        code: String,
+        name: Option<String>,
+    }
+
+    fn city(year: u16, code: &str, name: Option<&str>) -> City{
+        City{year, code: code.to_string(), name: name.map(str::to_string)}
+    }
+
+    #[test]
+    fn drops_duplicates_by_key(){
+        let stage = DedupStage::new(|c: &City| c.code.clone());
+        assert!(stage.process(&mut city(2023, "11", None), "u").unwrap());
+        assert!(!stage.process(&mut city(2022, "11", None), "u").unwrap());
+        assert!(stage.process(&mut city(2023, "12", None), "u").unwrap());
+        assert_eq!(stage.seen(), 2);
+
+        let stage = DedupStage::by_fields(&["year", "code"]);
+        assert!(stage.process(&mut city(2023, "11", Some("a")), "u").unwrap());
+        assert!(stage.process(&mut city(2022, "11", Some("a")), "u").unwrap());
+        assert!(!stage.process(&mut city(2023, "11", Some("b")), "u").unwrap());
+        assert!(stage.process(&mut city(2023, "1", Some("1")), "u").unwrap());
+    }
+
+    #[test]
+    fn sends_invalid_items_to_the_error_exporter(){
+        let errors = Buffer::default();
+        let stage = ValidateStage::new()
+            .with_required(&["code", "name"])
+            .with_check("recent", |c: &City| c.year >= 2009)
+            .with_errors(JsonLinesExporter::new(errors.clone()));
+        assert!(stage.process(&mut city(2023, "11", Some("Beijing")), "http://a/1").unwrap());
+        assert!(!stage.process(&mut city(2023, "12", Some("  ")), "http://a/2").unwrap());
+        assert!(!stage.process(&mut city(2000, "", None), "http://a/3").unwrap());
+        stage.finish().unwrap();
+        assert_eq!(stage.invalid(), 2);
+
+        let lines: Vec<Value> = errors.text().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines, vec![
+            json!({"url": "http://a/2", "errors": ["missing name"], "item": {"year": 2023, "code": "12", "name": "  "}}),
+            json!({"url": "http://a/3", "errors": ["missing code", "missing name", "failed recent"], "item": {"year": 2000, "code": "", "name": null}}),
+        ]);
+    }
+
+    #[test]
+    fn sorts_a_finished_csv(){
+        let path = std::env::temp_dir().join(format!("crawl-pipeline-test-{}-sorted.csv", std::process::id()));
+        let exporter = CsvExporter::create(&path).unwrap();
+        for item in [city(2023, "12", Some("b")), city(2022, "13", Some("c, \"d\"")), city(2023, "11", Some("a")), city(2022, "13", Some("e"))]{
+            exporter.export(&item).unwrap();
+        }
+        Exporter::<City>::finish(&exporter).unwrap();
+        sort_csv(&path, &["year", "code"]).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "year,code,name\n2022,13,\"c, \"\"d\"\"\"\n2022,13,e\n2023,11,a\n2023,12,b\n");
+        assert!(sort_csv(&path, &["missing"]).is_err());
+    }
+
+    #[test]
+    fn quotes_csv_fields(){
+        let buffer = Buffer::default();
+        let exporter = CsvExporter::new(buffer.clone());
+        exporter.export(&city(2023, "11", Some("Beijing, Dongcheng"))).unwrap();
+        exporter.export(&city(2023, "12", Some("the \"new\" district"))).unwrap();
+        exporter.export(&city(2023, "13", Some("two\nlines"))).unwrap();
+        exporter.export(&city(2023, "14", None)).unwrap();
+        Exporter::<City>::finish(&exporter).unwrap();
+        let text = buffer.text();
+        assert_eq!(text, "year,code,name\n2023,11,\"Beijing, Dongcheng\"\n2023,12,\"the \"\"new\"\" district\"\n2023,13,\"two\nlines\"\n2023,14,\n");
+        let names: Vec<String> = csv::Reader::from_reader(text.as_bytes()).records().map(|r| r.unwrap()[2].to_string()).collect();
+        assert_eq!(names, vec!["Beijing, Dongcheng", "the \"new\" district", "two\nlines", ""]);
+    }
+
+    #[test]
+    fn writes_json_arrays_and_lines(){
+        let empty = Buffer::default();
+        Exporter::<City>::finish(&JsonExporter::new(empty.clone())).unwrap();
+        assert_eq!(empty.text(), "[]\n");
+        assert_eq!(serde_json::from_str::<Value>(&empty.text()).unwrap(), json!([]));
+
+        let array = Buffer::default();
+        let exporter = JsonExporter::new(array.clone());
+        exporter.export(&city(2023, "11", Some("a"))).unwrap();
+        exporter.export(&city(2023, "12", None)).unwrap();
+        Exporter::<City>::finish(&exporter).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&array.text()).unwrap(), json!([
+            {"year": 2023, "code": "11", "name": "a"},
+            {"year": 2023, "code": "12", "name": null},
+        ]));
+
+        let lines = Buffer::default();
+        let exporter = JsonLinesExporter::new(lines.clone());
+        exporter.export(&json!({"a": "x\ny"})).unwrap();
+        exporter.export(&json!({"b": 1})).unwrap();
+        Exporter::<Value>::finish(&exporter).unwrap();
+        assert_eq!(lines.text(), "{\"a\":\"x\\ny\"}\n{\"b\":1}\n");
+    }
+
+    struct FailingExporter;
+
+    impl Exporter<City> for FailingExporter{
+        fn export(&self, item: &City) -> anyhow::Result<()>{
+            if item.code == "bad" {anyhow::bail!("cannot export {}", item.code)} else {Ok(())}
+        }
+    }
+
+    #[test]
+    fn counts_processed_dropped_and_failed_items(){
+        let buffer = Buffer::default();
+        let pipeline = Pipeline::new()
+            .with_stage(|c: &mut City, _: &str| {
+                if c.year == 0 {anyhow::bail!("no year")}
+                c.name = c.name.take().map(|n| n.trim().to_string());
+                Ok(true)
+            })
+            .with_stage(DedupStage::new(|c: &City| c.code.clone()))
+            .with_exporter(FailingExporter)
+            .with_exporter(JsonLinesExporter::new(buffer.clone()));
+        assert!(pipeline.process(&mut city(2023, "11", Some(" a ")), "u").unwrap());
+        assert!(!pipeline.process(&mut city(2023, "11", Some("b")), "u").unwrap());
+        assert!(pipeline.process(&mut city(0, "12", None), "u").is_err());
+        assert!(pipeline.process(&mut city(2023, "bad", None), "u").is_err());
+        pipeline.finish().unwrap();
+        assert_eq!((pipeline.processed(), pipeline.dropped(), pipeline.failed()), (4, 1, 2));
+        assert_eq!(buffer.text(), "{\"year\":2023,\"code\":\"11\",\"name\":\"a\"}\n");
+    }
+}
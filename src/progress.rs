@@ -0,0 +1,92 @@
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+#[derive(Debug, Clone)]
+pub struct Progress{
+    pub elapsed: Duration,
+    pub requested: usize,
+    pub downloaded: usize,
+    pub connected: usize,
+    pub parsed: usize,
+    pub queued: usize,
+    pub in_flight: usize,
+    pub finished: bool,
+}
+
+pub type ProgressCallback = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+impl Progress{
+    pub fn remaining(&self) -> usize{
+        self.requested.saturating_sub(self.downloaded)
+    }
+    pub fn rate(&self) -> f64{
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.downloaded as f64 / secs
+        }else{
+            0.0
+        }
+    }
+    pub fn eta(&self) -> Option<Duration>{
+        let rate = self.rate();
+        if rate > 0.0 {
+            Some(Duration::from_secs_f64(self.remaining() as f64 / rate))
+        }else{
+            None
+        }
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}s] downloaded {}/{} (network {}), parsed {}, queued {}, in flight {}, {:.1}/s",
+            self.elapsed.as_secs(), self.downloaded, self.requested, self.connected,
+            self.parsed, self.queued, self.in_flight, self.rate())?;
+        match self.eta(){
+            Some(eta) => write!(f, ", eta {}s", eta.as_secs()),
+            None => write!(f, ", eta -"),
+        }
+    }
+}
+
+pub fn log_progress(progress: &Progress){
+    info!(progress = %progress, "progress");
+}
+
+#[cfg(feature = "progress-bar")]
+pub struct ProgressBar{
+    bar: indicatif::ProgressBar,
+}
+
+#[cfg(feature = "progress-bar")]
+impl ProgressBar{
+    pub fn new() -> ProgressBar{
+        let bar = indicatif::ProgressBar::new(0);
+        bar.set_style(
+            indicatif::ProgressStyle::with_template("{elapsed_precise} [{wide_bar}] {pos}/{len} {msg}")
+                .unwrap_or_else(|_| indicatif::ProgressStyle::default_bar())
+        );
+        ProgressBar{bar}
+    }
+    pub fn update(&self, progress: &Progress){
+        self.bar.set_length(progress.requested as u64);
+        self.bar.set_position(progress.downloaded as u64);
+        let eta = match progress.eta(){
+            Some(eta) => format!("{}s", eta.as_secs()),
+            None => String::from("-"),
+        };
+        self.bar.set_message(format!("{:.1}/s queued {} eta {}", progress.rate(), progress.queued, eta));
+        if progress.finished {
+            self.bar.finish();
+        }
+    }
+}
+
+#[cfg(feature = "progress-bar")]
+impl Default for ProgressBar{
+    fn default() -> Self{
+        ProgressBar::new()
+    }
+}
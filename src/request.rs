@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::Bytes;
+use reqwest::{Method, Url};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+pub struct Request<E = ()>{
+    pub url: String,
+    pub method: Method,
+    pub headers: HeaderMap,
+    pub body: Option<Bytes>,
+    pub query: Vec<(String, String)>,
+    pub timeout: Option<Duration>,
+    pub session: Option<String>,
+    pub force: bool,
+    pub meta: BTreeMap<String, String>,
+    pub(crate) flag: Arc<E>,
+}
+
+pub fn hex_digest(data: &[u8]) -> String{
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Request<()>{
+    pub fn new<U: Into<String>>(url: U) -> Request<()>{
+        Request{
+            url: url.into(),
+            method: Method::GET,
+            headers: HeaderMap::new(),
+            body: None,
+            query: Vec::new(),
+            timeout: None,
+            session: None,
+            force: false,
+            meta: BTreeMap::new(),
+            flag: Arc::new(()),
+        }
+    }
+}
+
+impl<E> Request<E>{
+    pub fn with_flag<F>(self, flag: F) -> Request<F>{
+        self.with_shared_flag(Arc::new(flag))
+    }
+    pub fn with_shared_flag<F>(self, flag: Arc<F>) -> Request<F>{
+        Request{
+            url: self.url,
+            method: self.method,
+            headers: self.headers,
+            body: self.body,
+            query: self.query,
+            timeout: self.timeout,
+            session: self.session,
+            force: self.force,
+            meta: self.meta,
+            flag,
+        }
+    }
+    pub fn with_method(mut self, method: Method) -> Request<E>{
+        self.method = method;
+        self
+    }
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Request<E>{
+        self.headers.insert(name, value);
+        self
+    }
+    pub fn with_body<B: Into<Bytes>>(mut self, body: B) -> Request<E>{
+        self.body = Some(body.into());
+        if self.method == Method::GET{
+            self.method = Method::POST;
+        }
+        self
+    }
+    pub fn with_form<T: Serialize + ?Sized>(self, form: &T) -> anyhow::Result<Request<E>>{
+        let body = serde_urlencoded::to_string(form)?;
+        Ok(self.with_header(CONTENT_TYPE, HeaderValue::from_static("application/x-www-form-urlencoded")).with_body(body))
+    }
+    pub fn with_json<T: Serialize + ?Sized>(self, json: &T) -> anyhow::Result<Request<E>>{
+        let body = serde_json::to_vec(json)?;
+        Ok(self.with_header(CONTENT_TYPE, HeaderValue::from_static("application/json")).with_body(body))
+    }
+    pub fn with_query<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Request<E>{
+        self.query.push((key.into(), value.into()));
+        self
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> Request<E>{
+        self.timeout = Some(timeout);
+        self
+    }
+    pub fn with_session<S: Into<String>>(mut self, session: S) -> Request<E>{
+        self.session = Some(session.into());
+        self
+    }
+    pub fn with_force(mut self, force: bool) -> Request<E>{
+        self.force = force;
+        self
+    }
+    pub fn with_meta<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Request<E>{
+        self.meta.insert(key.into(), value.into());
+        self
+    }
+    pub fn flag(&self) -> &E{
+        &self.flag
+    }
+
+    pub fn full_url(&self) -> String{
+        if self.query.is_empty(){
+            return self.url.clone();
+        }
+        match Url::parse(&self.url){
+            Ok(mut url) => {
+                url.query_pairs_mut().extend_pairs(self.query.iter());
+                url.to_string()
+            },
+            Err(_) => self.url.clone(),
+        }
+    }
+
+    pub fn cache_key(&self) -> String{
+        let url = self.full_url();
+        if self.method == Method::GET && self.body.is_none(){
+            return url;
+        }
+        let mut data = self.method.as_str().as_bytes().to_vec();
+        if let Some(body) = &self.body{
+            data.push(b'\n');
+            data.extend_from_slice(body);
+        }
+        format!("{}@{}-{}", url, self.method, &hex_digest(&data)[..16])
+    }
+}
+
+impl<E> Clone for Request<E>{
+    fn clone(&self) -> Self{
+        Request{
+            url: self.url.clone(),
+            method: self.method.clone(),
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            query: self.query.clone(),
+            timeout: self.timeout,
+            session: self.session.clone(),
+            force: self.force,
+            meta: self.meta.clone(),
+            flag: Arc::clone(&self.flag),
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+use crate::cache::now;
+
+/// A five field cron expression (minute hour day-of-month month day-of-week) or an `@` alias.
+/// Times are seconds since the unix epoch and schedules fire in UTC. Weekday `7` is Sunday like `0`,
+/// and when both day fields are restricted a day matching either of them fires.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron{
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> anyhow::Result<Vec<bool>>{
+    let mut set = vec![false; max as usize + 1];
+    for part in field.split(','){
+        let (range, step) = match part.split_once('/'){
+            Some((r, s)) => (r, s.parse::<u32>()?),
+            None => (part, 1),
+        };
+        if step == 0{
+            anyhow::bail!("invalid cron step: {}", part);
+        }
+        let (start, end) = match range{
+            "*" => (min, max),
+            r => match r.split_once('-'){
+                Some((a, b)) => (a.parse()?, b.parse()?),
+                None => {
+                    let v = r.parse()?;
+                    (v, if part.contains('/') {max} else {v})
+                }
+            },
+        };
+        if start < min || end > max || start > end{
+            anyhow::bail!("cron value out of range: {}", part);
+        }
+        for v in (start..=end).step_by(step as usize){
+            set[v as usize] = true;
+        }
+    }
+    Ok(set)
+}
+
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32){
+    let z = days + 719468;
+    let era = if z >= 0 {z} else {z - 146096} / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 {mp + 3} else {mp - 9} as u32;
+    (if month <= 2 {yoe + era * 400 + 1} else {yoe + era * 400}, month, day)
+}
+
+impl Cron{
+    fn day_matches(&self, days: i64) -> bool{
+        let (_, month, day) = civil_from_days(days);
+        let weekday = (days + 4).rem_euclid(7) as usize;
+        if !self.months[month as usize]{
+            return false;
+        }
+        match (self.any_day, self.any_weekday){
+            (true, true) => true,
+            (false, true) => self.days[day as usize],
+            (true, false) => self.weekdays[weekday],
+            (false, false) => self.days[day as usize] || self.weekdays[weekday],
+        }
+    }
+
+    pub fn next_after(&self, time: u64) -> u64{
+        let mut t = (time / 60 + 1) * 60;
+        let limit = t + 5 * 366 * 86400;
+        while t < limit{
+            let days = (t / 86400) as i64;
+            if !self.day_matches(days){
+                t = (t / 86400 + 1) * 86400;
+                continue;
+            }
+            let hour = (t % 86400 / 3600) as usize;
+            if !self.hours[hour]{
+                t = (t / 3600 + 1) * 3600;
+                continue;
+            }
+            if self.minutes[(t % 3600 / 60) as usize]{
+                return t;
+            }
+            t += 60;
+        }
+        u64::MAX
+    }
+}
+
+impl FromStr for Cron{
+    type Err = anyhow::Error;
+
+    fn from_str(expr: &str) -> anyhow::Result<Cron>{
+        let expr = match expr.trim(){
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            e => e,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5{
+            anyhow::bail!("cron expression needs 5 fields: {}", expr);
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        if weekdays[7]{
+            weekdays[0] = true;
+        }
+        Ok(Cron{
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Schedule{
+    Interval(Duration),
+    Cron(Cron),
+}
+
+impl Schedule{
+    pub fn cron(expr: &str) -> anyhow::Result<Schedule>{
+        Ok(Schedule::Cron(expr.parse()?))
+    }
+    pub fn next_after(&self, time: u64) -> u64{
+        match self{
+            Schedule::Interval(interval) => time + interval.as_secs().max(1),
+            Schedule::Cron(cron) => cron.next_after(time),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChangeReport{
+    pub started: u64,
+    pub finished: u64,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    pub unchanged: usize,
+}
+
+impl ChangeReport{
+    pub fn diff(previous: &BTreeMap<String, String>, current: &BTreeMap<String, String>) -> ChangeReport{
+        let mut report = ChangeReport::default();
+        for (url, hash) in current.iter(){
+            match previous.get(url){
+                None => report.added.push(url.clone()),
+                Some(h) if h != hash => report.changed.push(url.clone()),
+                Some(_) => report.unchanged += 1,
+            }
+        }
+        report.removed = previous.keys().filter(|url| !current.contains_key(*url)).cloned().collect();
+        report
+    }
+    pub fn is_empty(&self) -> bool{
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl Display for ChangeReport{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result{
+        write!(f, "added {} removed {} changed {} unchanged {}", self.added.len(), self.removed.len(), self.changed.len(), self.unchanged)
+    }
+}
+
+type ReportCallback = Box<dyn Fn(&ChangeReport) + Send + Sync>;
+
+/// Runs a crawl on a schedule and reports which urls were added, removed or changed.
+///
+/// The crawl closure returns a content hash per url. Pages served from the cache hash the same as
+/// last time, so the closure must fetch fresh copies on every run: seed its requests with
+/// `Request::with_force(true)`. A downloader `with_revalidate(true)` only refetches pages that
+/// came with an ETag or Last-Modified header, on sites without them every run reports unchanged.
+pub struct Scheduler{
+    schedule: Schedule,
+    state_path: Option<PathBuf>,
+    max_runs: Option<usize>,
+    on_report: Option<ReportCallback>,
+    previous: Mutex<BTreeMap<String, String>>,
+}
+
+impl Scheduler{
+    pub fn new(schedule: Schedule) -> Scheduler{
+        Scheduler{schedule, state_path: None, max_runs: None, on_report: None, previous: Mutex::new(BTreeMap::new())}
+    }
+    pub fn with_state_file<P: Into<PathBuf>>(mut self, path: P) -> Scheduler{
+        let path = path.into();
+        match fs::read(&path){
+            Ok(data) => match serde_json::from_slice(&data){
+                Ok(previous) => self.previous = Mutex::new(previous),
+                Err(e) => warn!(path = %path.display(), error = %e, "schedule state load failed"),
+            },
+            Err(e) => debug!(path = %path.display(), error = %e, "no schedule state"),
+        }
+        self.state_path = Some(path);
+        self
+    }
+    pub fn with_max_runs(mut self, max_runs: usize) -> Scheduler{
+        self.max_runs = Some(max_runs);
+        self
+    }
+    pub fn on_report<F: Fn(&ChangeReport) + Send + Sync + 'static>(mut self, callback: F) -> Scheduler{
+        self.on_report = Some(Box::new(callback));
+        self
+    }
+
+    pub fn run_once<F>(&self, crawl: F) -> anyhow::Result<ChangeReport>
+    where F: FnOnce() -> anyhow::Result<BTreeMap<String, String>>
+    {
+        let started = now();
+        let current = crawl()?;
+        let mut previous = self.previous.lock().unwrap();
+        let mut report = ChangeReport::diff(&previous, &current);
+        report.started = started;
+        report.finished = now();
+        info!(added = report.added.len(), removed = report.removed.len(), changed = report.changed.len(), unchanged = report.unchanged, "crawl changes");
+        if let Some(path) = &self.state_path{
+            if let Some(p) = path.parent(){
+                fs::create_dir_all(p)?;
+            }
+            fs::write(path, serde_json::to_vec_pretty(&current)?)?;
+        }
+        *previous = current;
+        if let Some(callback) = &self.on_report{
+            callback(&report);
+        }
+        Ok(report)
+    }
+
+    pub fn run<F>(&self, mut crawl: F) -> anyhow::Result<()>
+    where F: FnMut() -> anyhow::Result<BTreeMap<String, String>>
+    {
+        let mut runs = 0;
+        let mut next = match self.schedule{
+            Schedule::Interval(_) => now(),
+            Schedule::Cron(_) => self.schedule.next_after(now()),
+        };
+        while self.max_runs.map(|m| runs < m).unwrap_or(true){
+            if next == u64::MAX{
+                anyhow::bail!("schedule never fires");
+            }
+            let current = now();
+            if next > current{
+                debug!(wait = next - current, "waiting for next run");
+                sleep(Duration::from_secs(next - current));
+            }
+            if let Err(e) = self.run_once(&mut crawl){
+                error!(error = %e, "scheduled crawl failed");
+            }
+            runs += 1;
+            next = self.schedule.next_after(now().max(next));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn days_from_civil(year: i64, month: u32, day: u32) -> i64{
+        let year = if month <= 2 {year - 1} else {year};
+        let era = if year >= 0 {year} else {year - 399} / 400;
+        let yoe = year - era * 400;
+        let mp = if month > 2 {month - 3} else {month + 9} as i64;
+        let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    fn at(year: i64, month: u32, day: u32, hour: u64, minute: u64) -> u64{
+        days_from_civil(year, month, day) as u64 * 86400 + hour * 3600 + minute * 60
+    }
+
+    fn next(expr: &str, time: u64) -> u64{
+        expr.parse::<Cron>().unwrap().next_after(time)
+    }
+
+    #[test]
+    fn converts_days_to_dates(){
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+        assert_eq!(civil_from_days(days_from_civil(2000, 12, 31) + 1), (2001, 1, 1));
+    }
+
+    #[test]
+    fn fires_on_steps(){
+        assert_eq!(next("*/15 * * * *", at(2024, 1, 1, 0, 0)), at(2024, 1, 1, 0, 15));
+        assert_eq!(next("*/15 * * * *", at(2024, 1, 1, 0, 46)), at(2024, 1, 1, 1, 0));
+        assert_eq!(next("5/20 * * * *", at(2024, 1, 1, 0, 25)), at(2024, 1, 1, 0, 45));
+        assert_eq!(next("0 */6 * * *", at(2024, 1, 1, 19, 0)), at(2024, 1, 2, 0, 0));
+        assert_eq!(next("* * * * *", at(2024, 1, 1, 0, 0) + 30), at(2024, 1, 1, 0, 1));
+    }
+
+    #[test]
+    fn fires_on_ranges_and_lists(){
+        assert_eq!(next("0 9-17 * * *", at(2024, 1, 1, 17, 30)), at(2024, 1, 2, 9, 0));
+        assert_eq!(next("0,30 8,20 * * *", at(2024, 1, 1, 8, 0)), at(2024, 1, 1, 8, 30));
+        assert_eq!(next("0,30 8,20 * * *", at(2024, 1, 1, 8, 30)), at(2024, 1, 1, 20, 0));
+        assert_eq!(next("0 0 1-7/3 * *", at(2024, 1, 1, 0, 0)), at(2024, 1, 4, 0, 0));
+        assert_eq!(next("0 0 31 * *", at(2024, 2, 1, 0, 0)), at(2024, 3, 31, 0, 0));
+        assert_eq!(next("0 0 29 2 *", at(2024, 3, 1, 0, 0)), at(2028, 2, 29, 0, 0));
+    }
+
+    #[test]
+    fn treats_weekday_seven_as_sunday(){
+        // 2024-01-01 is a Monday
+        assert_eq!(next("0 0 * * 7", at(2024, 1, 1, 0, 0)), at(2024, 1, 7, 0, 0));
+        assert_eq!("0 0 * * 7".parse::<Cron>().unwrap(), "0 0 * * 0,7".parse::<Cron>().unwrap());
+        assert_eq!(next("0 0 * * 6-7", at(2024, 1, 6, 12, 0)), at(2024, 1, 7, 0, 0));
+        assert_eq!(next("0 0 * * 6-7", at(2024, 1, 7, 12, 0)), at(2024, 1, 13, 0, 0));
+    }
+
+    #[test]
+    fn ors_day_of_month_and_day_of_week(){
+        // every Friday and every 13th
+        let cron = "0 0 13 * 5";
+        assert_eq!(next(cron, at(2024, 1, 1, 0, 0)), at(2024, 1, 5, 0, 0));
+        assert_eq!(next(cron, at(2024, 1, 12, 0, 0)), at(2024, 1, 13, 0, 0));
+        assert_eq!(next(cron, at(2024, 1, 13, 0, 0)), at(2024, 1, 19, 0, 0));
+        // a restricted month still applies to both
+        assert_eq!(next("0 0 13 2 5", at(2024, 1, 1, 0, 0)), at(2024, 2, 2, 0, 0));
+        assert_eq!(next("0 0 * * 5", at(2024, 1, 12, 0, 0)), at(2024, 1, 19, 0, 0));
+    }
+
+    #[test]
+    fn expands_aliases(){
+        for (alias, expr) in [("@yearly", "0 0 1 1 *"), ("@annually", "0 0 1 1 *"), ("@monthly", "0 0 1 * *"), ("@weekly", "0 0 * * 0"), ("@daily", "0 0 * * *"), ("@midnight", "0 0 * * *"), ("@hourly", "0 * * * *")]{
+            assert_eq!(alias.parse::<Cron>().unwrap(), expr.parse::<Cron>().unwrap(), "{}", alias);
+        }
+        let time = at(2024, 1, 10, 10, 10);
+        assert_eq!(next("@hourly", time), at(2024, 1, 10, 11, 0));
+        assert_eq!(next("@daily", time), at(2024, 1, 11, 0, 0));
+        assert_eq!(next("@weekly", time), at(2024, 1, 14, 0, 0));
+        assert_eq!(next("@monthly", time), at(2024, 2, 1, 0, 0));
+        assert_eq!(next(" @yearly ", time), at(2025, 1, 1, 0, 0));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions(){
+        for expr in ["*/0 * * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "* * * 13 *", "* * * * 8", "5-1 * * * *", "a * * * *", "* * * *", "* * * * * *", "@often"]{
+            assert!(expr.parse::<Cron>().is_err(), "{}", expr);
+        }
+        assert_eq!(next("0 0 30 2 *", at(2024, 1, 1, 0, 0)), u64::MAX);
+    }
+
+    #[test]
+    fn diffs_crawls(){
+        let previous: BTreeMap<String, String> = [("a", "1"), ("b", "2"), ("c", "3")].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let current: BTreeMap<String, String> = [("a", "1"), ("b", "9"), ("d", "4")].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let report = ChangeReport::diff(&previous, &current);
+        assert_eq!((report.added, report.removed, report.changed, report.unchanged), (vec!["d".to_string()], vec!["c".to_string()], vec!["b".to_string()], 1));
+        assert!(ChangeReport::diff(&current, &current).is_empty());
+    }
+}
@@ -0,0 +1,289 @@
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::de::value::Error as TraceError;
+use serde::forward_to_deserialize_any;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType{
+    Bool,
+    Int,
+    Double,
+    Text,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column{
+    pub name: String,
+    pub kind: ColumnType,
+    pub nullable: bool,
+}
+
+/// Columns of an exported table. Records are checked against it and any missing,
+/// unknown or mistyped field is an error.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schema{
+    pub columns: Vec<Column>,
+}
+
+impl Schema{
+    pub fn new() -> Schema{
+        Schema::default()
+    }
+    pub fn with_column(mut self, name: &str, kind: ColumnType) -> Schema{
+        self.columns.push(Column{name: name.to_string(), kind, nullable: false});
+        self
+    }
+    pub fn with_nullable(mut self, name: &str, kind: ColumnType) -> Schema{
+        self.columns.push(Column{name: name.to_string(), kind, nullable: true});
+        self
+    }
+    /// Traces the columns of a struct from its `Deserialize` impl: `bool`, integers,
+    /// floats, strings and unit enums map to columns and `Option` fields are nullable.
+    pub fn of<T: DeserializeOwned>() -> anyhow::Result<Schema>{
+        let mut columns = Vec::new();
+        T::deserialize(ItemTracer{columns: &mut columns})?;
+        Ok(Schema{columns})
+    }
+    pub fn column(&self, name: &str) -> Option<&Column>{
+        self.columns.iter().find(|c| c.name == name)
+    }
+
+    pub fn check(&self, record: &Map<String, Value>) -> anyhow::Result<()>{
+        if let Some(name) = record.keys().find(|k| self.column(k).is_none()){
+            anyhow::bail!("field {} is not in the schema", name);
+        }
+        for column in self.columns.iter(){
+            let value = record.get(&column.name).unwrap_or(&Value::Null);
+            let valid = match (column.kind, value){
+                (_, Value::Null) => column.nullable,
+                (ColumnType::Bool, Value::Bool(_)) => true,
+                (ColumnType::Int, Value::Number(n)) => n.as_i64().is_some(),
+                (ColumnType::Double, Value::Number(_)) => true,
+                (ColumnType::Text, Value::String(_)) => true,
+                _ => false,
+            };
+            if !valid{
+                anyhow::bail!("field {} is {}, expected {}", column.name, value, column.describe());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Column{
+    fn describe(&self) -> String{
+        let kind = match self.kind{
+            ColumnType::Bool => "bool",
+            ColumnType::Int => "int",
+            ColumnType::Double => "double",
+            ColumnType::Text => "text",
+        };
+        if self.nullable {format!("nullable {}", kind)} else {kind.to_string()}
+    }
+}
+
+struct ItemTracer<'a>{
+    columns: &'a mut Vec<Column>,
+}
+
+impl<'de> de::Deserializer<'de> for ItemTracer<'_>{
+    type Error = TraceError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, TraceError>{
+        Err(de::Error::custom("item type must be a struct"))
+    }
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, TraceError>{
+        visitor.visit_map(FieldsTracer{fields, index: 0, columns: self.columns})
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, TraceError>{
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any!{
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+struct FieldsTracer<'a>{
+    fields: &'static [&'static str],
+    index: usize,
+    columns: &'a mut Vec<Column>,
+}
+
+impl<'de> MapAccess<'de> for FieldsTracer<'_>{
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, TraceError>{
+        match self.fields.get(self.index){
+            Some(field) => seed.deserialize(field.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, TraceError>{
+        let name = self.fields[self.index];
+        self.index += 1;
+        seed.deserialize(FieldTracer{name, nullable: false, columns: self.columns})
+    }
+}
+
+struct FieldTracer<'a>{
+    name: &'static str,
+    nullable: bool,
+    columns: &'a mut Vec<Column>,
+}
+
+impl FieldTracer<'_>{
+    fn record(self, kind: ColumnType){
+        self.columns.push(Column{name: self.name.to_string(), kind, nullable: self.nullable});
+    }
+}
+
+impl<'de> de::Deserializer<'de> for FieldTracer<'_>{
+    type Error = TraceError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, TraceError>{
+        Err(de::Error::custom(format!("field {} has no column type", self.name)))
+    }
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Bool);
+        visitor.visit_bool(false)
+    }
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Int);
+        visitor.visit_i8(0)
+    }
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Int);
+        visitor.visit_i16(0)
+    }
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Int);
+        visitor.visit_i32(0)
+    }
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Int);
+        visitor.visit_i64(0)
+    }
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Int);
+        visitor.visit_u8(0)
+    }
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Int);
+        visitor.visit_u16(0)
+    }
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Int);
+        visitor.visit_u32(0)
+    }
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Int);
+        visitor.visit_u64(0)
+    }
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Double);
+        visitor.visit_f32(0.0)
+    }
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Double);
+        visitor.visit_f64(0.0)
+    }
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Text);
+        visitor.visit_char(' ')
+    }
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Text);
+        visitor.visit_str("")
+    }
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        self.record(ColumnType::Text);
+        visitor.visit_string(String::new())
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError>{
+        visitor.visit_some(FieldTracer{nullable: true, ..self})
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, TraceError>{
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, variants: &'static [&'static str], visitor: V) -> Result<V::Value, TraceError>{
+        let Some(variant) = variants.first() else {
+            return Err(de::Error::custom(format!("field {} is an empty enum", self.name)));
+        };
+        self.record(ColumnType::Text);
+        visitor.visit_enum((*variant).into_deserializer())
+    }
+
+    forward_to_deserialize_any!{
+        i128 u128 bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use crate::pipeline::to_record;
+    use super::*;
+
+    #[derive(Deserialize, Serialize)]
+    enum Level{
+        Province,
+        #[allow(dead_code)]
+        City,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct Code(String);
+
+    #[derive(Deserialize, Serialize)]
+    struct Row{
+        year: u16,
+        code: Code,
+        #[serde(rename = "parent")]
+        parent_code: Option<String>,
+        level: Level,
+        share: f64,
+        active: bool,
+    }
+
+    #[test]
+    fn traces_struct_fields(){
+        let schema = Schema::of::<Row>().unwrap();
+        assert_eq!(schema, Schema::new()
+            .with_column("year", ColumnType::Int)
+            .with_column("code", ColumnType::Text)
+            .with_nullable("parent", ColumnType::Text)
+            .with_column("level", ColumnType::Text)
+            .with_column("share", ColumnType::Double)
+            .with_column("active", ColumnType::Bool));
+        let row = Row{year: 2023, code: Code("11".to_string()), parent_code: None, level: Level::Province, share: 1.0, active: true};
+        schema.check(&to_record(&row).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rejects_untraceable_types(){
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Nested{
+            tags: Vec<String>,
+        }
+        assert!(Schema::of::<Nested>().unwrap_err().to_string().contains("tags"));
+        assert!(Schema::of::<Value>().is_err());
+        assert!(Schema::of::<String>().is_err());
+    }
+
+    #[test]
+    fn checks_records(){
+        let schema = Schema::new().with_column("id", ColumnType::Int).with_nullable("name", ColumnType::Text);
+        let record = |value: Value| value.as_object().cloned().unwrap();
+        assert!(schema.check(&record(json!({"id": 1, "name": "a"}))).is_ok());
+        assert!(schema.check(&record(json!({"id": 1}))).is_ok());
+        assert!(schema.check(&record(json!({"id": 1.5}))).is_err());
+        assert!(schema.check(&record(json!({"id": null}))).is_err());
+        assert!(schema.check(&record(json!({"id": 1, "name": 2}))).is_err());
+        assert!(schema.check(&record(json!({"id": 1, "extra": true}))).is_err());
+        assert!(schema.check(&record(json!({"id": u64::MAX}))).is_err());
+    }
+}
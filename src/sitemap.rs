@@ -0,0 +1,393 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::Read;
+use std::sync::Arc;
+use flate2::read::GzDecoder;
+use quick_xml::events::Event;
+use quick_xml::name::{Namespace, ResolveResult};
+use quick_xml::NsReader;
+use reqwest::Url;
+use tracing::{debug, info, warn};
+use crate::downloader::Downloader;
+use crate::request::Request;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SitemapEntry{
+    pub url: String,
+    pub lastmod: Option<String>,
+    pub priority: Option<f32>,
+    pub changefreq: Option<String>,
+}
+
+impl SitemapEntry{
+    pub fn lastmod_time(&self) -> Option<u64>{
+        self.lastmod.as_deref().and_then(parse_w3c_datetime)
+    }
+    pub fn priority(&self) -> f32{
+        self.priority.unwrap_or(0.5)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sitemap{
+    pub entries: Vec<SitemapEntry>,
+    pub sitemaps: Vec<SitemapEntry>,
+}
+
+pub fn robots_sitemaps(robots: &str) -> Vec<String>{
+    robots.lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.trim().eq_ignore_ascii_case("sitemap"){
+                let value = value.split('#').next().unwrap_or("").trim();
+                if !value.is_empty(){
+                    return Some(value.to_string());
+                }
+            }
+            None
+        })
+        .collect()
+}
+
+fn gunzip(body: &[u8]) -> anyhow::Result<Vec<u8>>{
+    if !body.starts_with(&[0x1f, 0x8b]){
+        return Ok(body.to_vec());
+    }
+    let mut data = Vec::new();
+    GzDecoder::new(body).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+const SITEMAP_NAMESPACES: [&[u8]; 2] = [b"http://www.sitemaps.org/schemas/sitemap/0.9", b"http://www.google.com/schemas/sitemap/0.84"];
+
+fn is_sitemap_ns(ns: &ResolveResult) -> bool{
+    match ns{
+        ResolveResult::Bound(Namespace(ns)) => SITEMAP_NAMESPACES.contains(ns),
+        ResolveResult::Unbound => true,
+        ResolveResult::Unknown(_) => false,
+    }
+}
+
+/// Parses a urlset or sitemap index, gzipped or not. Only `loc`, `lastmod`, `changefreq`
+/// and `priority` directly under `url`/`sitemap` are read, extension tags such as
+/// `image:loc` are ignored.
+pub fn parse(body: &[u8]) -> anyhow::Result<Sitemap>{
+    let data = gunzip(body)?;
+    let mut reader = NsReader::from_reader(data.as_slice());
+    reader.config_mut().trim_text(true);
+    let mut sitemap = Sitemap::default();
+    let mut buf = Vec::new();
+    let mut depth = 0;
+    let mut entry: Option<(usize, SitemapEntry)> = None;
+    let mut field: Option<String> = None;
+    loop{
+        match reader.read_resolved_event_into(&mut buf)?{
+            (ns, Event::Start(e)) => {
+                depth += 1;
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                let sitemap_ns = is_sitemap_ns(&ns);
+                field = None;
+                match &entry{
+                    None if sitemap_ns && (name == "url" || name == "sitemap") => {
+                        entry = Some((depth, SitemapEntry{url: String::new(), lastmod: None, priority: None, changefreq: None}));
+                    },
+                    Some((entry_depth, _)) if sitemap_ns && depth == entry_depth + 1 => field = Some(name),
+                    _ => {},
+                }
+            },
+            (_, Event::Text(e)) => {
+                if let (Some((_, entry)), Some(field)) = (entry.as_mut(), field.as_deref()){
+                    let text = e.unescape()?.trim().to_string();
+                    set_field(entry, field, text);
+                }
+            },
+            (_, Event::CData(e)) => {
+                if let (Some((_, entry)), Some(field)) = (entry.as_mut(), field.as_deref()){
+                    let text = String::from_utf8_lossy(&e).trim().to_string();
+                    set_field(entry, field, text);
+                }
+            },
+            (_, Event::End(e)) => {
+                field = None;
+                if entry.as_ref().is_some_and(|(d, _)| *d == depth){
+                    if let Some((_, entry)) = entry.take().filter(|(_, e)| !e.url.is_empty()){
+                        match e.local_name().as_ref(){
+                            b"sitemap" => sitemap.sitemaps.push(entry),
+                            _ => sitemap.entries.push(entry),
+                        }
+                    }
+                }
+                depth -= 1;
+            },
+            (_, Event::Eof) => break,
+            _ => {},
+        }
+        buf.clear();
+    }
+    Ok(sitemap)
+}
+
+fn set_field(entry: &mut SitemapEntry, field: &str, text: String){
+    match field{
+        "loc" => entry.url = text,
+        "lastmod" => entry.lastmod = Some(text),
+        "priority" => entry.priority = text.parse().ok(),
+        "changefreq" => entry.changefreq = Some(text),
+        _ => {},
+    }
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64{
+    let year = if month <= 2 {year - 1} else {year};
+    let era = if year >= 0 {year} else {year - 399} / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 {month - 3} else {month + 9}) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+pub fn parse_w3c_datetime(value: &str) -> Option<u64>{
+    let value = value.trim();
+    let (date, time) = match value.split_once('T'){
+        Some((d, t)) => (d, Some(t)),
+        None => (value, None),
+    };
+    let mut parts = date.split('-').map(|p| p.parse::<i64>());
+    let year = parts.next()?.ok()?;
+    let month = parts.next().unwrap_or(Ok(1)).ok()?;
+    let day = parts.next().unwrap_or(Ok(1)).ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day){
+        return None;
+    }
+    let mut seconds = days_from_civil(year, month, day) * 86400;
+    if let Some(time) = time{
+        let (clock, offset) = match time.find(['Z', 'z', '+', '-']){
+            Some(i) => time.split_at(i),
+            None => (time, ""),
+        };
+        let mut parts = clock.split(':');
+        let hour = parts.next()?.parse::<i64>().ok()?;
+        let minute = parts.next().unwrap_or("0").parse::<i64>().ok()?;
+        let second = parts.next().unwrap_or("0").split('.').next()?.parse::<i64>().ok()?;
+        seconds += hour * 3600 + minute * 60 + second;
+        if let Some(sign) = offset.chars().next().filter(|c| *c == '+' || *c == '-'){
+            let (h, m) = offset[1..].split_once(':').unwrap_or((&offset[1..], "0"));
+            let delta = h.parse::<i64>().ok()? * 3600 + m.parse::<i64>().ok()? * 60;
+            seconds -= if sign == '+' {delta} else {-delta};
+        }
+    }
+    u64::try_from(seconds).ok()
+}
+
+pub struct SitemapSource{
+    sitemaps: Vec<String>,
+    robots: Vec<String>,
+    skip_fresh: bool,
+    max_sitemaps: usize,
+}
+
+impl SitemapSource{
+    pub fn new() -> SitemapSource{
+        SitemapSource{sitemaps: Vec::new(), robots: Vec::new(), skip_fresh: false, max_sitemaps: 1000}
+    }
+    pub fn with_sitemap<U: Into<String>>(mut self, url: U) -> SitemapSource{
+        self.sitemaps.push(url.into());
+        self
+    }
+    pub fn with_robots<U: Into<String>>(mut self, site: U) -> SitemapSource{
+        self.robots.push(site.into());
+        self
+    }
+    pub fn with_skip_fresh(mut self, skip_fresh: bool) -> SitemapSource{
+        self.skip_fresh = skip_fresh;
+        self
+    }
+    pub fn with_max_sitemaps(mut self, max_sitemaps: usize) -> SitemapSource{
+        self.max_sitemaps = max_sitemaps;
+        self
+    }
+
+    pub fn discover<E: Send + Sync + 'static>(&self, downloader: &Downloader<E>) -> Vec<String>{
+        let mut sitemaps = self.sitemaps.clone();
+        for site in self.robots.iter(){
+            let base = match Url::parse(site){
+                Ok(url) => url,
+                Err(e) => {
+                    warn!(site = %site, error = %e, "invalid site url");
+                    continue;
+                }
+            };
+            let found = base.join("/robots.txt").ok()
+                .and_then(|url| downloader.fetch(&Request::new(url.as_str())).ok())
+                .filter(|page| page.status == 200)
+                .map(|page| robots_sitemaps(&page.text()))
+                .unwrap_or_default();
+            if found.is_empty(){
+                if let Ok(url) = base.join("/sitemap.xml"){
+                    sitemaps.push(url.to_string());
+                }
+            }else{
+                debug!(site = %site, sitemaps = found.len(), "sitemaps from robots.txt");
+                sitemaps.extend(found);
+            }
+        }
+        sitemaps
+    }
+
+    /// Page entries from all discovered sitemaps and nested indexes, highest priority first.
+    /// Priority only orders this list; once seeded the downloader queue is first in, first out.
+    pub fn entries<E: Send + Sync + 'static>(&self, downloader: &Downloader<E>) -> anyhow::Result<Vec<SitemapEntry>>{
+        let mut queue = VecDeque::from(self.discover(downloader));
+        let mut seen = HashSet::new();
+        let mut urls = HashSet::new();
+        let mut entries = Vec::new();
+        while let Some(url) = queue.pop_front(){
+            if seen.len() >= self.max_sitemaps{
+                warn!(max_sitemaps = self.max_sitemaps, "too many sitemaps");
+                break;
+            }
+            if !seen.insert(url.clone()){
+                continue;
+            }
+            let page = match downloader.fetch(&Request::new(url.as_str())){
+                Ok(page) if page.status == 200 => page,
+                Ok(page) => {
+                    warn!(url = %url, status = page.status, "sitemap fetch failed");
+                    continue;
+                },
+                Err(e) => {
+                    warn!(url = %url, error = %e, "sitemap fetch failed");
+                    continue;
+                }
+            };
+            let sitemap = match parse(&page.body){
+                Ok(sitemap) => sitemap,
+                Err(e) => {
+                    warn!(url = %url, error = %e, "sitemap parse failed");
+                    continue;
+                }
+            };
+            debug!(url = %url, entries = sitemap.entries.len(), sitemaps = sitemap.sitemaps.len(), "sitemap");
+            queue.extend(sitemap.sitemaps.into_iter().map(|s| s.url));
+            entries.extend(sitemap.entries.into_iter().filter(|e| urls.insert(e.url.clone())));
+        }
+        if seen.is_empty(){
+            anyhow::bail!("no sitemap found");
+        }
+        entries.sort_by(|a, b| b.priority().total_cmp(&a.priority()));
+        Ok(entries)
+    }
+
+    pub fn requests<E: Send + Sync + 'static>(&self, downloader: &Downloader<E>) -> anyhow::Result<Vec<Request>>{
+        let mut requests = Vec::new();
+        for entry in self.entries(downloader)?{
+            let mut request = Request::new(entry.url.as_str())
+                .with_meta("sitemap_priority", entry.priority().to_string());
+            if let Some(lastmod) = &entry.lastmod{
+                request = request.with_meta("sitemap_lastmod", lastmod.as_str());
+            }
+            if let (Some(lastmod), Some(meta)) = (entry.lastmod_time(), downloader.cached_meta(&request)){
+                if meta.fetched >= lastmod{
+                    if self.skip_fresh{
+                        debug!(url = %entry.url, "cache newer than lastmod");
+                        continue;
+                    }
+                }else{
+                    request = request.with_force(true);
+                }
+            }
+            requests.push(request);
+        }
+        Ok(requests)
+    }
+
+    pub fn seed<E: Send + Sync + 'static>(&self, downloader: &Downloader<E>, flag: Arc<E>) -> anyhow::Result<usize>{
+        let requests = self.requests(downloader)?;
+        let count = requests.len();
+        for request in requests{
+            downloader.start_request(request.with_shared_flag(Arc::clone(&flag)))?;
+        }
+        info!(urls = count, "seeded from sitemaps");
+        Ok(count)
+    }
+}
+
+impl Default for SitemapSource{
+    fn default() -> Self{
+        SitemapSource::new()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::Write;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use super::*;
+
+    #[test]
+    fn ignores_extension_tags(){
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9"
+        xmlns:image="http://www.google.com/schemas/sitemap-image/1.1"
+        xmlns:news="http://www.google.com/schemas/sitemap-news/0.9">
+  <url>
+    <loc>https://ex.com/page</loc>
+    <image:image><image:loc>https://ex.com/img.jpg</image:loc></image:image>
+    <news:news><news:publication_date>2001-01-01</news:publication_date></news:news>
+    <lastmod>2024-05-01</lastmod>
+    <priority>0.8</priority>
+  </url>
+</urlset>"#;
+        let sitemap = parse(xml.as_bytes()).unwrap();
+        assert_eq!(sitemap.entries, vec![SitemapEntry{
+            url: "https://ex.com/page".to_string(),
+            lastmod: Some("2024-05-01".to_string()),
+            priority: Some(0.8),
+            changefreq: None,
+        }]);
+    }
+
+    #[test]
+    fn ignores_nested_sitemap_names(){
+        let xml = r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9" xmlns:x="urn:x">
+  <url><x:wrap><loc>https://ex.com/wrong</loc></x:wrap><loc>https://ex.com/right</loc></url>
+  <x:url><x:loc>https://ex.com/other</x:loc></x:url>
+</urlset>"#;
+        let sitemap = parse(xml.as_bytes()).unwrap();
+        assert_eq!(sitemap.entries.len(), 1);
+        assert_eq!(sitemap.entries[0].url, "https://ex.com/right");
+    }
+
+    #[test]
+    fn parses_index_without_namespace(){
+        let xml = "<sitemapindex><sitemap><loc><![CDATA[https://ex.com/a.xml]]></loc><lastmod>2024-01-01T10:00:00Z</lastmod></sitemap></sitemapindex>";
+        let sitemap = parse(xml.as_bytes()).unwrap();
+        assert!(sitemap.entries.is_empty());
+        assert_eq!(sitemap.sitemaps.len(), 1);
+        assert_eq!(sitemap.sitemaps[0].url, "https://ex.com/a.xml");
+        assert_eq!(sitemap.sitemaps[0].lastmod_time(), Some(1704103200));
+    }
+
+    #[test]
+    fn parses_gzip(){
+        let xml = r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9"><url><loc>https://ex.com/gz</loc><changefreq>daily</changefreq></url></urlset>"#;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(xml.as_bytes()).unwrap();
+        let sitemap = parse(&encoder.finish().unwrap()).unwrap();
+        assert_eq!(sitemap.entries[0].url, "https://ex.com/gz");
+        assert_eq!(sitemap.entries[0].changefreq.as_deref(), Some("daily"));
+    }
+
+    #[test]
+    fn reads_robots_sitemaps(){
+        let robots = "User-agent: *\nDisallow: /private\nSitemap: https://ex.com/a.xml # main\nsitemap:https://ex.com/b.xml\n";
+        assert_eq!(robots_sitemaps(robots), vec!["https://ex.com/a.xml", "https://ex.com/b.xml"]);
+    }
+
+    #[test]
+    fn parses_w3c_datetimes(){
+        assert_eq!(parse_w3c_datetime("1970-01-02"), Some(86400));
+        assert_eq!(parse_w3c_datetime("2024-01-01T10:00:00+02:00"), Some(1704096000));
+        assert_eq!(parse_w3c_datetime("2024-13-01"), None);
+    }
+}
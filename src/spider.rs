@@ -0,0 +1,195 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, warn};
+use crate::downloader::{Downloader, Response, ResThreadArg, get_res_thread_arg, start_crawl};
+use crate::pipeline::Pipeline;
+use crate::request::Request;
+
+#[allow(clippy::large_enum_variant)]
+pub enum Output<E, I>{
+    Request(Request<E>),
+    Item(I),
+}
+
+pub trait Spider: Send + Sync + 'static{
+    type Flag: Send + Sync + 'static;
+    type Item: Send + 'static;
+
+    fn start_urls(&self) -> Vec<Request<Self::Flag>>;
+    fn parse(&self, response: &Response<Self::Flag>) -> anyhow::Result<Vec<Output<Self::Flag, Self::Item>>>;
+}
+
+type ItemCallback<I> = Box<dyn Fn(I) + Send + Sync>;
+
+/// Counts of what a crawl did, returned by `Engine::run_with_summary`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Summary{
+    pub pages: usize,
+    pub bytes: usize,
+    pub skipped: usize,
+    pub duplicates: usize,
+    pub failed: usize,
+    pub items: usize,
+}
+
+pub struct Engine<S: Spider>{
+    spider: Arc<S>,
+    downloader: Arc<Downloader<S::Flag>>,
+    download_threads: u16,
+    parse_threads: usize,
+    seen: Mutex<HashSet<String>>,
+    items: Mutex<Vec<S::Item>>,
+    on_item: Option<ItemCallback<S::Item>>,
+    pipeline: Option<Pipeline<S::Item>>,
+    summary: Mutex<Summary>,
+}
+
+fn strip_fragment(url: &str) -> &str{
+    match url.split_once('#'){
+        Some((u, _)) => u,
+        None => url,
+    }
+}
+
+impl<S: Spider> Engine<S>{
+    pub fn new(spider: S, downloader: Downloader<S::Flag>) -> Engine<S>{
+        Engine{
+            spider: Arc::new(spider),
+            downloader: Arc::new(downloader),
+            download_threads: 16,
+            parse_threads: 16,
+            seen: Mutex::new(HashSet::new()),
+            items: Mutex::new(Vec::new()),
+            on_item: None,
+            pipeline: None,
+            summary: Mutex::new(Summary::default()),
+        }
+    }
+    pub fn with_threads(mut self, download_threads: u16, parse_threads: usize) -> Engine<S>{
+        self.download_threads = download_threads;
+        self.parse_threads = parse_threads;
+        self
+    }
+    pub fn on_item<F: Fn(S::Item) + Send + Sync + 'static>(mut self, callback: F) -> Engine<S>{
+        self.on_item = Some(Box::new(callback));
+        self
+    }
+    pub fn with_pipeline(mut self, pipeline: Pipeline<S::Item>) -> Engine<S>{
+        self.pipeline = Some(pipeline);
+        self
+    }
+    pub fn downloader(&self) -> &Arc<Downloader<S::Flag>>{
+        &self.downloader
+    }
+
+    fn enqueue(&self, mut request: Request<S::Flag>) -> anyhow::Result<()>{
+        request.url = strip_fragment(&request.url).to_string();
+        if !self.seen.lock().unwrap().insert(request.cache_key()){
+            return Ok(());
+        }
+        self.downloader.start_request(request)
+    }
+
+    fn handle(&self, response: Response<S::Flag>){
+        match &response.data{
+            Ok(Some(body)) => {
+                let mut summary = self.summary.lock().unwrap();
+                summary.pages += 1;
+                summary.bytes += body.len();
+            },
+            Ok(None) => {
+                let mut summary = self.summary.lock().unwrap();
+                match response.duplicate_of{
+                    Some(_) => summary.duplicates += 1,
+                    None => summary.skipped += 1,
+                }
+                return;
+            },
+            Err(e) => {
+                if response.can_retry() {
+                    if let Err(e) = response.retry(response.request().force){
+                        warn!(url = %response.url, error = %e, "retry failed");
+                    }
+                }else{
+                    warn!(url = %response.url, error = %e, "giving up");
+                    self.summary.lock().unwrap().failed += 1;
+                }
+                return;
+            }
+        }
+        let outputs = match self.spider.parse(&response){
+            Ok(outputs) => outputs,
+            Err(e) => {
+                warn!(url = %response.url, error = %e, "parse failed");
+                return;
+            }
+        };
+        for output in outputs{
+            match output{
+                Output::Request(request) => {
+                    if let Err(e) = self.enqueue(request){
+                        warn!(url = %response.url, error = %e, "enqueue failed");
+                    }
+                },
+                Output::Item(mut item) => {
+                    if let Some(pipeline) = &self.pipeline{
+                        match pipeline.process(&mut item, &response.url){
+                            Ok(true) => {},
+                            Ok(false) => continue,
+                            Err(e) => {
+                                warn!(url = %response.url, error = %e, "item failed");
+                                continue;
+                            }
+                        }
+                    }
+                    self.summary.lock().unwrap().items += 1;
+                    match (&self.on_item, &self.pipeline){
+                        (Some(callback), _) => callback(item),
+                        (None, None) => self.items.lock().unwrap().push(item),
+                        (None, Some(_)) => {},
+                    }
+                },
+            }
+        }
+    }
+
+    fn parse_run(&self, arg: ResThreadArg<S::Flag>){
+        while !self.downloader.is_closed() {
+            if let Ok(response) = arg.get_msg_timeout(Duration::from_millis(100)){
+                self.handle(response);
+            }
+        }
+    }
+
+    pub fn run(self) -> anyhow::Result<Vec<S::Item>>{
+        Ok(self.run_with_summary()?.0)
+    }
+
+    pub fn run_with_summary(self) -> anyhow::Result<(Vec<S::Item>, Summary)>{
+        for request in self.spider.start_urls(){
+            self.enqueue(request)?;
+        }
+        let engine = Arc::new(self);
+        let mut handles = Vec::new();
+        for _ in 0..engine.parse_threads{
+            let arg = get_res_thread_arg(&engine.downloader);
+            let e = Arc::clone(&engine);
+            handles.push(thread::spawn(move || e.parse_run(arg)));
+        }
+        start_crawl(&engine.downloader, engine.download_threads);
+        engine.downloader.wait_finish();
+        engine.downloader.close();
+        for handle in handles{
+            let _ = handle.join();
+        }
+        debug!(urls = engine.seen.lock().unwrap().len(), "crawl finished");
+        if let Some(pipeline) = &engine.pipeline{
+            pipeline.finish()?;
+        }
+        let items = std::mem::take(&mut *engine.items.lock().unwrap());
+        let summary = *engine.summary.lock().unwrap();
+        Ok((items, summary))
+    }
+}
@@ -0,0 +1,221 @@
+use std::path::Path;
+use std::sync::Mutex;
+use rusqlite::Connection;
+use rusqlite::types::Value as SqlValue;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::warn;
+use crate::pipeline::{Exporter, to_record};
+use crate::schema::{ColumnType, Schema};
+
+fn quote(name: &str) -> String{
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn sql_type(kind: ColumnType) -> &'static str{
+    match kind{
+        ColumnType::Bool | ColumnType::Int => "INTEGER",
+        ColumnType::Double => "REAL",
+        ColumnType::Text => "TEXT",
+    }
+}
+
+fn sql_value(value: &Value) -> SqlValue{
+    match value{
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(*b as i64),
+        Value::Number(n) => match n.as_i64(){
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Real(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => SqlValue::Text(s.clone()),
+        v => SqlValue::Text(v.to_string()),
+    }
+}
+
+struct State{
+    conn: Connection,
+    pending: usize,
+}
+
+/// Upserts items into `table`, created from the schema if it does not exist.
+/// Items that do not match the schema are rejected with an error.
+/// Rows are committed every `batch_size` items, on `finish` and when the exporter is dropped.
+pub struct SqliteExporter{
+    schema: Schema,
+    insert: String,
+    batch_size: usize,
+    state: Mutex<State>,
+}
+
+impl SqliteExporter{
+    pub fn create<P: AsRef<Path>>(path: P, table: &str, schema: Schema, primary_key: &[&str]) -> anyhow::Result<SqliteExporter>{
+        if let Some(p) = path.as_ref().parent(){
+            std::fs::create_dir_all(p)?;
+        }
+        SqliteExporter::new(Connection::open(path)?, table, schema, primary_key)
+    }
+    pub fn new(conn: Connection, table: &str, schema: Schema, primary_key: &[&str]) -> anyhow::Result<SqliteExporter>{
+        if schema.columns.is_empty(){
+            anyhow::bail!("sqlite schema has no columns");
+        }
+        for key in primary_key.iter(){
+            if schema.column(key).is_none(){
+                anyhow::bail!("primary key {} is not a column of the schema", key);
+            }
+        }
+        let mut defs: Vec<String> = schema.columns.iter()
+            .map(|c| format!("{} {}{}", quote(&c.name), sql_type(c.kind), if c.nullable {""} else {" NOT NULL"}))
+            .collect();
+        let keys: Vec<String> = primary_key.iter().map(|k| quote(k)).collect();
+        if !keys.is_empty(){
+            defs.push(format!("PRIMARY KEY ({})", keys.join(", ")));
+        }
+        conn.execute_batch(&format!("CREATE TABLE IF NOT EXISTS {} ({})", quote(table), defs.join(", ")))?;
+        let names: Vec<String> = schema.columns.iter().map(|c| quote(&c.name)).collect();
+        let params: Vec<String> = (1..=names.len()).map(|i| format!("?{}", i)).collect();
+        let mut insert = format!("INSERT INTO {} ({}) VALUES ({})", quote(table), names.join(", "), params.join(", "));
+        if !keys.is_empty(){
+            let updates: Vec<String> = schema.columns.iter()
+                .filter(|c| !primary_key.contains(&c.name.as_str()))
+                .map(|c| format!("{0} = excluded.{0}", quote(&c.name)))
+                .collect();
+            if updates.is_empty(){
+                insert.push_str(&format!(" ON CONFLICT ({}) DO NOTHING", keys.join(", ")));
+            }else{
+                insert.push_str(&format!(" ON CONFLICT ({}) DO UPDATE SET {}", keys.join(", "), updates.join(", ")));
+            }
+        }
+        Ok(SqliteExporter{
+            schema,
+            insert,
+            batch_size: 1000,
+            state: Mutex::new(State{conn, pending: 0}),
+        })
+    }
+    pub fn with_batch_size(mut self, batch_size: usize) -> SqliteExporter{
+        self.batch_size = batch_size.max(1);
+        self
+    }
+    fn commit(&self) -> anyhow::Result<()>{
+        let mut state = match self.state.lock(){
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if !state.conn.is_autocommit(){
+            state.conn.execute_batch("COMMIT")?;
+        }
+        state.pending = 0;
+        Ok(())
+    }
+}
+
+impl<I: Serialize> Exporter<I> for SqliteExporter{
+    fn export(&self, item: &I) -> anyhow::Result<()>{
+        let record = to_record(item)?;
+        self.schema.check(&record)?;
+        let values: Vec<SqlValue> = self.schema.columns.iter()
+            .map(|c| record.get(&c.name).map(sql_value).unwrap_or(SqlValue::Null))
+            .collect();
+        let mut state = self.state.lock().unwrap();
+        // a failed insert leaves the batch open, so ask the connection rather than trusting pending
+        if state.conn.is_autocommit(){
+            state.conn.execute_batch("BEGIN")?;
+        }
+        state.conn.prepare_cached(&self.insert)?.execute(rusqlite::params_from_iter(values))?;
+        state.pending += 1;
+        if state.pending >= self.batch_size{
+            state.conn.execute_batch("COMMIT")?;
+            state.pending = 0;
+        }
+        Ok(())
+    }
+    fn finish(&self) -> anyhow::Result<()>{
+        self.commit()
+    }
+}
+
+impl Drop for SqliteExporter{
+    fn drop(&mut self){
+        if let Err(e) = self.commit(){
+            warn!(error = %e, "sqlite commit on drop failed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use serde_json::json;
+    use super::*;
+
+    type Row = (i64, String, Option<String>, Option<i64>, String);
+
+    fn exporter(conn: Connection) -> SqliteExporter{
+        let schema = Schema::new()
+            .with_column("year", ColumnType::Int)
+            .with_column("code", ColumnType::Text)
+            .with_nullable("parent", ColumnType::Text)
+            .with_nullable("population", ColumnType::Int);
+        SqliteExporter::new(conn, "codes", schema, &["year", "code"]).unwrap()
+    }
+
+    #[test]
+    fn upserts_on_primary_key(){
+        let exporter = exporter(Connection::open_in_memory().unwrap());
+        exporter.export(&json!({"year": 2023, "code": "11", "parent": null, "population": null})).unwrap();
+        exporter.export(&json!({"year": 2023, "code": "11", "parent": "0", "population": 21})).unwrap();
+        exporter.export(&json!({"year": 2024, "code": "11", "population": 22})).unwrap();
+        Exporter::<Value>::finish(&exporter).unwrap();
+        let state = exporter.state.lock().unwrap();
+        let rows: Vec<Row> = state.conn
+            .prepare("SELECT year, code, parent, population, typeof(population) FROM codes ORDER BY year").unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?))).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(rows, vec![
+            (2023, "11".to_string(), Some("0".to_string()), Some(21), "integer".to_string()),
+            (2024, "11".to_string(), None, Some(22), "integer".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn keeps_exporting_after_a_failed_insert(){
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE codes (year INTEGER NOT NULL, code TEXT NOT NULL, parent TEXT, population INTEGER CHECK (population >= 0), PRIMARY KEY (year, code))").unwrap();
+        let exporter = exporter(conn).with_batch_size(2);
+        assert!(exporter.export(&json!({"year": 2023, "code": "10", "population": -1})).is_err());
+        exporter.export(&json!({"year": 2023, "code": "11", "population": 1})).unwrap();
+        exporter.export(&json!({"year": 2023, "code": "12", "population": 2})).unwrap();
+        assert!(exporter.state.lock().unwrap().conn.is_autocommit());
+        assert!(exporter.export(&json!({"year": 2023, "code": "13", "population": -3})).is_err());
+        exporter.export(&json!({"year": 2023, "code": "14", "population": 4})).unwrap();
+        Exporter::<Value>::finish(&exporter).unwrap();
+        let state = exporter.state.lock().unwrap();
+        let codes: Vec<String> = state.conn.prepare("SELECT code FROM codes ORDER BY code").unwrap()
+            .query_map([], |r| r.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(codes, vec!["11", "12", "14"]);
+    }
+
+    #[test]
+    fn commits_pending_rows_on_drop(){
+        let dir = std::env::temp_dir().join(format!("crawl-sqlite-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("codes.db");
+        let schema = Schema::new().with_column("code", ColumnType::Text);
+        let exporter = SqliteExporter::create(&path, "codes", schema, &["code"]).unwrap();
+        exporter.export(&json!({"code": "11"})).unwrap();
+        exporter.export(&json!({"code": "12"})).unwrap();
+        drop(exporter);
+        let count: i64 = Connection::open(&path).unwrap().query_row("SELECT count(*) FROM codes", [], |r| r.get(0)).unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn rejects_mismatched_records(){
+        let exporter = exporter(Connection::open_in_memory().unwrap());
+        assert!(exporter.export(&json!({"year": 2023, "code": 11})).is_err());
+        assert!(exporter.export(&json!({"year": 2023, "code": "11", "area": 1.5})).is_err());
+        assert!(exporter.export(&json!({"code": "11"})).is_err());
+        assert!(SqliteExporter::new(Connection::open_in_memory().unwrap(), "t", Schema::new().with_column("a", ColumnType::Int), &["b"]).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, sleep};
+use std::time::Duration;
+use bytes::Bytes;
+use tracing::debug;
+use crate::downloader::{Downloader, ResMessage, get_res_thread_arg, start_crawl};
+use crate::request::Request;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FixtureMode{
+    Record,
+    Replay,
+}
+
+impl FixtureMode{
+    pub fn from_env() -> FixtureMode{
+        if std::env::var_os("CRAWL_RECORD").is_some() {FixtureMode::Record} else {FixtureMode::Replay}
+    }
+}
+
+pub fn fixture_downloader<E: Send + Sync + 'static, P: Into<PathBuf>>(dir: P, base_url: &str, mode: FixtureMode) -> Downloader<E>{
+    let dir = dir.into();
+    Downloader::new(dir.to_string_lossy().into_owned(), base_url.to_string())
+        .with_offline(mode == FixtureMode::Replay)
+}
+
+pub fn run_requests<E: Send + Sync + 'static>(downloader: Downloader<E>, requests: Vec<Request<E>>, timeout: Duration) -> anyhow::Result<Vec<ResMessage<E>>>{
+    let downloader = Arc::new(downloader);
+    let count = requests.len();
+    for request in requests{
+        downloader.start_request(request)?;
+    }
+    let arg = get_res_thread_arg(&downloader);
+    start_crawl(&downloader, 4);
+    let mut responses = Vec::with_capacity(count);
+    for _ in 0..count{
+        responses.push(arg.get_msg_timeout(timeout)?);
+    }
+    downloader.close();
+    Ok(responses)
+}
+
+#[derive(Debug, Clone)]
+pub struct MockResponse{
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+    pub delay: Option<Duration>,
+}
+
+impl MockResponse{
+    pub fn new(status: u16) -> MockResponse{
+        MockResponse{status, headers: Vec::new(), body: Bytes::new(), delay: None}
+    }
+    pub fn ok<B: Into<Bytes>>(body: B) -> MockResponse{
+        MockResponse::new(200).with_header("Content-Type", "text/html; charset=utf-8").with_body(body)
+    }
+    pub fn redirect(location: &str) -> MockResponse{
+        MockResponse::new(302).with_header("Location", location)
+    }
+    pub fn with_header<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> MockResponse{
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+    pub fn with_body<B: Into<Bytes>>(mut self, body: B) -> MockResponse{
+        self.body = body.into();
+        self
+    }
+    pub fn with_delay(mut self, delay: Duration) -> MockResponse{
+        self.delay = Some(delay);
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockRequest{
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl MockRequest{
+    pub fn header(&self, name: &str) -> Option<&str>{
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Default)]
+struct Routes{
+    responses: HashMap<String, Vec<MockResponse>>,
+    requests: Vec<MockRequest>,
+}
+
+pub struct MockServer{
+    addr: SocketAddr,
+    routes: Arc<Mutex<Routes>>,
+    stopped: Arc<AtomicBool>,
+}
+
+fn read_request(stream: &TcpStream) -> io::Result<MockRequest>{
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+    let mut headers = Vec::new();
+    loop{
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty(){
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':'){
+            headers.push((k.trim().to_string(), v.trim().to_string()));
+        }
+    }
+    let length = headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(MockRequest{method, path, headers, body: Bytes::from(body)})
+}
+
+fn handle(mut stream: TcpStream, routes: &Mutex<Routes>) -> io::Result<()>{
+    let request = read_request(&stream)?;
+    debug!(method = %request.method, path = %request.path, "mock request");
+    let response = {
+        let mut routes = routes.lock().unwrap();
+        let response = match routes.responses.get_mut(&request.path){
+            Some(list) if list.len() > 1 => list.remove(0),
+            Some(list) if !list.is_empty() => list[0].clone(),
+            _ => MockResponse::new(404),
+        };
+        routes.requests.push(request);
+        response
+    };
+    if let Some(delay) = response.delay{
+        sleep(delay);
+    }
+    let reason = reqwest::StatusCode::from_u16(response.status).ok().and_then(|s| s.canonical_reason()).unwrap_or("");
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason);
+    for (k, v) in response.headers.iter(){
+        head.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", response.body.len()));
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+impl MockServer{
+    pub fn start() -> io::Result<MockServer>{
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let server = MockServer{
+            addr: listener.local_addr()?,
+            routes: Arc::new(Mutex::new(Routes::default())),
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        let routes = Arc::clone(&server.routes);
+        let stopped = Arc::clone(&server.stopped);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten(){
+                if stopped.load(Ordering::Relaxed){
+                    break;
+                }
+                let routes = Arc::clone(&routes);
+                thread::spawn(move || {
+                    if let Err(e) = handle(stream, &routes){
+                        debug!(error = %e, "mock connection failed");
+                    }
+                });
+            }
+        });
+        Ok(server)
+    }
+
+    pub fn route(&self, path: &str, response: MockResponse) -> &MockServer{
+        self.sequence(path, vec![response])
+    }
+    pub fn sequence(&self, path: &str, responses: Vec<MockResponse>) -> &MockServer{
+        self.routes.lock().unwrap().responses.insert(path.to_string(), responses);
+        self
+    }
+
+    pub fn base_url(&self) -> String{
+        format!("http://{}/", self.addr)
+    }
+    pub fn url(&self, path: &str) -> String{
+        format!("http://{}{}", self.addr, path)
+    }
+    pub fn requests(&self) -> Vec<MockRequest>{
+        self.routes.lock().unwrap().requests.clone()
+    }
+    pub fn hits(&self, path: &str) -> usize{
+        self.routes.lock().unwrap().requests.iter().filter(|r| r.path == path).count()
+    }
+}
+
+impl Drop for MockServer{
+    fn drop(&mut self){
+        self.stopped.store(true, Ordering::Relaxed);
+        let _ = TcpStream::connect(self.addr);
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use reqwest::{Method, Url};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING};
+use tracing::{debug, warn};
+use crate::request::hex_digest;
+use crate::schedule::civil_from_days;
+
+const VERSION: &str = "WARC/1.1";
+const IDENTICAL_PAYLOAD: &str = "http://netpreserve.org/warc/1.1/revisit/identical-payload-digest";
+const NOT_MODIFIED: &str = "http://netpreserve.org/warc/1.1/revisit/server-not-modified";
+
+pub fn format_date(time: SystemTime) -> String{
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = duration.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, duration.subsec_micros())
+}
+
+pub fn record_id() -> String{
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let seed = format!("{:?}-{}-{}", SystemTime::now(), std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+    let h = hex_digest(seed.as_bytes());
+    format!("<urn:uuid:{}-{}-4{}-a{}-{}>", &h[0..8], &h[8..12], &h[13..16], &h[17..20], &h[20..32])
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WarcRecord{
+    pub headers: Vec<(String, String)>,
+    pub block: Bytes,
+}
+
+impl WarcRecord{
+    pub fn new(record_type: &str, target_uri: Option<&str>) -> WarcRecord{
+        let mut record = WarcRecord::default()
+            .with_header("WARC-Type", record_type)
+            .with_header("WARC-Record-ID", record_id())
+            .with_header("WARC-Date", format_date(SystemTime::now()));
+        if let Some(uri) = target_uri{
+            record = record.with_header("WARC-Target-URI", uri);
+        }
+        record
+    }
+    pub fn with_header<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> WarcRecord{
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+    pub fn with_block<B: Into<Bytes>>(mut self, content_type: &str, block: B) -> WarcRecord{
+        self.block = block.into();
+        let digest = format!("sha256:{}", hex_digest(&self.block));
+        self.with_header("Content-Type", content_type)
+            .with_header("WARC-Block-Digest", digest)
+    }
+    pub fn header(&self, name: &str) -> Option<&str>{
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+    pub fn record_type(&self) -> Option<&str>{
+        self.header("WARC-Type")
+    }
+    pub fn record_id(&self) -> Option<&str>{
+        self.header("WARC-Record-ID")
+    }
+    pub fn target_uri(&self) -> Option<&str>{
+        self.header("WARC-Target-URI")
+    }
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut out = format!("{}\r\n", VERSION).into_bytes();
+        for (k, v) in self.headers.iter(){
+            out.extend_from_slice(format!("{}: {}\r\n", k, v).as_bytes());
+        }
+        out.extend_from_slice(format!("Content-Length: {}\r\n\r\n", self.block.len()).as_bytes());
+        out.extend_from_slice(&self.block);
+        out.extend_from_slice(b"\r\n\r\n");
+        out
+    }
+}
+
+fn write_headers(out: &mut Vec<u8>, headers: &HeaderMap){
+    for (k, v) in headers.iter(){
+        out.extend_from_slice(k.as_str().as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(v.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+}
+
+pub fn http_request_block(method: &Method, url: &Url, headers: &HeaderMap, body: Option<&[u8]>) -> Vec<u8>{
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query(){
+        target.push('?');
+        target.push_str(query);
+    }
+    let mut out = format!("{} {} HTTP/1.1\r\n", method, target).into_bytes();
+    if let Some(host) = url.host_str(){
+        let host = match url.port(){
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        out.extend_from_slice(format!("host: {}\r\n", host).as_bytes());
+    }
+    write_headers(&mut out, headers);
+    if let Some(body) = body{
+        out.extend_from_slice(body);
+    }
+    out
+}
+
+fn response_head(status: u16, headers: &HeaderMap, length: Option<usize>) -> Vec<u8>{
+    let reason = reqwest::StatusCode::from_u16(status).ok().and_then(|s| s.canonical_reason()).unwrap_or("");
+    let mut out = format!("HTTP/1.1 {} {}\r\n", status, reason).into_bytes();
+    let mut headers = headers.clone();
+    headers.remove(TRANSFER_ENCODING);
+    if let Some(length) = length{
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+    }
+    write_headers(&mut out, &headers);
+    out
+}
+
+pub fn http_response_block(status: u16, headers: &HeaderMap, body: &[u8]) -> Vec<u8>{
+    let mut out = response_head(status, headers, Some(body.len()));
+    out.extend_from_slice(body);
+    out
+}
+
+pub fn parse_http_response(block: &[u8]) -> Option<(u16, HeaderMap, Bytes)>{
+    let end = block.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&block[..end]).ok()?;
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+    let mut headers = HeaderMap::new();
+    for line in lines{
+        if let Some((k, v)) = line.split_once(':'){
+            if let (Ok(k), Ok(v)) = (HeaderName::from_bytes(k.trim().as_bytes()), HeaderValue::from_str(v.trim())){
+                headers.append(k, v);
+            }
+        }
+    }
+    Some((status, headers, Bytes::copy_from_slice(&block[end + 4..])))
+}
+
+pub struct WarcWriter{
+    dir: PathBuf,
+    prefix: String,
+    max_size: u64,
+    gzip: bool,
+    file: Option<File>,
+    size: u64,
+    index: usize,
+    digests: HashMap<String, (String, String)>,
+}
+
+impl WarcWriter{
+    pub fn new<P: Into<PathBuf>, S: Into<String>>(dir: P, prefix: S) -> WarcWriter{
+        WarcWriter{
+            dir: dir.into(),
+            prefix: prefix.into(),
+            max_size: 1 << 30,
+            gzip: true,
+            file: None,
+            size: 0,
+            index: 0,
+            digests: HashMap::new(),
+        }
+    }
+    pub fn with_max_size(mut self, max_size: u64) -> WarcWriter{
+        self.max_size = max_size;
+        self
+    }
+    pub fn with_gzip(mut self, gzip: bool) -> WarcWriter{
+        self.gzip = gzip;
+        self
+    }
+
+    fn open(&mut self) -> io::Result<()>{
+        fs::create_dir_all(&self.dir)?;
+        let stamp: String = format_date(SystemTime::now()).chars().filter(|c| c.is_ascii_digit()).take(14).collect();
+        let name = format!("{}-{}-{:05}.warc{}", self.prefix, stamp, self.index, if self.gzip {".gz"} else {""});
+        let path = self.dir.join(&name);
+        debug!(path = %path.display(), "warc file opened");
+        self.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        self.size = 0;
+        self.index += 1;
+        let info = format!("software: crawl/{}\r\nformat: WARC File Format 1.1\r\n", env!("CARGO_PKG_VERSION"));
+        let record = WarcRecord::new("warcinfo", None)
+            .with_header("WARC-Filename", name)
+            .with_block("application/warc-fields", info);
+        self.write_raw(&record)
+    }
+
+    fn write_raw(&mut self, record: &WarcRecord) -> io::Result<()>{
+        let data = record.to_bytes();
+        let data = if self.gzip{
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data)?;
+            encoder.finish()?
+        }else{
+            data
+        };
+        if let Some(file) = self.file.as_mut(){
+            file.write_all(&data)?;
+        }
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn write(&mut self, record: &WarcRecord) -> io::Result<()>{
+        if self.file.is_none() || self.size >= self.max_size{
+            self.open()?;
+        }
+        self.write_raw(record)
+    }
+
+    pub fn write_exchange(&mut self, request: Vec<u8>, url: &str, status: u16, headers: &HeaderMap, body: &[u8], meta: &[(String, String)]) -> io::Result<()>{
+        let digest = format!("sha256:{}", hex_digest(body));
+        let date = format_date(SystemTime::now());
+        let response = match self.digests.get(&digest){
+            Some((uri, refers_date)) => WarcRecord::new("revisit", Some(url))
+                .with_header("WARC-Profile", IDENTICAL_PAYLOAD)
+                .with_header("WARC-Refers-To-Target-URI", uri.as_str())
+                .with_header("WARC-Refers-To-Date", refers_date.as_str())
+                .with_header("WARC-Payload-Digest", digest.as_str())
+                .with_block("application/http;msgtype=response", response_head(status, headers, Some(body.len()))),
+            None => {
+                self.digests.insert(digest.clone(), (url.to_string(), date.clone()));
+                WarcRecord::new("response", Some(url))
+                    .with_header("WARC-Payload-Digest", digest.as_str())
+                    .with_block("application/http;msgtype=response", http_response_block(status, headers, body))
+            },
+        };
+        self.write_related(response, request, url, meta)
+    }
+
+    pub fn write_not_modified(&mut self, request: Vec<u8>, url: &str, headers: &HeaderMap, meta: &[(String, String)]) -> io::Result<()>{
+        let response = WarcRecord::new("revisit", Some(url))
+            .with_header("WARC-Profile", NOT_MODIFIED)
+            .with_block("application/http;msgtype=response", response_head(304, headers, None));
+        self.write_related(response, request, url, meta)
+    }
+
+    fn write_related(&mut self, response: WarcRecord, request: Vec<u8>, url: &str, meta: &[(String, String)]) -> io::Result<()>{
+        let id = response.record_id().unwrap_or_default().to_string();
+        let request = WarcRecord::new("request", Some(url))
+            .with_header("WARC-Concurrent-To", id.as_str())
+            .with_block("application/http;msgtype=request", request);
+        self.write(&request)?;
+        self.write_raw(&response)?;
+        if !meta.is_empty(){
+            let fields: String = meta.iter().map(|(k, v)| format!("{}: {}\r\n", k, v)).collect();
+            let metadata = WarcRecord::new("metadata", Some(url))
+                .with_header("WARC-Refers-To", id.as_str())
+                .with_block("application/warc-fields", fields);
+            self.write_raw(&metadata)?;
+        }
+        if let Some(file) = self.file.as_mut(){
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+pub struct WarcReader<R: BufRead>{
+    reader: R,
+}
+
+impl WarcReader<Box<dyn BufRead>>{
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<WarcReader<Box<dyn BufRead>>>{
+        let mut file = BufReader::new(File::open(path)?);
+        let gzip = file.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+        let reader: Box<dyn BufRead> = if gzip{
+            Box::new(BufReader::new(MultiGzDecoder::new(file)))
+        }else{
+            Box::new(file)
+        };
+        Ok(WarcReader{reader})
+    }
+}
+
+impl<R: BufRead> WarcReader<R>{
+    pub fn new(reader: R) -> WarcReader<R>{
+        WarcReader{reader}
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<WarcRecord>>{
+        let mut line = String::new();
+        loop{
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0{
+                return Ok(None);
+            }
+            if !line.trim().is_empty(){
+                break;
+            }
+        }
+        if !line.starts_with("WARC/"){
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid warc record: {}", line.trim())));
+        }
+        let mut record = WarcRecord::default();
+        let mut length = 0;
+        loop{
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 || line.trim().is_empty(){
+                break;
+            }
+            if let Some((k, v)) = line.split_once(':'){
+                let (k, v) = (k.trim(), v.trim());
+                if k.eq_ignore_ascii_case("Content-Length"){
+                    length = v.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid content length"))?;
+                }else{
+                    record.headers.push((k.to_string(), v.to_string()));
+                }
+            }
+        }
+        let mut block = vec![0; length];
+        self.reader.read_exact(&mut block)?;
+        record.block = Bytes::from(block);
+        Ok(Some(record))
+    }
+}
+
+impl<R: BufRead> Iterator for WarcReader<R>{
+    type Item = io::Result<WarcRecord>;
+
+    fn next(&mut self) -> Option<Self::Item>{
+        self.read_record().transpose()
+    }
+}
+
+#[derive(Default)]
+pub struct WarcArchive{
+    responses: HashMap<String, (u16, HeaderMap, Bytes)>,
+    payloads: HashMap<String, Bytes>,
+    pending: Vec<(String, u16, HeaderMap, String)>,
+}
+
+impl WarcArchive{
+    pub fn new() -> WarcArchive{
+        WarcArchive::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<WarcArchive>{
+        let path = path.as_ref();
+        let mut archive = WarcArchive::new();
+        if path.is_dir(){
+            let mut files: Vec<PathBuf> = fs::read_dir(path)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.to_string_lossy().ends_with(".warc") || p.to_string_lossy().ends_with(".warc.gz"))
+                .collect();
+            files.sort();
+            for file in files{
+                archive.add_file(file)?;
+            }
+        }else{
+            archive.add_file(path)?;
+        }
+        for (uri, ..) in archive.pending.iter(){
+            warn!(url = %uri, "revisit without original payload");
+        }
+        Ok(archive)
+    }
+
+    pub fn add_file<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<usize>{
+        let mut count = 0;
+        for record in WarcReader::open(path)?{
+            let record = record?;
+            let (Some(kind), Some(uri)) = (record.record_type(), record.target_uri()) else {
+                continue;
+            };
+            let Some((status, headers, body)) = parse_http_response(&record.block) else {
+                continue;
+            };
+            let digest = record.header("WARC-Payload-Digest").unwrap_or_default().to_string();
+            match (kind, record.header("WARC-Profile")){
+                ("response", _) => {
+                    if !digest.is_empty(){
+                        self.payloads.insert(digest, body.clone());
+                    }
+                    self.responses.insert(uri.to_string(), (status, headers, body));
+                    count += 1;
+                },
+                ("revisit", Some(IDENTICAL_PAYLOAD)) => self.pending.push((uri.to_string(), status, headers, digest)),
+                _ => {},
+            }
+        }
+        let pending = std::mem::take(&mut self.pending);
+        for (uri, status, headers, digest) in pending{
+            match self.payloads.get(&digest){
+                Some(body) => {
+                    self.responses.insert(uri, (status, headers, body.clone()));
+                    count += 1;
+                },
+                None => self.pending.push((uri, status, headers, digest)),
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn get(&self, url: &str) -> Option<(u16, &HeaderMap, &Bytes)>{
+        self.responses.get(url).map(|(s, h, b)| (*s, h, b))
+    }
+    pub fn len(&self) -> usize{
+        self.responses.len()
+    }
+    pub fn is_empty(&self) -> bool{
+        self.responses.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+    use reqwest::header::CONTENT_TYPE;
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("crawl-warc-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn request(url: &str) -> Vec<u8>{
+        http_request_block(&Method::GET, &Url::parse(url).unwrap(), &HeaderMap::new(), None)
+    }
+
+    fn records(path: &Path) -> Vec<WarcRecord>{
+        WarcReader::open(path).unwrap().map(|r| r.unwrap()).collect()
+    }
+
+    #[test]
+    fn round_trips_responses_revisits_and_not_modified(){
+        let dir = temp_dir("round-trip");
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        let mut writer = WarcWriter::new(&dir, "test").with_max_size(1);
+        let meta = vec![("charset".to_string(), "utf-8".to_string())];
+        writer.write_exchange(request("http://example.com/a"), "http://example.com/a", 200, &headers, b"same body", &meta).unwrap();
+        writer.write_exchange(request("http://example.com/b"), "http://example.com/b", 200, &headers, b"same body", &[]).unwrap();
+        writer.write_not_modified(request("http://example.com/a"), "http://example.com/a", &headers, &[]).unwrap();
+
+        let mut files: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        files.sort();
+        assert_eq!(files.len(), 3);
+        assert!(files.iter().all(|f| f.to_string_lossy().ends_with(".warc.gz")));
+        for file in files.iter(){
+            let data = fs::read(file).unwrap();
+            let mut first = String::new();
+            GzDecoder::new(&data[..]).read_to_string(&mut first).unwrap();
+            assert!(first.starts_with("WARC/1.1\r\n"));
+            assert_eq!(first.matches("WARC/1.1\r\n").count(), 1);
+            let mut all = String::new();
+            MultiGzDecoder::new(&data[..]).read_to_string(&mut all).unwrap();
+            assert!(all.starts_with(&first) && all.len() > first.len());
+        }
+
+        let types: Vec<Vec<String>> = files.iter()
+            .map(|f| records(f).iter().map(|r| r.record_type().unwrap_or_default().to_string()).collect())
+            .collect();
+        assert_eq!(types, vec![
+            vec!["warcinfo", "request", "response", "metadata"],
+            vec!["warcinfo", "request", "revisit"],
+            vec!["warcinfo", "request", "revisit"],
+        ]);
+        let first = records(&files[0]);
+        assert_eq!(first[1].header("WARC-Concurrent-To"), first[2].record_id());
+        assert_eq!(first[3].header("WARC-Refers-To"), first[2].record_id());
+        assert_eq!(&first[3].block[..], b"charset: utf-8\r\n");
+        assert_eq!(first[2].header("WARC-Block-Digest"), Some(format!("sha256:{}", hex_digest(&first[2].block)).as_str()));
+        let revisit = &records(&files[1])[2];
+        assert_eq!(revisit.header("WARC-Profile"), Some(IDENTICAL_PAYLOAD));
+        assert_eq!(revisit.header("WARC-Refers-To-Target-URI"), Some("http://example.com/a"));
+        assert_eq!(revisit.header("WARC-Payload-Digest"), first[2].header("WARC-Payload-Digest"));
+        let not_modified = &records(&files[2])[2];
+        assert_eq!(not_modified.header("WARC-Profile"), Some(NOT_MODIFIED));
+        assert!(not_modified.block.starts_with(b"HTTP/1.1 304 Not Modified\r\n"));
+
+        let archive = WarcArchive::load(&dir).unwrap();
+        assert_eq!(archive.len(), 2);
+        for url in ["http://example.com/a", "http://example.com/b"]{
+            let (status, headers, body) = archive.get(url).unwrap();
+            assert_eq!(status, 200);
+            assert_eq!(headers.get(CONTENT_TYPE).unwrap(), "text/html");
+            assert_eq!(&body[..], b"same body");
+        }
+    }
+
+    #[test]
+    fn reads_plain_warc_files(){
+        let dir = temp_dir("plain");
+        let mut writer = WarcWriter::new(&dir, "plain").with_gzip(false);
+        writer.write_exchange(request("http://example.com/a?x=1"), "http://example.com/a?x=1", 404, &HeaderMap::new(), b"missing", &[]).unwrap();
+        let files: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].to_string_lossy().ends_with(".warc"));
+        let records = records(&files[0]);
+        assert!(records[1].block.starts_with(b"GET /a?x=1 HTTP/1.1\r\nhost: example.com\r\n"));
+        let archive = WarcArchive::load(&files[0]).unwrap();
+        let (status, _, body) = archive.get("http://example.com/a?x=1").unwrap();
+        assert_eq!((status, &body[..]), (404, &b"missing"[..]));
+    }
+}
@@ -0,0 +1,73 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crawl::config::CrawlConfig;
+use crawl::fingerprint::Dedup;
+use crawl::spider::Summary;
+use crawl::testing::{MockResponse, MockServer};
+
+fn cache_dir(name: &str) -> PathBuf{
+    let dir = std::env::temp_dir().join(format!("crawl-config-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn html(body: &str) -> MockResponse{
+    MockResponse::new(200).with_header("Content-Type", "text/html").with_body(body.to_string())
+}
+
+fn config(server: &MockServer, dir: &Path, extra: &str) -> CrawlConfig{
+    CrawlConfig::from_toml(&format!(
+        "seeds = [\"{}\"]\ncache_dir = \"{}\"\nthreads = 2\nparse_threads = 2\n{}\n[[follow]]\n",
+        server.url("/index.html"), dir.display(), extra,
+    )).unwrap()
+}
+
+#[test]
+fn engine_reports_a_summary(){
+    let server = MockServer::start().unwrap();
+    server.route("/index.html", html(r#"<a href="a.html">a</a><a href="b.html">b</a><a href="missing.html">m</a><a href="busy.html">busy</a>"#));
+    server.route("/a.html", html("same"));
+    server.route("/b.html", html("same"));
+    server.route("/busy.html", MockResponse::new(503));
+    let dir = cache_dir("summary");
+    let config = config(&server, &dir, "[retry]\nmax = 1");
+    let downloader = config.downloader().unwrap().with_dedup(Dedup::Exact);
+    let (_, summary) = config.engine_with(downloader).unwrap().run_with_summary().unwrap();
+    assert_eq!(summary, Summary{pages: 3, bytes: summary.bytes, skipped: 0, duplicates: 1, failed: 1, items: 0});
+    assert_eq!(server.hits("/busy.html"), 2);
+}
+
+#[test]
+fn extracts_items_only_from_successful_html(){
+    let server = MockServer::start().unwrap();
+    server.route("/index.html", html(r#"<title>Index</title><a href="page.html">p</a><a href="missing.html">m</a><a href="data.json">d</a>"#));
+    server.route("/page.html", html("<title>Page</title>"));
+    server.route("/missing.html", MockResponse::new(404).with_header("Content-Type", "text/html").with_body("<title>Not found</title>"));
+    server.route("/data.json", MockResponse::new(200).with_header("Content-Type", "application/json").with_body(r#"{"title": "data"}"#));
+    let dir = cache_dir("items");
+    let config = config(&server, &dir, "[[items]]\n[items.fields]\ntitle = \"title\"");
+    let mut titles: Vec<String> = config.engine().unwrap().run().unwrap().iter()
+        .map(|item| item["title"].as_str().unwrap_or_default().to_string())
+        .collect();
+    titles.sort();
+    assert_eq!(titles, vec!["Index", "Page"]);
+    assert_eq!(server.hits("/missing.html"), 1);
+    assert_eq!(server.hits("/data.json"), 1);
+}
+
+#[test]
+fn follows_links_only_from_successful_html(){
+    let server = MockServer::start().unwrap();
+    server.route("/index.html", html(r#"<a href="page.html">p</a><a href="missing.html">m</a><a href="data.json">d</a><link rel="stylesheet" href="gone.css">"#));
+    server.route("/page.html", html("<title>Page</title>"));
+    server.route("/missing.html", MockResponse::new(404).with_header("Content-Type", "text/html").with_body(r#"<a href="from-missing.html">x</a><img src="missing.png">"#));
+    server.route("/data.json", MockResponse::new(200).with_header("Content-Type", "application/json").with_body(r#"{"html": "<a href=\"from-json.html\">x</a>"}"#));
+    server.route("/gone.css", MockResponse::new(404).with_header("Content-Type", "text/css").with_body("body{background:url(from-css.png)}"));
+    let dir = cache_dir("follow");
+    config(&server, &dir, "requisites = true").engine().unwrap().run().unwrap();
+    assert_eq!(server.hits("/page.html"), 1);
+    assert_eq!(server.hits("/gone.css"), 1);
+    for path in ["/from-missing.html", "/missing.png", "/from-json.html", "/from-css.png"]{
+        assert_eq!(server.hits(path), 0, "{}", path);
+    }
+}
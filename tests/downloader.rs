@@ -0,0 +1,250 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crawl::downloader::{Downloader, NotCached, ResMessage, ServerError};
+use crawl::fingerprint::Dedup;
+use crawl::link::LinkExtractor;
+use crawl::request::Request;
+use crawl::testing::{FixtureMode, MockResponse, MockServer, fixture_downloader, run_requests};
+use crawl::warc::{WarcReader, WarcWriter};
+use reqwest::header::{HeaderName, HeaderValue};
+
+fn cache_dir(name: &str) -> PathBuf{
+    let dir = std::env::temp_dir().join(format!("crawl-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn downloader(dir: &Path, server: &MockServer) -> Downloader<()>{
+    Downloader::new(dir.to_string_lossy().into_owned(), server.base_url())
+}
+
+fn fetch(downloader: Downloader<()>, url: String) -> ResMessage<()>{
+    run_requests(downloader, vec![Request::new(url)], Duration::from_secs(10)).unwrap().remove(0)
+}
+
+#[test]
+fn retries_server_errors_without_caching_them(){
+    let server = MockServer::start().unwrap();
+    server.sequence("/a", vec![MockResponse::new(500).with_body("oops"), MockResponse::ok("fine")]);
+    let dir = cache_dir("retry");
+    let response = fetch(downloader(&dir, &server), server.url("/a"));
+    assert_eq!(response.body().map(|b| &b[..]), Some(&b"fine"[..]));
+    assert_eq!(response.status, Some(200));
+    assert_eq!(response.attempt, 1);
+    assert_eq!(server.hits("/a"), 2);
+    assert_eq!(fs::read(dir.join("a")).unwrap(), b"fine");
+}
+
+#[test]
+fn gives_up_on_server_errors_after_max_retries(){
+    let server = MockServer::start().unwrap();
+    server.route("/a", MockResponse::new(503).with_body("busy"));
+    let dir = cache_dir("give-up");
+    let response = fetch(downloader(&dir, &server).with_retries(2), server.url("/a"));
+    let error = response.data.as_ref().unwrap_err();
+    assert_eq!(error.downcast_ref::<ServerError>().map(|e| e.status), Some(503));
+    assert_eq!(response.status, Some(503));
+    assert_eq!(server.hits("/a"), 3);
+    assert!(!dir.join("a").exists());
+}
+
+#[test]
+fn keeps_client_errors_with_their_status(){
+    let server = MockServer::start().unwrap();
+    let dir = cache_dir("not-found");
+    let response = fetch(downloader(&dir, &server), server.url("/missing"));
+    assert!(response.body().is_some());
+    assert_eq!(response.status, Some(404));
+    assert_eq!(server.hits("/missing"), 1);
+}
+
+#[test]
+fn serves_cached_pages_without_network(){
+    let server = MockServer::start().unwrap();
+    server.route("/a", MockResponse::ok("cached"));
+    let dir = cache_dir("cache-hit");
+    fetch(downloader(&dir, &server), server.url("/a"));
+    let response = fetch(downloader(&dir, &server), server.url("/a"));
+    assert_eq!(response.body().map(|b| &b[..]), Some(&b"cached"[..]));
+    assert_eq!(response.status, Some(200));
+    assert_eq!(server.hits("/a"), 1);
+
+    let forced = run_requests(downloader(&dir, &server), vec![Request::new(server.url("/a")).with_force(true)], Duration::from_secs(10)).unwrap();
+    assert!(forced[0].body().is_some());
+    assert_eq!(server.hits("/a"), 2);
+}
+
+#[test]
+fn revalidates_cached_pages_with_etag(){
+    let server = MockServer::start().unwrap();
+    server.route("/a", MockResponse::ok("first").with_header("ETag", "\"v1\""));
+    let dir = cache_dir("revalidate");
+    fetch(downloader(&dir, &server), server.url("/a"));
+
+    server.route("/a", MockResponse::new(304));
+    let response = fetch(downloader(&dir, &server).with_revalidate(true), server.url("/a"));
+    assert_eq!(response.body().map(|b| &b[..]), Some(&b"first"[..]));
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[1].header("If-None-Match"), Some("\"v1\""));
+
+    server.route("/a", MockResponse::ok("second").with_header("ETag", "\"v2\""));
+    let response = fetch(downloader(&dir, &server).with_revalidate(true), server.url("/a"));
+    assert_eq!(response.body().map(|b| &b[..]), Some(&b"second"[..]));
+    assert_eq!(fs::read(dir.join("a")).unwrap(), b"second");
+}
+
+#[test]
+fn offline_mode_serves_cache_only(){
+    let server = MockServer::start().unwrap();
+    server.route("/a", MockResponse::ok("cached"));
+    let dir = cache_dir("offline");
+    fetch(downloader(&dir, &server), server.url("/a"));
+
+    let responses = run_requests(
+        downloader(&dir, &server).with_offline(true),
+        vec![Request::new(server.url("/a")).with_force(true), Request::new(server.url("/b"))],
+        Duration::from_secs(10),
+    ).unwrap();
+    let cached = responses.iter().find(|r| r.url.ends_with("/a")).unwrap();
+    assert_eq!(cached.body().map(|b| &b[..]), Some(&b"cached"[..]));
+    let missing = responses.iter().find(|r| r.url.ends_with("/b")).unwrap();
+    assert!(missing.data.as_ref().unwrap_err().is::<NotCached>());
+    assert!(!missing.can_retry());
+    assert_eq!(server.hits("/a"), 1);
+    assert_eq!(server.hits("/b"), 0);
+}
+
+#[test]
+fn fixtures_replay_what_was_recorded(){
+    let server = MockServer::start().unwrap();
+    server.route("/a", MockResponse::ok("recorded"));
+    let dir = cache_dir("fixtures");
+    let record = fixture_downloader::<(), _>(&dir, &server.base_url(), FixtureMode::Record);
+    fetch(record, server.url("/a"));
+
+    let replay = fixture_downloader::<(), _>(&dir, &server.base_url(), FixtureMode::Replay);
+    assert!(replay.is_offline());
+    let response = fetch(replay, server.url("/a"));
+    assert_eq!(response.body().map(|b| &b[..]), Some(&b"recorded"[..]));
+    assert_eq!(server.hits("/a"), 1);
+}
+
+#[test]
+fn reports_duplicates_apart_from_skipped_requests(){
+    let server = MockServer::start().unwrap();
+    server.route("/a", MockResponse::ok("same"));
+    server.route("/b", MockResponse::ok("same"));
+    let dir = cache_dir("dedup");
+    let responses = run_requests(
+        downloader(&dir, &server).with_dedup(Dedup::Exact),
+        vec![Request::new(server.url("/a")), Request::new(server.url("/b")), Request::new("http://elsewhere.invalid/")],
+        Duration::from_secs(10),
+    ).unwrap();
+    let pages: Vec<&ResMessage<()>> = responses.iter().filter(|r| r.body().is_some()).collect();
+    let duplicates: Vec<&ResMessage<()>> = responses.iter().filter(|r| r.duplicate_of.is_some()).collect();
+    assert_eq!(pages.len(), 1);
+    assert_eq!(duplicates.len(), 1);
+    assert_eq!(duplicates[0].duplicate_of.as_deref(), Some(pages[0].url.as_str()));
+    assert!(matches!(duplicates[0].data, Ok(None)));
+    let skipped = responses.iter().find(|r| r.url.starts_with("http://elsewhere")).unwrap();
+    assert!(matches!(skipped.data, Ok(None)));
+    assert_eq!(skipped.duplicate_of, None);
+}
+
+#[test]
+fn stores_directory_urls_as_index_html(){
+    let server = MockServer::start().unwrap();
+    server.route("/", MockResponse::ok("home"));
+    server.route("/docs/", MockResponse::ok("docs"));
+    let dir = cache_dir("index");
+    let urls = [server.base_url(), server.url("/docs/")];
+    for _ in 0..2{
+        let requests = urls.iter().map(|url| Request::new(url.as_str())).collect();
+        let responses = run_requests(downloader(&dir, &server), requests, Duration::from_secs(10)).unwrap();
+        assert!(responses.iter().all(|r| r.body().is_some() && r.attempt == 0));
+    }
+    assert_eq!(fs::read(dir.join("index.html")).unwrap(), b"home");
+    assert_eq!(fs::read(dir.join("docs/index.html")).unwrap(), b"docs");
+    assert_eq!(server.hits("/"), 1);
+    assert_eq!(server.hits("/docs/"), 1);
+}
+
+#[test]
+fn archives_requests_with_default_headers(){
+    let server = MockServer::start().unwrap();
+    server.route("/a", MockResponse::ok("body"));
+    let dir = cache_dir("warc-headers");
+    let warc = dir.join("warc");
+    let downloader = downloader(&dir.join("cache"), &server)
+        .with_warc(WarcWriter::new(&warc, "test").with_gzip(false))
+        .with_header(HeaderName::from_static("x-token"), HeaderValue::from_static("default"))
+        .with_header(HeaderName::from_static("accept"), HeaderValue::from_static("text/html"))
+        .with_user_agent("crawl-test/1.0");
+    let request = Request::new(server.url("/a")).with_header(HeaderName::from_static("accept"), HeaderValue::from_static("application/json"));
+    run_requests(downloader, vec![request], Duration::from_secs(10)).unwrap();
+
+    let sent = &server.requests()[0];
+    assert_eq!(sent.header("User-Agent"), Some("crawl-test/1.0"));
+    assert_eq!(sent.header("X-Token"), Some("default"));
+    assert_eq!(sent.header("Accept"), Some("application/json"));
+    let file = fs::read_dir(&warc).unwrap().next().unwrap().unwrap().path();
+    let record = WarcReader::open(file).unwrap().map(|r| r.unwrap()).find(|r| r.record_type() == Some("request")).unwrap();
+    let block = String::from_utf8(record.block.to_vec()).unwrap();
+    assert!(block.contains("user-agent: crawl-test/1.0\r\n"), "{}", block);
+    assert!(block.contains("x-token: default\r\n"), "{}", block);
+    assert!(block.contains("accept: application/json\r\n"), "{}", block);
+    assert!(!block.contains("text/html"), "{}", block);
+}
+
+#[test]
+fn sends_cookies_back_per_session(){
+    let server = MockServer::start().unwrap();
+    server.route("/set", MockResponse::ok("set").with_header("Set-Cookie", "user=anna; Path=/"));
+    server.route("/set-other", MockResponse::ok("set").with_header("Set-Cookie", "user=ben; Path=/"));
+    server.route("/check", MockResponse::ok("check"));
+    let dir = cache_dir("cookies");
+    let cookie_file = dir.join("cookies.txt");
+    let downloader = downloader(&dir, &server).with_cookie_file(&cookie_file);
+    downloader.fetch(&Request::new(server.url("/check"))).unwrap();
+    downloader.fetch(&Request::new(server.url("/set"))).unwrap();
+    downloader.fetch(&Request::new(server.url("/check"))).unwrap();
+    downloader.fetch(&Request::new(server.url("/set-other")).with_session("other")).unwrap();
+    downloader.fetch(&Request::new(server.url("/check")).with_session("other")).unwrap();
+    downloader.fetch(&Request::new(server.url("/check")).with_session("fresh")).unwrap();
+    downloader.fetch(&Request::new(server.url("/check"))).unwrap();
+
+    let cookies: Vec<Option<String>> = server.requests().iter()
+        .filter(|r| r.path == "/check")
+        .map(|r| r.header("Cookie").map(str::to_string))
+        .collect();
+    assert_eq!(cookies, vec![None, Some("user=anna".to_string()), Some("user=ben".to_string()), None, Some("user=anna".to_string())]);
+    assert_eq!(downloader.session("other").unwrap().get(&server.url("/")), vec![("user".to_string(), "ben".to_string())]);
+
+    downloader.save_cookies().unwrap();
+    let restored = self::downloader(&dir, &server).with_cookie_file(&cookie_file);
+    restored.fetch(&Request::new(server.url("/check"))).unwrap();
+    assert_eq!(server.requests().last().unwrap().header("Cookie"), Some("user=anna"));
+}
+
+#[test]
+fn extracts_links_only_from_html_responses(){
+    let server = MockServer::start().unwrap();
+    let page = r#"<a href="next.html">next</a>"#;
+    server.route("/page", MockResponse::ok(page));
+    server.route("/data.json", MockResponse::new(200).with_header("Content-Type", "application/json").with_body(page));
+    server.route("/image.png", MockResponse::new(200).with_header("Content-Type", "image/png").with_body(page));
+    let dir = cache_dir("links");
+    let requests = ["/page", "/data.json", "/image.png"].iter().map(|path| Request::new(server.url(path))).collect();
+    let responses = run_requests(downloader(&dir, &server), requests, Duration::from_secs(10)).unwrap();
+    let extractor = LinkExtractor::new();
+    for response in responses.iter(){
+        let links = extractor.extract_response(response).unwrap();
+        if response.url.ends_with("/page"){
+            assert_eq!(links, vec![server.url("/next.html")]);
+        }else{
+            assert!(links.is_empty(), "{}", response.url);
+        }
+    }
+}
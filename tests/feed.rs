@@ -0,0 +1,48 @@
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use crawl::downloader::{Downloader, get_res_thread_arg, start_crawl};
+use crawl::feed::FeedSource;
+use crawl::testing::{MockResponse, MockServer};
+
+fn rss(server: &MockServer) -> MockResponse{
+    let items: String = ["a", "b"].iter()
+        .map(|id| format!("<item><guid>{0}</guid><link>{1}</link><pubDate>Mon, 01 Jan 2024 00:00:00 GMT</pubDate></item>", id, server.url(&format!("/{}.html", id))))
+        .collect();
+    MockResponse::new(200).with_header("Content-Type", "application/rss+xml").with_body(format!("<rss><channel>{}</channel></rss>", items))
+}
+
+fn crawl(server: &MockServer, dir: &std::path::Path) -> Vec<String>{
+    let feed = FeedSource::new().with_feed(server.url("/feed.xml")).with_state_file(dir.join("feed.json"));
+    let downloader = Arc::new(Downloader::<()>::new(dir.join("cache").to_string_lossy().into_owned(), server.base_url()).with_retries(0));
+    let count = feed.seed(&downloader, Arc::new(())).unwrap();
+    let arg = get_res_thread_arg(&downloader);
+    start_crawl(&downloader, 2);
+    let mut fetched = Vec::new();
+    for _ in 0..count{
+        let response = arg.get_msg_timeout(Duration::from_secs(10)).unwrap();
+        if feed.mark_fetched(&response).unwrap(){
+            fetched.push(response.url.clone());
+        }
+    }
+    downloader.close();
+    fetched.sort();
+    fetched
+}
+
+#[test]
+fn retries_items_whose_page_failed(){
+    let server = MockServer::start().unwrap();
+    server.route("/feed.xml", rss(&server));
+    server.route("/a.html", MockResponse::ok("a"));
+    server.route("/b.html", MockResponse::new(503));
+    let dir = std::env::temp_dir().join(format!("crawl-feed-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    assert_eq!(crawl(&server, &dir), vec![server.url("/a.html")]);
+    server.route("/b.html", MockResponse::ok("b"));
+    assert_eq!(crawl(&server, &dir), vec![server.url("/b.html")]);
+    assert_eq!(crawl(&server, &dir), Vec::<String>::new());
+    assert_eq!(server.hits("/a.html"), 1);
+    assert_eq!(server.hits("/b.html"), 2);
+}
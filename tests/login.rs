@@ -0,0 +1,109 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+use crawl::auth::Auth;
+use crawl::downloader::Downloader;
+use crawl::request::Request;
+use crawl::testing::{MockResponse, MockServer, run_requests};
+use reqwest::header::{AUTHORIZATION, HeaderValue};
+
+fn cache_dir(name: &str) -> String{
+    let dir: PathBuf = std::env::temp_dir().join(format!("crawl-login-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    dir.to_string_lossy().into_owned()
+}
+
+#[test]
+fn logs_in_once_when_many_workers_are_logged_out(){
+    let server = Arc::new(MockServer::start().unwrap());
+    let pages: Vec<String> = (0..8).map(|i| format!("/page{}", i)).collect();
+    for page in pages.iter(){
+        server.route(page, MockResponse::ok("please log in"));
+    }
+    server.route("/session", MockResponse::ok("token"));
+    let logins = Arc::new(AtomicUsize::new(0));
+    let (s, l, p) = (Arc::clone(&server), Arc::clone(&logins), pages.clone());
+    let downloader = Downloader::<()>::new(cache_dir("relogin"), server.base_url())
+        .with_logged_out(|page| page.text().contains("log in"))
+        .with_login(move |d| {
+            // the session from the login at startup has already expired when the pages are fetched
+            if l.fetch_add(1, Ordering::SeqCst) > 0{
+                d.fetch(&Request::new(s.url("/session")))?;
+                sleep(Duration::from_millis(300));
+                for page in p.iter(){
+                    s.route(page, MockResponse::ok("content"));
+                }
+            }
+            Ok(())
+        });
+    let requests = pages.iter().map(|page| Request::new(server.url(page))).collect();
+    let responses = run_requests(downloader, requests, Duration::from_secs(10)).unwrap();
+
+    assert_eq!(logins.load(Ordering::SeqCst), 2);
+    assert_eq!(server.hits("/session"), 1);
+    assert_eq!(responses.len(), pages.len());
+    for response in responses.iter(){
+        assert_eq!(response.body().map(|b| &b[..]), Some(&b"content"[..]), "{}", response.url);
+    }
+    assert!(responses.iter().any(|r| r.attempt == 1));
+}
+
+#[test]
+fn posts_the_login_form_and_keeps_its_cookie(){
+    let server = MockServer::start().unwrap();
+    server.route("/login", MockResponse::ok("welcome").with_header("Set-Cookie", "sid=abc; Path=/"));
+    server.route("/page", MockResponse::ok("content"));
+    let downloader = Downloader::<()>::new(cache_dir("form"), server.base_url())
+        .with_auth(Auth::form(server.url("/login"), &[("user", "me"), ("pass", "a b&c")]));
+    let responses = run_requests(downloader, vec![Request::new(server.url("/page"))], Duration::from_secs(10)).unwrap();
+    assert!(responses[0].body().is_some());
+
+    let requests = server.requests();
+    assert_eq!(requests[0].method, "POST");
+    assert_eq!(requests[0].path, "/login");
+    assert_eq!(requests[0].header("Content-Type"), Some("application/x-www-form-urlencoded"));
+    assert_eq!(&requests[0].body[..], b"user=me&pass=a+b%26c");
+    assert_eq!(requests[1].path, "/page");
+    assert_eq!(requests[1].header("Cookie"), Some("sid=abc"));
+}
+
+#[test]
+fn fails_form_login_on_error_status(){
+    let server = MockServer::start().unwrap();
+    server.route("/login", MockResponse::new(403).with_body("denied"));
+    let logins = Arc::new(AtomicUsize::new(0));
+    let l = Arc::clone(&logins);
+    let downloader = Downloader::<()>::new(cache_dir("form-error"), server.base_url())
+        .with_auth(Auth::form(server.url("/login"), &[("user", "me")]))
+        .with_login(move |_| {
+            l.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+    let error = downloader.login().unwrap_err().to_string();
+    assert!(error.contains("403"), "{}", error);
+    assert_eq!(logins.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn sends_basic_and_bearer_credentials_in_scope(){
+    let server = MockServer::start().unwrap();
+    server.route("/private/a", MockResponse::ok("a"));
+    server.route("/public/a", MockResponse::ok("b"));
+    let basic = Downloader::<()>::new(cache_dir("basic"), server.url("/private/")).with_auth(Auth::basic("user", "pass"));
+    basic.fetch(&Request::new(server.url("/private/a"))).unwrap();
+    basic.fetch(&Request::new(server.url("/public/a"))).unwrap();
+    let bearer = Downloader::<()>::new(cache_dir("bearer"), server.url("/private/")).with_auth(Auth::bearer("secret"));
+    bearer.fetch(&Request::new(server.url("/private/a"))).unwrap();
+    bearer.fetch(&Request::new(server.url("/private/a")).with_header(AUTHORIZATION, HeaderValue::from_static("Token own"))).unwrap();
+
+    let authorization: Vec<Option<String>> = server.requests().iter().map(|r| r.header("Authorization").map(str::to_string)).collect();
+    assert_eq!(authorization, vec![
+        Some("Basic dXNlcjpwYXNz".to_string()),
+        None,
+        Some("Bearer secret".to_string()),
+        Some("Token own".to_string()),
+    ]);
+}
@@ -0,0 +1,87 @@
+use std::io::Write;
+use std::time::Duration;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use crawl::downloader::{Downloader, NotCached};
+use crawl::request::Request;
+use crawl::sitemap::SitemapSource;
+use crawl::testing::{MockResponse, MockServer, run_requests};
+use crawl::warc::{WarcArchive, WarcWriter};
+
+fn xml(body: String) -> MockResponse{
+    MockResponse::new(200).with_header("Content-Type", "application/xml").with_body(body)
+}
+
+fn gzip(body: String) -> MockResponse{
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body.as_bytes()).unwrap();
+    MockResponse::new(200).with_header("Content-Type", "application/gzip").with_body(encoder.finish().unwrap())
+}
+
+fn index(locs: &[String]) -> String{
+    let sitemaps: String = locs.iter().map(|loc| format!("<sitemap><loc>{}</loc></sitemap>", loc)).collect();
+    format!(r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">{}</sitemapindex>"#, sitemaps)
+}
+
+fn urlset(urls: &[(String, f32)]) -> String{
+    let entries: String = urls.iter()
+        .map(|(loc, priority)| format!("<url><loc>{}</loc><image:image><image:loc>{}img.png</image:loc></image:image><priority>{}</priority></url>", loc, loc, priority))
+        .collect();
+    format!(r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9" xmlns:image="http://www.google.com/schemas/sitemap-image/1.1">{}</urlset>"#, entries)
+}
+
+#[test]
+fn follows_nested_gzip_indexes_from_robots(){
+    let server = MockServer::start().unwrap();
+    server.route("/robots.txt", MockResponse::ok(format!("User-agent: *\nSitemap: {}\n", server.url("/index.xml.gz"))));
+    server.route("/index.xml.gz", gzip(index(&[server.url("/nested.xml"), server.url("/pages.xml")])));
+    server.route("/nested.xml", xml(index(&[server.url("/more.xml.gz"), server.url("/index.xml.gz")])));
+    server.route("/pages.xml", xml(urlset(&[(server.url("/low"), 0.1), (server.url("/shared"), 0.5)])));
+    server.route("/more.xml.gz", gzip(urlset(&[(server.url("/high"), 0.9), (server.url("/shared"), 0.5)])));
+
+    let downloader = Downloader::<()>::new(String::from("unused"), server.base_url());
+    let entries = SitemapSource::new().with_robots(server.base_url()).entries(&downloader).unwrap();
+    let urls: Vec<&str> = entries.iter().map(|e| e.url.as_str()).collect();
+    assert_eq!(urls, vec![server.url("/high"), server.url("/shared"), server.url("/low")]);
+    assert_eq!(server.hits("/index.xml.gz"), 1);
+    assert_eq!(server.hits("/more.xml.gz"), 1);
+}
+
+#[test]
+fn falls_back_to_sitemap_xml(){
+    let server = MockServer::start().unwrap();
+    server.route("/sitemap.xml", xml(urlset(&[(server.url("/a"), 0.5)])));
+    let downloader = Downloader::<()>::new(String::from("unused"), server.base_url());
+    let entries = SitemapSource::new().with_robots(server.base_url()).entries(&downloader).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].url, server.url("/a"));
+    assert_eq!(server.hits("/robots.txt"), 1);
+}
+
+#[test]
+fn reads_cached_and_archived_sitemaps_offline(){
+    let server = MockServer::start().unwrap();
+    server.route("/robots.txt", MockResponse::ok(format!("User-agent: *\nSitemap: {}\n", server.url("/index.xml"))));
+    server.route("/index.xml", xml(index(&[server.url("/pages.xml")])));
+    server.route("/pages.xml", xml(urlset(&[(server.url("/a"), 0.5)])));
+    let dir = std::env::temp_dir().join(format!("crawl-sitemap-test-{}-offline", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let warc = dir.join("warc");
+    let online = Downloader::<()>::new(dir.join("cache").to_string_lossy().into_owned(), server.base_url())
+        .with_warc(WarcWriter::new(&warc, "test"));
+    let requests = ["/robots.txt", "/index.xml", "/pages.xml"].iter().map(|path| Request::new(server.url(path))).collect();
+    run_requests(online, requests, Duration::from_secs(10)).unwrap();
+
+    let cached = Downloader::<()>::new(dir.join("cache").to_string_lossy().into_owned(), server.base_url()).with_offline(true);
+    let entries = SitemapSource::new().with_robots(server.base_url()).entries(&cached).unwrap();
+    assert_eq!(entries.iter().map(|e| e.url.as_str()).collect::<Vec<_>>(), vec![server.url("/a")]);
+    let archived = Downloader::<()>::new(dir.join("empty").to_string_lossy().into_owned(), server.base_url())
+        .with_offline(true)
+        .with_warc_source(WarcArchive::load(&warc).unwrap());
+    let entries = SitemapSource::new().with_robots(server.base_url()).entries(&archived).unwrap();
+    assert_eq!(entries.iter().map(|e| e.url.as_str()).collect::<Vec<_>>(), vec![server.url("/a")]);
+    let missing = archived.fetch(&Request::new(server.url("/missing.xml"))).err().unwrap();
+    assert!(missing.downcast_ref::<NotCached>().is_some());
+    assert_eq!(server.hits("/index.xml"), 1);
+    assert_eq!(server.hits("/pages.xml"), 1);
+}
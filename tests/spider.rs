@@ -0,0 +1,43 @@
+use std::fs;
+use std::time::Duration;
+use crawl::downloader::{Downloader, Response};
+use crawl::request::Request;
+use crawl::spider::{Engine, Output, Spider};
+use crawl::testing::{MockResponse, MockServer};
+
+struct BodySpider{
+    seed: Request,
+}
+
+impl Spider for BodySpider{
+    type Flag = ();
+    type Item = String;
+
+    fn start_urls(&self) -> Vec<Request>{
+        vec![self.seed.clone()]
+    }
+
+    fn parse(&self, response: &Response<()>) -> anyhow::Result<Vec<Output<(), String>>>{
+        Ok(response.text().into_iter().map(Output::Item).collect())
+    }
+}
+
+#[test]
+fn retries_with_the_request_force(){
+    let server = MockServer::start().unwrap();
+    server.route("/a", MockResponse::ok("old"));
+    let dir = std::env::temp_dir().join(format!("crawl-spider-test-{}-force", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let downloader = || Downloader::new(dir.to_string_lossy().into_owned(), server.base_url());
+    let seed = Request::new(server.url("/a"));
+    Engine::new(BodySpider{seed: seed.clone()}, downloader()).run().unwrap();
+
+    // the first forced attempt times out, the retry must not fall back to the cached page
+    server.sequence("/a", vec![MockResponse::ok("slow").with_delay(Duration::from_secs(2)), MockResponse::ok("new")]);
+    let seed = seed.with_force(true).with_timeout(Duration::from_millis(200));
+    let (items, summary) = Engine::new(BodySpider{seed}, downloader()).with_threads(2, 2).run_with_summary().unwrap();
+    assert_eq!(items, vec!["new".to_string()]);
+    assert_eq!(summary.pages, 1);
+    assert_eq!(summary.failed, 0);
+    assert_eq!(fs::read(dir.join("a")).unwrap(), b"new");
+}